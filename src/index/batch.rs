@@ -0,0 +1,47 @@
+use super::message::with_expiry;
+use super::message::Message;
+use super::operation::Operation;
+
+/// Messages that are applied to a tree in a single transaction.
+/// Messages for the same key take effect in the order they were added.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub msgs: Vec<Message>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { msgs: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.msgs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty()
+    }
+
+    fn push(&mut self, op: Operation, key: &[u8], data: &[u8]) -> &mut WriteBatch {
+        self.msgs.push(Message::new(op, key.to_vec(), data.to_vec()));
+        self
+    }
+
+    pub fn assign(&mut self, key: &[u8], data: &[u8]) -> &mut WriteBatch {
+        self.push(Operation::Assign, key, data)
+    }
+
+    /// Assigns a value that reads as absent once the tree's clock
+    /// reaches `expires`.
+    pub fn assign_expiring(&mut self, key: &[u8], data: &[u8], expires: u64) -> &mut WriteBatch {
+        self.push(Operation::AssignExpiring, key, &with_expiry(expires, data))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.push(Operation::Delete, key, &[])
+    }
+
+    pub fn merge(&mut self, key: &[u8], data: &[u8]) -> &mut WriteBatch {
+        self.push(Operation::Merge, key, data)
+    }
+}
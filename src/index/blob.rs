@@ -0,0 +1,191 @@
+use super::message::Stored;
+use super::store::Store;
+
+use std::cmp;
+use std::io;
+use std::io::Read;
+use std::ops::Range;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+/// When assigned values are kept outside the nodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlobPolicy {
+    /// Values longer than this many bytes are written as blobs.
+    pub threshold: usize,
+    /// Size of the chunks a blob is written in, and read back in
+    /// by a `ValueReader`.
+    pub chunk: usize,
+}
+
+/// Where a value kept outside the nodes is: `len` bytes in chunks of
+/// `chunk` bytes, stored under consecutive ids from `id` on. The ids
+/// are taken from the node ids of the tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlobRef {
+    pub id: u64,
+    pub len: u64,
+    pub chunk: u32,
+}
+
+impl BlobRef {
+    /// Size of an encoded reference.
+    pub const SIZE: usize = 20;
+
+    /// The ids of the chunks.
+    pub fn ids(&self) -> Range<u64> {
+        self.id..(self.id + self.len.div_ceil(u64::from(self.chunk)))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![0; BlobRef::SIZE];
+        LittleEndian::write_u64(&mut data[..8], self.id);
+        LittleEndian::write_u64(&mut data[8..16], self.len);
+        LittleEndian::write_u32(&mut data[16..], self.chunk);
+        data
+    }
+
+    /// Reads the reference at the start of `data`.
+    pub fn decode(data: &[u8]) -> io::Result<BlobRef> {
+        if data.len() < BlobRef::SIZE || LittleEndian::read_u32(&data[16..BlobRef::SIZE]) == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid blob reference"));
+        }
+        Ok(BlobRef {
+            id: LittleEndian::read_u64(&data[..8]),
+            len: LittleEndian::read_u64(&data[8..16]),
+            chunk: LittleEndian::read_u32(&data[16..BlobRef::SIZE]),
+        })
+    }
+}
+
+/// Reads a stored value in pieces. The chunks of a blob are read from
+/// the store one at a time, followed by any bytes merged onto it.
+pub struct ValueReader<'s, 'a: 's> {
+    store: &'s Store<'a>,
+    blob: Option<BlobRef>,
+    /// Index of the next chunk to read.
+    next: u64,
+    /// Bytes not yet returned, from `pos` on.
+    pending: Vec<u8>,
+    pos: usize,
+    tail: Vec<u8>,
+}
+
+impl<'s, 'a> ValueReader<'s, 'a> {
+    pub fn new(store: &'s Store<'a>, stored: Stored) -> io::Result<ValueReader<'s, 'a>> {
+        let (blob, tail) = if stored.blob {
+            (Some(BlobRef::decode(&stored.val)?), stored.val[BlobRef::SIZE..].to_vec())
+        } else {
+            (None, stored.val)
+        };
+        Ok(ValueReader {
+            store: store,
+            blob: blob,
+            next: 0,
+            pending: vec![],
+            pos: 0,
+            tail: tail,
+        })
+    }
+
+    /// Total length of the value in bytes.
+    pub fn len(&self) -> u64 {
+        self.blob.map_or(0, |blob| blob.len) + self.tail.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the rest of the value into memory.
+    pub fn into_vec(mut self) -> io::Result<Vec<u8>> {
+        let mut val = Vec::with_capacity(self.len() as usize);
+        self.read_to_end(&mut val)?;
+        Ok(val)
+    }
+
+    // loads the next chunk, or the tail after the last one; false
+    // once the whole value has been returned
+    fn refill(&mut self) -> io::Result<bool> {
+        let ids = self.blob.map_or(0..0, |blob| blob.ids());
+        if self.next < ids.end - ids.start {
+            let blob = self.blob.unwrap();
+            let chunk = self.store.read_blob(ids.start + self.next)?;
+            let expected = cmp::min(u64::from(blob.chunk), blob.len - self.next * u64::from(blob.chunk));
+            if chunk.len() as u64 != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("blob chunk {} has {} bytes", ids.start + self.next, chunk.len()),
+                ));
+            }
+            self.next += 1;
+            self.pending = chunk;
+        } else if !self.tail.is_empty() {
+            self.pending = self.tail.split_off(0);
+        } else {
+            return Ok(false);
+        }
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<'s, 'a> Read for ValueReader<'s, 'a> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.pending.len() {
+            if out.is_empty() || !self.refill()? {
+                return Ok(0);
+            }
+        }
+        let len = cmp::min(out.len(), self.pending.len() - self.pos);
+        out[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::compression::LevelCompression;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    #[test]
+    fn read_chunks() {
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let blob = BlobRef {
+            id: 4,
+            len: 10,
+            chunk: 4,
+        };
+        assert_eq!(4..7, blob.ids());
+        assert!(store.write_blob(4, b"0123").is_ok());
+        assert!(store.write_blob(5, b"4567").is_ok());
+        assert!(store.write_blob(6, b"89").is_ok());
+        let mut val = blob.encode();
+        val.extend_from_slice(b"ab");
+        let stored = Stored {
+            val: val,
+            expires: 0,
+            blob: true,
+        };
+
+        let mut reader = ValueReader::new(&store, stored.clone()).unwrap();
+        assert_eq!(12, reader.len());
+        let mut out = [0; 3];
+        assert_eq!(3, reader.read(&mut out).unwrap());
+        assert_eq!(b"012", &out);
+        assert_eq!(1, reader.read(&mut out).unwrap());
+        assert_eq!(b"3", &out[..1]);
+        let mut rest = vec![];
+        assert!(reader.read_to_end(&mut rest).is_ok());
+        assert_eq!(b"456789ab".to_vec(), rest);
+
+        assert!(store.write_blob(5, b"45").is_ok());
+        assert!(ValueReader::new(&store, stored).unwrap().into_vec().is_err());
+        assert!(BlobRef::decode(b"short").is_err());
+    }
+}
@@ -36,6 +36,12 @@ impl<'a> Buf<'a> {
     }
 }
 
+impl<'a> Clone for Buf<'a> {
+    fn clone(&self) -> Buf<'a> {
+        Buf::Owned(self.to_vec())
+    }
+}
+
 impl<'a> Ord for Buf<'a> {
     fn cmp(&self, other: &Buf) -> Ordering {
         self.bytes().cmp(other.bytes())
@@ -0,0 +1,345 @@
+use super::buf::Buf;
+use super::comparator::in_range;
+use super::comparator::Comparator;
+use super::comparator::KeyRange;
+use super::flush::Pending;
+use super::message::BufMessage;
+
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::Index;
+
+/// Buffered messages bound for one child, sorted by key.
+/// Messages for the same key are kept in the order they arrived.
+#[derive(Debug, Clone, Default)]
+pub struct Partition<'a> {
+    pub msgs: Vec<BufMessage<'a>>,
+    /// Total size of the message keys and data.
+    pub bytes: usize,
+    /// Sequence number of the oldest message since the last flush,
+    /// meaningless while the partition is empty.
+    pub oldest: u64,
+}
+
+/// The message buffer of an internal node with one partition per child.
+#[derive(Debug, Clone, Default)]
+pub struct Buffer<'a> {
+    pub parts: Vec<Partition<'a>>,
+    seq: u64,
+}
+
+fn msg_bytes(msg: &BufMessage) -> usize {
+    let tag = if msg.epoch == 0 { 0 } else { mem::size_of::<u64>() };
+    msg.key.len() + msg.data.len() + tag
+}
+
+// the sequence number of the older of two partitions that are joined
+fn older(left: &Partition, right: &Partition) -> u64 {
+    match (left.msgs.is_empty(), right.msgs.is_empty()) {
+        (true, _) => right.oldest,
+        (false, true) => left.oldest,
+        (false, false) => left.oldest.min(right.oldest),
+    }
+}
+
+fn owned<'a>(msgs: Vec<BufMessage<'a>>) -> Vec<BufMessage<'a>> {
+    // shared buffers point into the data of the node they came from
+    msgs.to_vec()
+}
+
+impl<'a> Partition<'a> {
+    /// Range of the messages for `key`.
+    fn range(&self, cmp: &Comparator, key: &[u8]) -> (usize, usize) {
+        let lo = self
+            .msgs
+            .partition_point(|msg| cmp.compare(msg.key.bytes(), key) == Ordering::Less);
+        let hi = lo
+            + self.msgs[lo..].partition_point(|msg| cmp.compare(msg.key.bytes(), key) == Ordering::Equal);
+        (lo, hi)
+    }
+
+    fn push_all(&mut self, msgs: Vec<BufMessage<'a>>) {
+        for msg in &msgs {
+            self.bytes += msg_bytes(msg);
+        }
+        self.msgs.extend(msgs);
+    }
+}
+
+impl<'a> Buffer<'a> {
+    pub fn new(children: usize) -> Buffer<'a> {
+        Buffer {
+            parts: (0..children).map(|_| Partition::default()).collect(),
+            seq: 0,
+        }
+    }
+
+    /// Partitions messages among the children separated by `keys`.
+    pub fn partition(
+        cmp: &Comparator,
+        keys: &[Buf],
+        children: usize,
+        mut msgs: Vec<BufMessage<'a>>,
+    ) -> Buffer<'a> {
+        let mut buffer = Buffer::new(children);
+        msgs.sort_by(|a, b| cmp.compare(a.key.bytes(), b.key.bytes()));
+        let mut child = 0;
+        for msg in msgs {
+            while child < keys.len()
+                && cmp.compare(msg.key.bytes(), keys[child].bytes()) != Ordering::Less
+            {
+                child += 1;
+            }
+            buffer.seq += 1;
+            let part = &mut buffer.parts[child];
+            if part.msgs.is_empty() {
+                part.oldest = buffer.seq;
+            }
+            part.bytes += msg_bytes(&msg);
+            part.msgs.push(msg);
+        }
+        buffer
+    }
+
+    /// Rebuilds a buffer from messages in key order, the number of
+    /// messages in each partition and the sequence number of its oldest.
+    pub fn from_counts(counts: &[usize], oldest: &[u64], msgs: Vec<BufMessage<'a>>) -> Buffer<'a> {
+        let mut buffer = Buffer::new(counts.len());
+        let mut msgs = msgs.into_iter();
+        for ((part, &count), &seq) in buffer.parts.iter_mut().zip(counts).zip(oldest) {
+            part.push_all(msgs.by_ref().take(count).collect());
+            part.oldest = seq;
+        }
+        // later messages must count as newer than every one kept
+        buffer.seq = oldest.iter().cloned().max().unwrap_or(0);
+        buffer
+    }
+
+    /// Number of buffered messages.
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(|part| part.msgs.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.iter().all(|part| part.msgs.is_empty())
+    }
+
+    /// Serialized size of the buffered messages in bytes.
+    pub fn size(&self, overhead: usize) -> usize {
+        self.parts
+            .iter()
+            .map(|part| part.bytes + overhead * part.msgs.len())
+            .sum()
+    }
+
+    /// All messages in key order.
+    pub fn iter<'b>(&'b self) -> impl Iterator<Item = &'b BufMessage<'a>> {
+        self.parts.iter().flat_map(|part| part.msgs.iter())
+    }
+
+    /// Messages buffered for `key` in the partition of `child`.
+    pub fn get(&self, cmp: &Comparator, child: usize, key: &[u8]) -> &[BufMessage<'a>] {
+        let part = &self.parts[child];
+        let (lo, hi) = part.range(cmp, key);
+        &part.msgs[lo..hi]
+    }
+
+    /// Adds a message for `child`. A message that supersedes earlier
+    /// messages for the same key replaces those from its own epoch,
+    /// which are returned.
+    pub fn insert(&mut self, cmp: &Comparator, child: usize, msg: BufMessage<'a>) -> Vec<BufMessage<'a>> {
+        self.seq += 1;
+        let seq = self.seq;
+        let part = &mut self.parts[child];
+        if part.msgs.is_empty() {
+            part.oldest = seq;
+        }
+        let (lo, hi) = part.range(cmp, msg.key.bytes());
+        if msg.op.supersedes() {
+            // messages of older epochs are versions of their own
+            let lo = match part.msgs[lo..hi].iter().rposition(|old| old.epoch != msg.epoch) {
+                Some(pos) => lo + pos + 1,
+                None => lo,
+            };
+            let replaced: Vec<BufMessage<'a>> = part.msgs.drain(lo..hi).collect();
+            for old in &replaced {
+                part.bytes -= msg_bytes(old);
+            }
+            part.bytes += msg_bytes(&msg);
+            part.msgs.insert(lo, msg);
+            replaced
+        } else {
+            part.bytes += msg_bytes(&msg);
+            part.msgs.insert(hi, msg);
+            vec![]
+        }
+    }
+
+    /// Removes and returns the messages for `child`.
+    pub fn take(&mut self, child: usize) -> Vec<BufMessage<'a>> {
+        let part = mem::take(&mut self.parts[child]);
+        part.msgs
+    }
+
+    /// Removes and returns the messages for `child` with keys in `range`.
+    pub fn take_range(&mut self, cmp: &Comparator, child: usize, range: &KeyRange) -> Vec<BufMessage<'a>> {
+        let part = &mut self.parts[child];
+        let (taken, kept): (Vec<_>, Vec<_>) = mem::take(&mut part.msgs)
+            .into_iter()
+            .partition(|msg| in_range(cmp, range, msg.key.bytes()));
+        for msg in &taken {
+            part.bytes -= msg_bytes(msg);
+        }
+        part.msgs = kept;
+        taken
+    }
+
+    /// Adds an empty partition for a new child at `child`.
+    pub fn insert_child(&mut self, child: usize) {
+        self.parts.insert(child, Partition::default());
+    }
+
+    /// Joins the partitions of `child` and its right sibling.
+    pub fn merge_children(&mut self, child: usize) {
+        let right = self.parts.remove(child + 1);
+        let part = &mut self.parts[child];
+        part.oldest = older(part, &right);
+        part.push_all(right.msgs);
+    }
+
+    /// Moves messages between `child` and its right sibling
+    /// after the separator between them has changed.
+    pub fn repartition(&mut self, cmp: &Comparator, child: usize, separator: &[u8]) {
+        let right = mem::take(&mut self.parts[child + 1]);
+        let left = mem::take(&mut self.parts[child]);
+        let oldest = older(&left, &right);
+        let mut msgs = left.msgs;
+        msgs.extend(right.msgs);
+        let split = msgs.partition_point(|msg| cmp.compare(msg.key.bytes(), separator) == Ordering::Less);
+        let upper = msgs.split_off(split);
+        self.parts[child].push_all(msgs);
+        self.parts[child + 1].push_all(upper);
+        self.parts[child].oldest = oldest;
+        self.parts[child + 1].oldest = oldest;
+    }
+
+    /// Splits off the partitions from `child` onwards.
+    pub fn split_off(&mut self, child: usize) -> Buffer<'a> {
+        Buffer {
+            parts: self.parts.split_off(child),
+            seq: self.seq,
+        }
+    }
+
+    /// Appends the partitions of a right sibling.
+    pub fn append(&mut self, other: Buffer<'a>) {
+        self.seq = self.seq.max(other.seq);
+        for part in other.parts {
+            let mut copy = Partition {
+                msgs: vec![],
+                bytes: 0,
+                oldest: part.oldest,
+            };
+            copy.push_all(owned(part.msgs));
+            self.parts.push(copy);
+        }
+    }
+
+    /// Summarizes the buffered messages for each child.
+    pub fn pending(&self) -> Vec<Pending> {
+        let mut pending = vec![];
+        for (child, part) in self.parts.iter().enumerate() {
+            if !part.msgs.is_empty() {
+                pending.push(Pending {
+                    child: child,
+                    count: part.msgs.len(),
+                    bytes: part.bytes,
+                    oldest: part.oldest as usize,
+                });
+            }
+        }
+        pending
+    }
+}
+
+impl<'a> Index<usize> for Buffer<'a> {
+    type Output = BufMessage<'a>;
+
+    /// The message at position `idx` in key order.
+    fn index(&self, idx: usize) -> &BufMessage<'a> {
+        self.iter().nth(idx).expect("buffer index out of range")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::comparator::Bytewise;
+    use index::operation::Operation;
+
+    fn msg<'a>(op: Operation, key: &[u8], data: &[u8]) -> BufMessage<'a> {
+        BufMessage::new(op, Buf::Owned(key.to_vec()), Buf::Owned(data.to_vec()))
+    }
+
+    #[test]
+    fn partition_buffer() {
+        let keys = vec![Buf::Owned(b"b".to_vec()), Buf::Owned(b"d".to_vec())];
+        let msgs = vec![
+            msg(Operation::Assign, b"e", b"1"),
+            msg(Operation::Assign, b"a", b"2"),
+            msg(Operation::Assign, b"b", b"3"),
+            msg(Operation::Assign, b"c", b"4"),
+        ];
+        let buffer = Buffer::partition(&Bytewise, &keys, 3, msgs);
+        assert_eq!(4, buffer.len());
+        assert_eq!(1, buffer.parts[0].msgs.len());
+        assert_eq!(2, buffer.parts[1].msgs.len());
+        assert_eq!(1, buffer.parts[2].msgs.len());
+        assert_eq!(4, buffer.parts[1].bytes);
+        assert_eq!(b"c", buffer[2].key.bytes());
+        let keys = buffer.iter().map(|msg| msg.key.to_vec()).collect::<Vec<_>>();
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"e".to_vec()], keys);
+    }
+
+    #[test]
+    fn insert_sorted() {
+        let mut buffer = Buffer::new(2);
+        buffer.insert(&Bytewise, 1, msg(Operation::Assign, b"y", b"1"));
+        buffer.insert(&Bytewise, 1, msg(Operation::Assign, b"x", b"2"));
+        buffer.insert(&Bytewise, 0, msg(Operation::Assign, b"a", b"3"));
+        buffer.insert(&Bytewise, 1, msg(Operation::Delete, b"y", b""));
+        assert_eq!(3, buffer.len());
+        assert_eq!(b"x", buffer.parts[1].msgs[0].key.bytes());
+        assert_eq!(Operation::Delete, buffer.parts[1].msgs[1].op);
+        assert_eq!(3, buffer.parts[1].bytes);
+        assert_eq!(1, buffer.get(&Bytewise, 1, b"x").len());
+        assert_eq!(0, buffer.get(&Bytewise, 1, b"z").len());
+        let pending = buffer.pending();
+        assert_eq!(3, pending[0].oldest);
+        assert_eq!(1, pending[1].oldest);
+        let msgs = buffer.take(1);
+        assert_eq!(2, msgs.len());
+        assert_eq!(0, buffer.parts[1].bytes);
+        assert_eq!(1, buffer.len());
+    }
+
+    #[test]
+    fn repartition_children() {
+        let keys = vec![Buf::Owned(b"c".to_vec())];
+        let msgs = vec![
+            msg(Operation::Assign, b"a", b"1"),
+            msg(Operation::Assign, b"b", b"2"),
+            msg(Operation::Assign, b"c", b"3"),
+        ];
+        let mut buffer = Buffer::partition(&Bytewise, &keys, 2, msgs);
+        buffer.repartition(&Bytewise, 0, b"b");
+        assert_eq!(1, buffer.parts[0].msgs.len());
+        assert_eq!(2, buffer.parts[1].msgs.len());
+        assert_eq!(4, buffer.parts[1].bytes);
+        buffer.merge_children(0);
+        assert_eq!(1, buffer.parts.len());
+        assert_eq!(3, buffer.parts[0].msgs.len());
+        assert_eq!(6, buffer.parts[0].bytes);
+    }
+}
@@ -0,0 +1,367 @@
+use super::node::Node;
+use super::store::Store;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+struct Entry<'a> {
+    node: Arc<Node<'a>>,
+    size: usize,
+    tick: u64,
+    dirty: bool,
+}
+
+struct Entries<'a> {
+    nodes: HashMap<u64, Entry<'a>>,
+    // least recently used entries have the smallest tick
+    lru: BTreeMap<u64, u64>,
+    // dirty nodes that have never been written to the store
+    pending: HashSet<u64>,
+    tick: u64,
+    bytes: usize,
+}
+
+/// Keeps decoded nodes in memory up to a byte budget. Writes are
+/// held as dirty nodes and reach the underlying store when they are
+/// evicted or when the store is synced at the end of a transaction.
+/// Lookups share the cached node, and only reads for a change copy it.
+/// The cache is locked, so flush workers and readers of a shared tree
+/// can use it from several threads.
+pub struct NodeCache<'a, S: Store<'a>> {
+    pub store: Mutex<S>,
+    pub budget: usize,
+    entries: Mutex<Entries<'a>>,
+}
+
+impl<'a> Entries<'a> {
+    fn touch(&mut self, id: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.nodes.get_mut(&id).unwrap();
+        self.lru.remove(&entry.tick);
+        self.lru.insert(tick, id);
+        entry.tick = tick;
+    }
+
+    fn insert(&mut self, node: Arc<Node<'a>>, dirty: bool) {
+        let id = node.id();
+        self.remove(id);
+        self.tick += 1;
+        let size = node.size();
+        self.bytes += size;
+        self.lru.insert(self.tick, id);
+        self.nodes.insert(
+            id,
+            Entry {
+                node: node,
+                size: size,
+                tick: self.tick,
+                dirty: dirty,
+            },
+        );
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Entry<'a>> {
+        let entry = self.nodes.remove(&id);
+        if let Some(ref entry) = entry {
+            self.lru.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+        entry
+    }
+
+    // the least recently used entry other than the most recent one,
+    // skipping dirty entries unless they may be written back
+    fn victim(&self, write_back: bool) -> Option<u64> {
+        let newest = *self.lru.keys().next_back()?;
+        self.lru
+            .range(..newest)
+            .map(|(_, id)| *id)
+            .find(|id| write_back || !self.nodes[id].dirty)
+    }
+}
+
+impl<'a, S: Store<'a>> NodeCache<'a, S> {
+    pub fn new(store: S, budget: usize) -> NodeCache<'a, S> {
+        NodeCache {
+            store: Mutex::new(store),
+            budget: budget,
+            entries: Mutex::new(Entries {
+                nodes: HashMap::new(),
+                lru: BTreeMap::new(),
+                pending: HashSet::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Total size of the cached nodes in bytes.
+    pub fn bytes(&self) -> usize {
+        self.entries.lock().expect("cache lock poisoned").bytes
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.entries.lock().expect("cache lock poisoned").nodes.contains_key(&id)
+    }
+
+    pub fn is_dirty(&self, id: u64) -> bool {
+        match self.entries.lock().expect("cache lock poisoned").nodes.get(&id) {
+            Some(entry) => entry.dirty,
+            None => false,
+        }
+    }
+
+    // Dirty nodes are written back before they are dropped, and stay
+    // cached if that fails. Readers evict clean nodes only, so they
+    // never wait for or fail on a write back.
+    fn evict(&self, entries: &mut Entries<'a>, write_back: bool) -> io::Result<()> {
+        while entries.bytes > self.budget {
+            let id = match entries.victim(write_back) {
+                Some(id) => id,
+                None => break,
+            };
+            if entries.nodes[&id].dirty {
+                self.store.lock().expect("cache lock poisoned").write(&entries.nodes[&id].node)?;
+                entries.pending.remove(&id);
+            }
+            entries.remove(id);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: Store<'a> + Send> Store<'a> for NodeCache<'a, S> {
+    // the caller may change the node, so it gets a copy of its own
+    fn read(&self, id: u64) -> io::Result<Node<'a>> {
+        self.read_shared(id).map(|node| (*node).clone())
+    }
+
+    fn read_shared(&self, id: u64) -> io::Result<Arc<Node<'a>>> {
+        {
+            let mut entries = self.entries.lock().expect("cache lock poisoned");
+            if entries.nodes.contains_key(&id) {
+                entries.touch(id);
+                return Ok(entries.nodes[&id].node.clone());
+            }
+        }
+        // other threads use the cache while the node is read
+        let node = Arc::new(self.store.lock().expect("cache lock poisoned").read(id)?);
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        if entries.nodes.contains_key(&id) {
+            entries.touch(id);
+            return Ok(entries.nodes[&id].node.clone());
+        }
+        entries.insert(node.clone(), false);
+        self.evict(&mut entries, false)?;
+        Ok(node)
+    }
+
+    fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let id = node.id();
+        if !entries.nodes.contains_key(&id) {
+            entries.pending.insert(id);
+        }
+        entries.insert(Arc::new(node.clone()), true);
+        self.evict(&mut entries, true)
+    }
+
+    fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        entries.remove(id);
+        if entries.pending.remove(&id) {
+            return Ok(());
+        }
+        self.store.lock().expect("cache lock poisoned").schedule_delete(id)
+    }
+
+    // blobs are written through and not cached
+    fn write_blob(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.store.lock().expect("cache lock poisoned").write_blob(id, data)
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        self.store.lock().expect("cache lock poisoned").read_blob(id)
+    }
+
+    // nodes written since the last sync are only in the cache
+    fn last_id(&self) -> io::Result<u64> {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        let last = self.store.lock().expect("cache lock poisoned").last_id()?;
+        Ok(entries.pending.iter().cloned().fold(last, u64::max))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let mut store = self.store.lock().expect("cache lock poisoned");
+        for entry in entries.nodes.values_mut() {
+            if entry.dirty {
+                store.write(&entry.node)?;
+                entry.dirty = false;
+            }
+        }
+        entries.pending.clear();
+        store.sync()
+    }
+
+    fn concurrent(&self) -> Option<&(Store<'a> + Sync)> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::buf::Buf;
+    use index::comparator::Bytewise;
+    use index::compression::LevelCompression;
+    use index::leaf::Leaf;
+    use index::node::Body;
+    use index::node::Header;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    fn leaf_node<'a>(id: u64, val: &[u8]) -> Node<'a> {
+        Node {
+            header: Header { id: id, epoch: 1 },
+            body: Body::Leaf(Leaf::new(
+                vec![Buf::Owned(b"key".to_vec())],
+                vec![Buf::Owned(val.to_vec())],
+            )),
+        }
+    }
+
+    fn blocks<'a>(cache: &NodeCache<'a, NodeStore<MemStore>>) -> usize {
+        cache.store.lock().unwrap().blocks.blocks.len()
+    }
+
+    struct FailingStore {
+        store: NodeStore<MemStore>,
+        fail: bool,
+    }
+
+    impl<'a> Store<'a> for FailingStore {
+        fn read(&self, id: u64) -> io::Result<Node<'a>> {
+            self.store.read(id)
+        }
+
+        fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+            if self.fail {
+                return Err(io::Error::other("write failed"));
+            }
+            self.store.write(node)
+        }
+
+        fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+            self.store.schedule_delete(id)
+        }
+    }
+
+    #[test]
+    fn write_back_on_sync() {
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut cache = NodeCache::new(store, 1 << 20);
+        assert!(cache.write(&leaf_node(1, b"foo")).is_ok());
+        assert!(cache.write(&leaf_node(1, b"bar")).is_ok());
+        assert!(cache.is_dirty(1));
+        assert_eq!(0, blocks(&cache));
+        let output = cache.read(1).unwrap();
+        assert_eq!(Some(&b"bar"[..]), output.body.leaf().get(&Bytewise, b"key"));
+        assert!(cache.sync().is_ok());
+        assert!(!cache.is_dirty(1));
+        assert_eq!(1, blocks(&cache));
+        let output = cache.store.lock().unwrap().read(1).unwrap();
+        assert_eq!(Some(&b"bar"[..]), output.body.leaf().get(&Bytewise, b"key"));
+    }
+
+    #[test]
+    fn share_cached_nodes() {
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut cache = NodeCache::new(store, 1 << 20);
+        assert!(cache.write(&leaf_node(1, b"foo")).is_ok());
+        let first = cache.read_shared(1).unwrap();
+        let second = cache.read_shared(1).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let mut copy = cache.read(1).unwrap();
+        if let Body::Leaf(ref mut leaf) = copy.body {
+            leaf.vals[0] = Buf::Owned(b"bar".to_vec());
+        }
+        assert_eq!(Some(&b"foo"[..]), first.body.leaf().get(&Bytewise, b"key"));
+        assert!(cache.concurrent().is_some());
+    }
+
+    #[test]
+    fn delete_pending_node() {
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut cache = NodeCache::new(store, 1 << 20);
+        assert!(cache.write(&leaf_node(1, b"foo")).is_ok());
+        assert!(cache.schedule_delete(1).is_ok());
+        assert!(!cache.contains(1));
+        assert!(cache.sync().is_ok());
+        assert_eq!(0, blocks(&cache));
+        assert!(cache.read(1).is_err());
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let size = leaf_node(1, b"foo").size();
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut cache = NodeCache::new(store, 2 * size);
+        assert!(cache.write(&leaf_node(1, b"foo")).is_ok());
+        assert!(cache.write(&leaf_node(2, b"foo")).is_ok());
+        assert!(cache.read(1).is_ok());
+        assert!(cache.write(&leaf_node(3, b"foo")).is_ok());
+        assert_eq!(2 * size, cache.bytes());
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+        assert_eq!(1, blocks(&cache));
+        assert!(cache.read(2).is_ok());
+        assert!(cache.contains(2));
+        // readers leave dirty nodes to the next write or sync
+        assert!(cache.contains(1));
+        assert_eq!(1, blocks(&cache));
+        assert!(cache.schedule_delete(2).is_ok());
+        assert_eq!(0, blocks(&cache));
+    }
+
+    #[test]
+    fn failed_write_back_keeps_node() {
+        let size = leaf_node(1, b"foo").size();
+        let store = FailingStore {
+            store: NodeStore::new(MemStore::new(), LevelCompression::none()),
+            fail: true,
+        };
+        let mut cache = NodeCache::new(store, size);
+        assert!(cache.write(&leaf_node(1, b"foo")).is_ok());
+        assert!(cache.write(&leaf_node(2, b"bar")).is_err());
+        assert!(cache.is_dirty(1));
+        assert!(cache.read(1).is_ok());
+        cache.store.lock().unwrap().fail = false;
+        assert!(cache.sync().is_ok());
+        let output = cache.store.lock().unwrap().read(1).unwrap();
+        assert_eq!(Some(&b"foo"[..]), output.body.leaf().get(&Bytewise, b"key"));
+    }
+
+    #[test]
+    fn readers_evict_clean_nodes() {
+        let size = leaf_node(1, b"foo").size();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(store.write(&leaf_node(2, b"bar")).is_ok());
+        assert!(store.write(&leaf_node(3, b"baz")).is_ok());
+        let mut cache = NodeCache::new(store, size);
+        assert!(cache.write(&leaf_node(1, b"foo")).is_ok());
+        assert!(cache.read_shared(2).is_ok());
+        assert!(cache.read_shared(3).is_ok());
+        // the dirty node stays, only the clean one is dropped
+        assert!(cache.is_dirty(1));
+        assert!(!cache.contains(2));
+        assert_eq!(2, blocks(&cache));
+    }
+}
@@ -0,0 +1,253 @@
+use std::io;
+use std::mem::size_of;
+
+/// Turns values of type `T` into bytes and back.
+pub trait Codec<T> {
+    fn encode(&self, item: &T, out: &mut Vec<u8>);
+    fn decode(&self, data: &[u8]) -> io::Result<T>;
+}
+
+/// A key whose encoding sorts bytewise in the same order as the values.
+/// Encodings are self-delimiting, so tuples of keys are encoded by
+/// concatenating their fields.
+pub trait OrderedKey: Sized {
+    fn write_key(&self, out: &mut Vec<u8>);
+    /// Reads one key from the front of `input` and advances past it.
+    fn read_key(input: &mut &[u8]) -> io::Result<Self>;
+}
+
+/// Encodes keys and values with their `OrderedKey` encoding.
+pub struct Ordered;
+
+/// Stores byte vectors as they are.
+pub struct Raw;
+
+/// Stores strings as their UTF-8 bytes.
+pub struct Utf8;
+
+impl<T: OrderedKey> Codec<T> for Ordered {
+    fn encode(&self, item: &T, out: &mut Vec<u8>) {
+        item.write_key(out);
+    }
+
+    fn decode(&self, data: &[u8]) -> io::Result<T> {
+        let mut input = data;
+        let item = T::read_key(&mut input)?;
+        if !input.is_empty() {
+            return Err(invalid("trailing bytes after key"));
+        }
+        Ok(item)
+    }
+}
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(&self, item: &Vec<u8>, out: &mut Vec<u8>) {
+        out.extend_from_slice(item);
+    }
+
+    fn decode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+impl Codec<String> for Utf8 {
+    fn encode(&self, item: &String, out: &mut Vec<u8>) {
+        out.extend_from_slice(item.as_bytes());
+    }
+
+    fn decode(&self, data: &[u8]) -> io::Result<String> {
+        String::from_utf8(data.to_vec()).map_err(|_| invalid("value is not utf-8"))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn take<'i>(input: &mut &'i [u8], len: usize) -> io::Result<&'i [u8]> {
+    if input.len() < len {
+        return Err(invalid("key is truncated"));
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! unsigned_key {
+    ($($ty:ty),*) => {$(
+        impl OrderedKey for $ty {
+            fn write_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn read_key(input: &mut &[u8]) -> io::Result<$ty> {
+                let mut buf = [0; size_of::<$ty>()];
+                buf.copy_from_slice(take(input, size_of::<$ty>())?);
+                Ok(<$ty>::from_be_bytes(buf))
+            }
+        }
+    )*}
+}
+
+// flipping the sign bit moves negative numbers below positive ones
+macro_rules! signed_key {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl OrderedKey for $ty {
+            fn write_key(&self, out: &mut Vec<u8>) {
+                ((*self ^ <$ty>::MIN) as $unsigned).write_key(out);
+            }
+
+            fn read_key(input: &mut &[u8]) -> io::Result<$ty> {
+                Ok(<$unsigned>::read_key(input)? as $ty ^ <$ty>::MIN)
+            }
+        }
+    )*}
+}
+
+unsigned_key!(u8, u16, u32, u64);
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
+// Zero bytes are escaped as 0x00 0xff and the end is marked by
+// 0x00 0x01, so a prefix sorts before every longer string.
+impl OrderedKey for Vec<u8> {
+    fn write_key(&self, out: &mut Vec<u8>) {
+        for byte in self {
+            out.push(*byte);
+            if *byte == 0 {
+                out.push(0xff);
+            }
+        }
+        out.extend_from_slice(&[0, 1]);
+    }
+
+    fn read_key(input: &mut &[u8]) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        loop {
+            let byte = take(input, 1)?[0];
+            if byte != 0 {
+                bytes.push(byte);
+                continue;
+            }
+            match take(input, 1)?[0] {
+                0xff => bytes.push(0),
+                1 => return Ok(bytes),
+                _ => return Err(invalid("bad escape in key")),
+            }
+        }
+    }
+}
+
+impl OrderedKey for String {
+    fn write_key(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().write_key(out);
+    }
+
+    fn read_key(input: &mut &[u8]) -> io::Result<String> {
+        String::from_utf8(Vec::read_key(input)?).map_err(|_| invalid("key is not utf-8"))
+    }
+}
+
+impl<A: OrderedKey, B: OrderedKey> OrderedKey for (A, B) {
+    fn write_key(&self, out: &mut Vec<u8>) {
+        self.0.write_key(out);
+        self.1.write_key(out);
+    }
+
+    fn read_key(input: &mut &[u8]) -> io::Result<(A, B)> {
+        Ok((A::read_key(input)?, B::read_key(input)?))
+    }
+}
+
+impl<A: OrderedKey, B: OrderedKey, C: OrderedKey> OrderedKey for (A, B, C) {
+    fn write_key(&self, out: &mut Vec<u8>) {
+        self.0.write_key(out);
+        self.1.write_key(out);
+        self.2.write_key(out);
+    }
+
+    fn read_key(input: &mut &[u8]) -> io::Result<(A, B, C)> {
+        Ok((A::read_key(input)?, B::read_key(input)?, C::read_key(input)?))
+    }
+}
+
+impl<A: OrderedKey, B: OrderedKey, C: OrderedKey, D: OrderedKey> OrderedKey for (A, B, C, D) {
+    fn write_key(&self, out: &mut Vec<u8>) {
+        self.0.write_key(out);
+        self.1.write_key(out);
+        self.2.write_key(out);
+        self.3.write_key(out);
+    }
+
+    fn read_key(input: &mut &[u8]) -> io::Result<(A, B, C, D)> {
+        Ok((
+            A::read_key(input)?,
+            B::read_key(input)?,
+            C::read_key(input)?,
+            D::read_key(input)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<T: OrderedKey>(key: &T) -> Vec<u8> {
+        let mut out = vec![];
+        Ordered.encode(key, &mut out);
+        out
+    }
+
+    fn assert_ordered<T: OrderedKey + Ord + Clone + ::std::fmt::Debug>(mut keys: Vec<T>) {
+        keys.sort();
+        let mut encoded: Vec<Vec<u8>> = keys.iter().map(encode).collect();
+        encoded.sort();
+        let decoded: Vec<T> = encoded
+            .iter()
+            .map(|data| Ordered.decode(data).ok().unwrap())
+            .collect();
+        assert_eq!(keys, decoded);
+    }
+
+    #[test]
+    fn integer_order() {
+        assert_ordered(vec![300u64, 0, 7, u64::MAX, 256]);
+        assert_ordered(vec![-300i32, 5, 0, -1, i32::MIN, i32::MAX]);
+        assert_ordered(vec![-1i8, 1, -128, 127]);
+    }
+
+    #[test]
+    fn string_order() {
+        assert_ordered(vec![
+            b"a".to_vec(),
+            b"a\0".to_vec(),
+            b"a\0\0b".to_vec(),
+            b"".to_vec(),
+            b"ab".to_vec(),
+            vec![0xff],
+        ]);
+        assert_ordered(vec!["b".to_string(), "a".to_string(), "ab".to_string()]);
+    }
+
+    #[test]
+    fn tuple_order() {
+        assert_ordered(vec![
+            ("a".to_string(), 2u32),
+            ("ab".to_string(), 1u32),
+            ("a".to_string(), 10u32),
+        ]);
+        assert_ordered(vec![
+            (1i64, b"x".to_vec(), -2i16),
+            (1i64, b"".to_vec(), 5i16),
+            (-1i64, b"z".to_vec(), 0i16),
+        ]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        let data = encode(&"key".to_string());
+        assert!(Codec::<String>::decode(&Ordered, &data[..data.len() - 1]).is_err());
+        assert!(Codec::<u32>::decode(&Ordered, &[0, 0, 0, 1, 2]).is_err());
+        assert!(Utf8.decode(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+/// Bounds of a range of keys.
+pub type KeyRange<'k> = (Bound<&'k [u8]>, Bound<&'k [u8]>);
+
+/// Orders the keys of a tree. The name is stored in the manifest,
+/// and a tree can only be opened with a comparator of the same name.
+pub trait Comparator: Send + Sync {
+    fn name(&self) -> &str;
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// Whether keys only compare equal when their bytes are equal.
+    /// Leaf filters hash the key bytes and are only kept if so.
+    fn exact(&self) -> bool {
+        true
+    }
+
+    /// Whether the keys starting with any one prefix sort next to each
+    /// other. Prefix scans only visit the children whose range can hold
+    /// such keys if so, and every child otherwise.
+    fn groups_prefixes(&self) -> bool {
+        false
+    }
+}
+
+/// Lexicographic order of the raw bytes.
+pub struct Bytewise;
+
+/// The reverse of lexicographic byte order.
+pub struct Reverse;
+
+/// Lexicographic order ignoring ASCII case.
+pub struct CaseInsensitive;
+
+/// Numeric order of keys that hold fixed-width integers. Keys of
+/// different widths are ordered by width first.
+pub struct Integer {
+    pub signed: bool,
+    pub little_endian: bool,
+}
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn groups_prefixes(&self) -> bool {
+        true
+    }
+}
+
+impl Comparator for Reverse {
+    fn name(&self) -> &str {
+        "reverse"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn groups_prefixes(&self) -> bool {
+        true
+    }
+}
+
+impl Comparator for CaseInsensitive {
+    fn name(&self) -> &str {
+        "case-insensitive"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let a = a.iter().map(|c| c.to_ascii_lowercase());
+        let b = b.iter().map(|c| c.to_ascii_lowercase());
+        a.cmp(b)
+    }
+
+    fn exact(&self) -> bool {
+        false
+    }
+}
+
+impl Comparator for Integer {
+    fn name(&self) -> &str {
+        match (self.signed, self.little_endian) {
+            (false, false) => "uint-be",
+            (false, true) => "uint-le",
+            (true, false) => "int-be",
+            (true, true) => "int-le",
+        }
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        if a.len() != b.len() || a.is_empty() {
+            return a.len().cmp(&b.len());
+        }
+        let (a, b) = if self.little_endian {
+            (a.iter().rev().cloned().collect(), b.iter().rev().cloned().collect())
+        } else {
+            (a.to_vec(), b.to_vec())
+        };
+        if self.signed && (a[0] ^ b[0]) & 0x80 != 0 {
+            // the negative number has the sign bit set
+            return b[0].cmp(&a[0]);
+        }
+        a.cmp(&b)
+    }
+}
+
+/// Whether `key` lies within `range` under the order of `cmp`.
+pub fn in_range(cmp: &Comparator, range: &KeyRange, key: &[u8]) -> bool {
+    let above = match range.0 {
+        Bound::Included(start) => cmp.compare(key, start) != Ordering::Less,
+        Bound::Excluded(start) => cmp.compare(key, start) == Ordering::Greater,
+        Bound::Unbounded => true,
+    };
+    let below = match range.1 {
+        Bound::Included(end) => cmp.compare(key, end) != Ordering::Greater,
+        Bound::Excluded(end) => cmp.compare(key, end) == Ordering::Less,
+        Bound::Unbounded => true,
+    };
+    above && below
+}
+
+/// The shortest prefix of `right` that sorts after `left` and not after
+/// `right`, to divide them as a pivot. `left` must sort before `right`.
+pub fn separator(cmp: &Comparator, left: &[u8], right: &[u8]) -> Vec<u8> {
+    for len in 1..right.len() {
+        let prefix = &right[..len];
+        if cmp.compare(left, prefix) == Ordering::Less && cmp.compare(prefix, right) != Ordering::Greater {
+            return prefix.to_vec();
+        }
+    }
+    right.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(cmp: &Comparator, mut keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        keys.sort_by(|a, b| cmp.compare(a, b));
+        keys
+    }
+
+    #[test]
+    fn orderings() {
+        let keys = vec![b"b".to_vec(), b"A".to_vec(), b"a1".to_vec(), b"c".to_vec()];
+        let expected = vec![b"A".to_vec(), b"a1".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(expected, sorted(&Bytewise, keys.clone()));
+        let expected = vec![b"c".to_vec(), b"b".to_vec(), b"a1".to_vec(), b"A".to_vec()];
+        assert_eq!(expected, sorted(&Reverse, keys.clone()));
+        assert_eq!(Ordering::Equal, CaseInsensitive.compare(b"Key", b"kEY"));
+        assert_eq!(Ordering::Less, CaseInsensitive.compare(b"a", b"B"));
+    }
+
+    #[test]
+    fn integers() {
+        let le = Integer {
+            signed: true,
+            little_endian: true,
+        };
+        let keys = vec![
+            300i32.to_le_bytes().to_vec(),
+            (-5i32).to_le_bytes().to_vec(),
+            2i32.to_le_bytes().to_vec(),
+            (-300i32).to_le_bytes().to_vec(),
+        ];
+        let expected = vec![
+            (-300i32).to_le_bytes().to_vec(),
+            (-5i32).to_le_bytes().to_vec(),
+            2i32.to_le_bytes().to_vec(),
+            300i32.to_le_bytes().to_vec(),
+        ];
+        assert_eq!(expected, sorted(&le, keys));
+        let be = Integer {
+            signed: false,
+            little_endian: false,
+        };
+        assert_eq!(Ordering::Greater, be.compare(&[1, 0], &[0, 255]));
+        assert_eq!("int-le", le.name());
+    }
+
+    #[test]
+    fn key_range() {
+        let range = (Bound::Included(&b"b"[..]), Bound::Excluded(&b"d"[..]));
+        assert!(in_range(&Bytewise, &range, b"b"));
+        assert!(in_range(&Bytewise, &range, b"c"));
+        assert!(!in_range(&Bytewise, &range, b"d"));
+        assert!(!in_range(&Reverse, &range, b"c"));
+        assert!(in_range(&CaseInsensitive, &range, b"C"));
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(b"abd".to_vec(), separator(&Bytewise, b"abc", b"abdef"));
+        assert_eq!(b"abc".to_vec(), separator(&Bytewise, b"ab", b"abcd"));
+        assert_eq!(b"b".to_vec(), separator(&Bytewise, b"a/zzz", b"b/aaa"));
+        assert_eq!(b"/docs/b".to_vec(), separator(&Bytewise, b"/docs/a/index.html", b"/docs/b/index.html"));
+        assert_eq!(b"ab".to_vec(), separator(&Reverse, b"c", b"ab"));
+        assert_eq!(b"B".to_vec(), separator(&CaseInsensitive, b"a", b"Bc"));
+        let be = Integer {
+            signed: false,
+            little_endian: false,
+        };
+        assert_eq!(vec![0, 2], separator(&be, &[0, 1], &[0, 2]));
+    }
+}
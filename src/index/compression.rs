@@ -0,0 +1,106 @@
+use std::io;
+
+use lz4_flex;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Deflate,
+}
+
+impl Compression {
+    pub fn serialize(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Deflate => 2,
+        }
+    }
+
+    pub fn deserialize(val: u8) -> io::Result<Compression> {
+        match val {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Deflate),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression codec {}", val),
+            )),
+        }
+    }
+
+    pub fn compress(self, input: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => input.to_vec(),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(input),
+            Compression::Deflate => compress_to_vec(input, 6),
+        }
+    }
+
+    pub fn decompress(self, input: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(input.to_vec()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(input)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+            Compression::Deflate => decompress_to_vec(input)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+        }
+    }
+}
+
+/// Selects the codec for a node by its level in the tree.
+/// The last entry applies to every level above it, so a
+/// single entry compresses the whole tree the same way.
+pub struct LevelCompression {
+    pub levels: Vec<Compression>,
+}
+
+impl LevelCompression {
+    pub fn new(levels: Vec<Compression>) -> LevelCompression {
+        LevelCompression { levels: levels }
+    }
+
+    pub fn none() -> LevelCompression {
+        LevelCompression::new(vec![])
+    }
+
+    pub fn for_level(&self, level: u32) -> Compression {
+        let level = level as usize;
+        match self.levels.last() {
+            None => Compression::None,
+            Some(last) => *self.levels.get(level).unwrap_or(last),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_codecs() {
+        let input = b"{\"key\": \"value\", \"key\": \"value\", \"key\": \"value\"}".to_vec();
+        for codec in &[Compression::None, Compression::Lz4, Compression::Deflate] {
+            let output = codec.compress(&input);
+            let output = codec.decompress(&output);
+            assert!(output.is_ok());
+            assert_eq!(input, output.unwrap());
+        }
+        let output = Compression::Deflate.compress(&input);
+        assert!(output.len() < input.len());
+    }
+
+    #[test]
+    fn level_compression() {
+        let input = LevelCompression::none();
+        assert_eq!(Compression::None, input.for_level(0));
+        assert_eq!(Compression::None, input.for_level(3));
+        let input = LevelCompression::new(vec![Compression::Deflate, Compression::Lz4]);
+        assert_eq!(Compression::Deflate, input.for_level(0));
+        assert_eq!(Compression::Lz4, input.for_level(1));
+        assert_eq!(Compression::Lz4, input.for_level(5));
+    }
+}
@@ -0,0 +1,279 @@
+use super::store::Blocks;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::Key;
+use chacha20poly1305::Nonce;
+use rand::OsRng;
+use rand::Rng;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+
+/// Keys addressed by id. New blocks are sealed with the active key,
+/// older keys are kept so that existing blocks remain readable.
+pub struct Keyring {
+    pub keys: HashMap<u32, [u8; KEY_LEN]>,
+    pub active: u32,
+}
+
+/// Seals every block with ChaCha20-Poly1305. Each block is stored as
+/// the key id, the nonce and the ciphertext. The block id is bound as
+/// associated data so that blocks cannot be swapped between nodes.
+pub struct EncryptedStore<B: Blocks> {
+    pub blocks: B,
+    pub keyring: Keyring,
+    // opened by the first write
+    rng: Option<OsRng>,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_key(hex: &str) -> io::Result<[u8; KEY_LEN]> {
+    // checking the digits first keeps the slicing below on char boundaries
+    if hex.len() != 2 * KEY_LEN || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(invalid_data(format!(
+            "key must be {} hex digits",
+            2 * KEY_LEN
+        )));
+    }
+    let mut key = [0 as u8; KEY_LEN];
+    for i in 0..KEY_LEN {
+        key[i] = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|err| invalid_data(err.to_string()))?;
+    }
+    Ok(key)
+}
+
+impl Keyring {
+    pub fn new(id: u32, key: [u8; KEY_LEN]) -> Keyring {
+        let mut keys = HashMap::new();
+        keys.insert(id, key);
+        Keyring {
+            keys: keys,
+            active: id,
+        }
+    }
+
+    /// Parses a keyfile with one `<id> <hex key>` pair per line.
+    /// Blank lines and lines starting with `#` are ignored, any other
+    /// line with more or fewer fields is an error.
+    /// The key with the highest id becomes the active key.
+    pub fn parse(input: &str) -> io::Result<Keyring> {
+        let mut keyring: Option<Keyring> = None;
+        for line in input.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let id = fields.next().unwrap();
+            let id = id.parse::<u32>().map_err(
+                |err| invalid_data(err.to_string()),
+            )?;
+            let key = match fields.next() {
+                Some(val) => parse_key(val)?,
+                None => return Err(invalid_data(format!("key {} is missing", id))),
+            };
+            if fields.next().is_some() {
+                return Err(invalid_data(format!("unexpected fields after key {}", id)));
+            }
+            match keyring {
+                Some(ref mut keyring) => {
+                    if keyring.keys.insert(id, key).is_some() {
+                        return Err(invalid_data(format!("duplicate key id {}", id)));
+                    }
+                    keyring.active = keyring.active.max(id);
+                }
+                None => keyring = Some(Keyring::new(id, key)),
+            }
+        }
+        keyring.ok_or_else(|| invalid_data("keyfile contains no keys".to_string()))
+    }
+
+    pub fn load(path: &Path) -> io::Result<Keyring> {
+        let mut file = File::open(path)?;
+        let mut input = String::new();
+        file.read_to_string(&mut input)?;
+        Keyring::parse(&input)
+    }
+
+    /// Adds a key and makes it the active key. Blocks sealed with
+    /// the previous key are re-encrypted when they are copied on write.
+    pub fn rotate(&mut self, id: u32, key: [u8; KEY_LEN]) -> io::Result<()> {
+        if self.keys.contains_key(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("duplicate key id {}", id),
+            ));
+        }
+        self.keys.insert(id, key);
+        self.active = id;
+        Ok(())
+    }
+
+    fn cipher(&self, id: u32) -> io::Result<ChaCha20Poly1305> {
+        match self.keys.get(&id) {
+            Some(key) => Ok(ChaCha20Poly1305::new(Key::from_slice(key))),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("key {} not found", id),
+            )),
+        }
+    }
+}
+
+fn associated_data(id: u64, key_id: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(12);
+    aad.write_u64::<LittleEndian>(id).unwrap();
+    aad.write_u32::<LittleEndian>(key_id).unwrap();
+    aad
+}
+
+impl<B: Blocks> EncryptedStore<B> {
+    pub fn new(blocks: B, keyring: Keyring) -> EncryptedStore<B> {
+        EncryptedStore {
+            blocks: blocks,
+            keyring: keyring,
+            rng: None,
+        }
+    }
+
+    /// Returns the id of the key that sealed a block.
+    pub fn key_id(&self, id: u64) -> io::Result<u32> {
+        let input = self.blocks.read(id)?;
+        Cursor::new(input).read_u32::<LittleEndian>()
+    }
+}
+
+impl<B: Blocks> Blocks for EncryptedStore<B> {
+    fn read(&self, id: u64) -> io::Result<Vec<u8>> {
+        let input = self.blocks.read(id)?;
+        let mut rdr = Cursor::new(&input[..]);
+        let key_id = rdr.read_u32::<LittleEndian>()?;
+        let mut nonce = [0 as u8; NONCE_LEN];
+        rdr.read_exact(&mut nonce)?;
+        let offset = rdr.position() as usize;
+        let aad = associated_data(id, key_id);
+        let payload = Payload {
+            msg: &input[offset..],
+            aad: &aad,
+        };
+        self.keyring
+            .cipher(key_id)?
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| invalid_data(format!("block {} failed authentication", id)))
+    }
+
+    fn write(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        let key_id = self.keyring.active;
+        let mut nonce = [0 as u8; NONCE_LEN];
+        if self.rng.is_none() {
+            self.rng = Some(OsRng::new()?);
+        }
+        if let Some(ref mut rng) = self.rng {
+            rng.fill_bytes(&mut nonce);
+        }
+        let aad = associated_data(id, key_id);
+        let payload = Payload {
+            msg: data,
+            aad: &aad,
+        };
+        let sealed = self.keyring
+            .cipher(key_id)?
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| invalid_data(format!("block {} failed encryption", id)))?;
+        let mut output = Vec::with_capacity(4 + NONCE_LEN + sealed.len());
+        output.write_u32::<LittleEndian>(key_id)?;
+        output.write_all(&nonce)?;
+        output.write_all(&sealed)?;
+        self.blocks.write(id, &output)
+    }
+
+    fn remove(&mut self, id: u64) -> io::Result<()> {
+        self.blocks.remove(id)
+    }
+
+    fn last_id(&self) -> io::Result<u64> {
+        self.blocks.last_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::store::MemStore;
+
+    #[test]
+    fn roundtrip_encrypted_block() {
+        let mut store = EncryptedStore::new(MemStore::new(), Keyring::new(1, [7; KEY_LEN]));
+        assert!(store.write(3, b"hello world").is_ok());
+        assert!(!store.blocks.blocks[&3].windows(5).any(|w| w == b"hello"));
+        let output = store.read(3);
+        assert!(output.is_ok());
+        assert_eq!(b"hello world".to_vec(), output.unwrap());
+    }
+
+    #[test]
+    fn tampered_block() {
+        let mut store = EncryptedStore::new(MemStore::new(), Keyring::new(1, [7; KEY_LEN]));
+        assert!(store.write(3, b"hello world").is_ok());
+        let last = store.blocks.blocks[&3].len() - 1;
+        store.blocks.blocks.get_mut(&3).unwrap()[last] ^= 1;
+        assert!(store.read(3).is_err());
+        let moved = store.blocks.blocks[&3].clone();
+        store.blocks.blocks.insert(4, moved);
+        assert!(store.read(4).is_err());
+    }
+
+    #[test]
+    fn rotate_keys() {
+        let mut store = EncryptedStore::new(MemStore::new(), Keyring::new(1, [7; KEY_LEN]));
+        assert!(store.write(3, b"foo").is_ok());
+        assert!(store.keyring.rotate(1, [8; KEY_LEN]).is_err());
+        assert!(store.keyring.rotate(2, [8; KEY_LEN]).is_ok());
+        assert!(store.write(4, b"bar").is_ok());
+        assert_eq!(1, store.key_id(3).unwrap());
+        assert_eq!(2, store.key_id(4).unwrap());
+        assert_eq!(b"foo".to_vec(), store.read(3).unwrap());
+        assert_eq!(b"bar".to_vec(), store.read(4).unwrap());
+    }
+
+    #[test]
+    fn parse_keyfile() {
+        let input = format!(
+            "# keys\n\n2 {}\n1 {}\n",
+            "ab".repeat(KEY_LEN),
+            "01".repeat(KEY_LEN)
+        );
+        let keyring = Keyring::parse(&input);
+        assert!(keyring.is_ok());
+        let keyring = keyring.unwrap();
+        assert_eq!(2, keyring.active);
+        assert_eq!([0xab; KEY_LEN], keyring.keys[&2]);
+        assert_eq!([0x01; KEY_LEN], keyring.keys[&1]);
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("1 abcd").is_err());
+        assert!(Keyring::parse(&format!("1 {}", "\u{e9}".repeat(KEY_LEN))).is_err());
+        assert!(Keyring::parse(&format!("1 +{}", "a".repeat(2 * KEY_LEN - 1))).is_err());
+        assert!(Keyring::parse(&format!("1 {}\n1 {}", "ab".repeat(32), "ab".repeat(32))).is_err());
+        assert!(Keyring::parse(&format!("1 {} 2", "ab".repeat(KEY_LEN))).is_err());
+        assert!(Keyring::parse(&format!("1 {} # old", "ab".repeat(KEY_LEN))).is_err());
+    }
+}
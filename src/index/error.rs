@@ -5,4 +5,6 @@ use std::io;
 pub enum ErrorType {
     IO(io::Error),
     Msg(String),
+    /// A key read by a transaction was changed before it committed.
+    Conflict(Vec<u8>),
 }
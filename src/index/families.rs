@@ -0,0 +1,546 @@
+use super::batch::WriteBatch;
+use super::comparator::Comparator;
+use super::error::ErrorType;
+use super::manifest::FamilyManifest;
+use super::manifest::Manifest;
+use super::mode::Mode;
+use super::secondary::Index;
+use super::secondary::IndexFn;
+use super::store::Overlay;
+use super::store::Store;
+use super::transaction::Transaction;
+use super::tree::Tree;
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// A key and its value.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Several named trees in one store. The trees have their own roots,
+/// comparators and settings but take node ids from a shared counter
+/// that starts after the highest id in the store, and a commit that
+/// covers several of them is applied atomically.
+pub struct Families {
+    ids: Arc<AtomicU64>,
+    trees: BTreeMap<String, Tree>,
+    // trees recorded in the manifest that have not been opened yet
+    closed: BTreeMap<String, Manifest>,
+    indexes: Vec<Index>,
+}
+
+// what a commit changes in a tree, restored if another tree fails
+struct Saved {
+    epoch: u64,
+    root: Option<u64>,
+    backlog: usize,
+    backlog_full: bool,
+}
+
+impl Saved {
+    fn of(tree: &Tree) -> Saved {
+        Saved {
+            epoch: tree.epoch,
+            root: tree.root,
+            backlog: tree.backlog,
+            backlog_full: tree.backlog_full,
+        }
+    }
+
+    fn restore(self, tree: &mut Tree) {
+        tree.epoch = self.epoch;
+        tree.root = self.root;
+        tree.backlog = self.backlog;
+        tree.backlog_full = self.backlog_full;
+    }
+}
+
+impl Families {
+    /// Families in `store`, with no trees yet.
+    pub fn new<'a>(store: &Store<'a>) -> Result<Families, ErrorType> {
+        Families::open(&FamilyManifest::default(), store)
+    }
+
+    /// Reads the trees recorded in `manifest`. Each of them has to be
+    /// opened with `open_tree` before it can be used.
+    pub fn open<'a>(manifest: &FamilyManifest, store: &Store<'a>) -> Result<Families, ErrorType> {
+        Ok(Families {
+            ids: Arc::new(AtomicU64::new(store.last_id().map_err(ErrorType::IO)?)),
+            trees: BTreeMap::new(),
+            closed: manifest.trees.iter().cloned().collect(),
+            indexes: vec![],
+        })
+    }
+
+    /// Opens a tree recorded in the manifest. Fails if the tree is
+    /// unknown or was written with a different comparator.
+    pub fn open_tree(
+        &mut self,
+        name: &str,
+        comparator: Arc<Comparator>,
+        max_pivots: usize,
+        max_buffer: usize,
+        mode: Mode,
+    ) -> Result<&mut Tree, ErrorType> {
+        let mut tree = match self.closed.get(name) {
+            Some(manifest) => Tree::open(manifest, comparator, max_pivots, max_buffer, mode)?,
+            None => return Err(ErrorType::Msg(format!("no closed tree named {:?}", name))),
+        };
+        self.closed.remove(name);
+        tree.share_ids(self.ids.clone());
+        Ok(self.trees.entry(name.to_string()).or_insert(tree))
+    }
+
+    /// Adds an empty tree under `name`.
+    pub fn create(&mut self, name: &str, mut tree: Tree) -> Result<&mut Tree, ErrorType> {
+        if self.trees.contains_key(name) || self.closed.contains_key(name) {
+            return Err(ErrorType::Msg(format!("tree {:?} already exists", name)));
+        }
+        if tree.root.is_some() {
+            return Err(ErrorType::Msg(format!("tree {:?} is not empty", name)));
+        }
+        tree.share_ids(self.ids.clone());
+        Ok(self.trees.entry(name.to_string()).or_insert(tree))
+    }
+
+    pub fn tree(&self, name: &str) -> Option<&Tree> {
+        self.trees.get(name)
+    }
+
+    pub fn tree_mut(&mut self, name: &str) -> Option<&mut Tree> {
+        self.trees.get_mut(name)
+    }
+
+    /// Keeps the tree `index` up to date with the values written to
+    /// `primary`, under the secondary keys that `keys` maps them to.
+    /// Values written to `primary` before the index was registered are
+    /// not indexed.
+    pub fn register_index(&mut self, primary: &str, index: &str, keys: Box<IndexFn>) -> Result<(), ErrorType> {
+        for name in &[primary, index] {
+            if !self.trees.contains_key(*name) {
+                return Err(unknown(name));
+            }
+        }
+        if self.indexes.iter().any(|other| other.name == index) {
+            return Err(ErrorType::Msg(format!("tree {:?} is already an index", index)));
+        }
+        self.indexes.push(Index {
+            primary: primary.to_string(),
+            name: index.to_string(),
+            keys: keys,
+        });
+        Ok(())
+    }
+
+    /// Looks up `key` in the tree `index` and returns the primary keys
+    /// and values it refers to, in primary key order.
+    pub fn query<'a>(
+        &self,
+        store: &Store<'a>,
+        index: &str,
+        key: &[u8],
+    ) -> Result<Vec<Entry>, ErrorType> {
+        let found = match self.indexes.iter().find(|other| other.name == index) {
+            Some(found) => found,
+            None => return Err(ErrorType::Msg(format!("no index named {:?}", index))),
+        };
+        found.query(&self.trees[&found.primary], &self.trees[index], store, key)
+    }
+
+    pub fn manifest(&self) -> FamilyManifest {
+        let mut trees: Vec<_> = self.closed.clone().into_iter().collect();
+        for (name, tree) in &self.trees {
+            trees.push((name.clone(), tree.manifest()));
+        }
+        trees.sort_by(|a, b| a.0.cmp(&b.0));
+        FamilyManifest { trees: trees }
+    }
+
+    /// Applies one batch to each of the named trees, all or none.
+    pub fn write<'a, S>(&mut self, store: &mut S, batches: Vec<(&str, WriteBatch)>) -> Result<(), ErrorType>
+    where
+        S: Store<'a> + Sync,
+    {
+        let mut txns = vec![];
+        for (name, batch) in batches {
+            let mut txn = match self.trees.get(name) {
+                Some(tree) => tree.transaction(),
+                None => return Err(unknown(name)),
+            };
+            txn.writes = batch;
+            txns.push((name, txn));
+        }
+        self.commit(store, txns)
+    }
+
+    /// Commits an optimistic transaction on each of the named trees,
+    /// along with the writes that keep their indexes up to date. The
+    /// nodes and blobs of every tree are staged until all of them have
+    /// committed, so either every tree changes or none does.
+    pub fn commit<'a, S>(&mut self, store: &mut S, txns: Vec<(&str, Transaction)>) -> Result<(), ErrorType>
+    where
+        S: Store<'a> + Sync,
+    {
+        if let Some(&(name, _)) = txns.iter().find(|(name, _)| !self.trees.contains_key(*name)) {
+            return Err(unknown(name));
+        }
+        let mut txns: Vec<(String, Transaction)> = txns.into_iter().map(|(name, txn)| (name.to_string(), txn)).collect();
+        self.update_indexes(&*store, &mut txns)?;
+        let mut saved = vec![];
+        let staged = {
+            let mut overlay = Overlay::new(&*store);
+            let mut result = Ok(());
+            for (name, txn) in txns {
+                let tree = self.trees.get_mut(&name).expect("tree was checked");
+                saved.push((name, Saved::of(tree)));
+                result = tree.commit(&mut overlay, txn);
+                if result.is_err() {
+                    break;
+                }
+            }
+            result.map(|_| (overlay.blobs, overlay.nodes, overlay.deletes))
+        };
+        let written = staged.and_then(|(blobs, nodes, deletes)| {
+            let mut done = vec![];
+            let synced = blobs
+                .iter()
+                .try_for_each(|(id, data)| store.write_blob(*id, data).map(|_| done.push(*id)))
+                .and_then(|_| nodes.values().try_for_each(|node| store.write(node).map(|_| done.push(node.id()))))
+                .and_then(|_| store.sync());
+            if synced.is_err() {
+                // no root refers to what was written before the failure
+                for id in done {
+                    let _ = store.schedule_delete(id);
+                }
+            }
+            synced.map(|_| deletes).map_err(ErrorType::IO)
+        });
+        match written {
+            Ok(deletes) => {
+                for id in deletes {
+                    store.schedule_delete(id).map_err(ErrorType::IO)?;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                for (name, state) in saved.into_iter().rev() {
+                    state.restore(self.trees.get_mut(&name).expect("tree was checked"));
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // adds a transaction on each affected index tree, or extends the
+    // one the caller passed in
+    fn update_indexes<'a>(&self, store: &Store<'a>, txns: &mut Vec<(String, Transaction)>) -> Result<(), ErrorType> {
+        for index in &self.indexes {
+            let primaries: Vec<usize> = (0..txns.len()).filter(|&i| txns[i].0 == index.primary).collect();
+            if primaries.is_empty() {
+                continue;
+            }
+            let pos = match txns.iter().position(|(name, _)| *name == index.name) {
+                Some(pos) => pos,
+                None => {
+                    txns.push((index.name.clone(), self.trees[&index.name].transaction()));
+                    txns.len() - 1
+                }
+            };
+            let mut entries = mem::replace(&mut txns[pos].1, self.trees[&index.name].transaction());
+            for i in primaries {
+                index.update(
+                    &self.trees[&index.primary],
+                    store,
+                    &mut txns[i].1,
+                    &mut entries,
+                )?;
+            }
+            txns[pos].1 = entries;
+        }
+        Ok(())
+    }
+}
+
+fn unknown(name: &str) -> ErrorType {
+    ErrorType::Msg(format!("no tree named {:?}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::comparator::Bytewise;
+    use index::comparator::Reverse;
+    use index::compression::LevelCompression;
+    use index::node::Node;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    use std::cell::Cell;
+    use std::io;
+
+    fn get(families: &Families, store: &Store, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        match families.tree(name).unwrap().get(store, key) {
+            Ok(val) => val,
+            Err(_) => panic!("get failed"),
+        }
+    }
+
+    // fails every write after the first `writes_left`
+    struct FailingStore {
+        store: NodeStore<MemStore>,
+        writes_left: usize,
+    }
+
+    impl<'a> Store<'a> for FailingStore {
+        fn read(&self, id: u64) -> io::Result<Node<'a>> {
+            self.store.read(id)
+        }
+
+        fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+            if self.writes_left == 0 {
+                return Err(io::Error::other("write failed"));
+            }
+            self.writes_left -= 1;
+            self.store.write(node)
+        }
+
+        fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+            self.store.schedule_delete(id)
+        }
+
+        fn last_id(&self) -> io::Result<u64> {
+            self.store.last_id()
+        }
+    }
+
+    struct CountingStore {
+        store: NodeStore<MemStore>,
+        leaf_reads: Cell<usize>,
+    }
+
+    impl<'a> Store<'a> for CountingStore {
+        fn read(&self, id: u64) -> io::Result<Node<'a>> {
+            let node = self.store.read(id)?;
+            if node.body.level() == 0 {
+                self.leaf_reads.set(self.leaf_reads.get() + 1);
+            }
+            Ok(node)
+        }
+
+        fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+            self.store.write(node)
+        }
+
+        fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+            self.store.schedule_delete(id)
+        }
+
+        fn last_id(&self) -> io::Result<u64> {
+            self.store.last_id()
+        }
+    }
+
+    fn families(store: &Store) -> Families {
+        match Families::new(store) {
+            Ok(families) => families,
+            Err(_) => panic!("families could not be created"),
+        }
+    }
+
+    fn setup() -> (Families, NodeStore<MemStore>) {
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut families = families(&store);
+        assert!(families.create("main", Tree::new(4, 4, Mode::Test)).is_ok());
+        let mut index = Tree::new(4, 4, Mode::Test);
+        index.comparator = Arc::new(Reverse);
+        assert!(families.create("index", index).is_ok());
+        (families, store)
+    }
+
+    #[test]
+    fn write_several_trees() {
+        let (mut families, mut store) = setup();
+        for i in 0..50 {
+            let mut main = WriteBatch::new();
+            main.assign(format!("key{:02}", i).as_bytes(), format!("val{}", i).as_bytes());
+            let mut index = WriteBatch::new();
+            index.assign(format!("val{}", i).as_bytes(), format!("key{:02}", i).as_bytes());
+            assert!(families.write(&mut store, vec![("main", main), ("index", index)]).is_ok());
+        }
+        for i in 0..50 {
+            let key = format!("key{:02}", i).into_bytes();
+            let val = format!("val{}", i).into_bytes();
+            assert_eq!(Some(val.clone()), get(&families, &store, "main", &key));
+            assert_eq!(Some(key), get(&families, &store, "index", &val));
+        }
+        assert_eq!(None, get(&families, &store, "index", b"key01"));
+        assert!(families.create("main", Tree::new(4, 4, Mode::Test)).is_err());
+        assert!(families.write(&mut store, vec![("other", WriteBatch::new())]).is_err());
+    }
+
+    #[test]
+    fn conflict_rolls_back_every_tree() {
+        let (mut families, mut store) = setup();
+        let mut batch = WriteBatch::new();
+        batch.assign(b"a", b"1");
+        assert!(families.write(&mut store, vec![("index", batch)]).is_ok());
+        let blocks = store.blocks.blocks.len();
+        let root = families.tree("index").unwrap().root;
+
+        let mut main = families.tree("main").unwrap().transaction();
+        main.writes.assign(b"x", b"1");
+        let mut index = families.tree("index").unwrap().transaction();
+        index.reads.insert(b"a".to_vec(), None);
+        index.writes.assign(b"b", b"2");
+        match families.commit(&mut store, vec![("main", main), ("index", index)]) {
+            Err(ErrorType::Conflict(key)) => assert_eq!(b"a".to_vec(), key),
+            _ => panic!("commit did not conflict"),
+        }
+        assert_eq!(None, families.tree("main").unwrap().root);
+        assert_eq!(root, families.tree("index").unwrap().root);
+        assert_eq!(blocks, store.blocks.blocks.len());
+        assert_eq!(None, get(&families, &store, "index", b"b"));
+    }
+
+    #[test]
+    fn failed_write_leaves_no_nodes() {
+        let mut store = FailingStore {
+            store: NodeStore::new(MemStore::new(), LevelCompression::none()),
+            writes_left: usize::MAX,
+        };
+        let mut families = families(&store);
+        assert!(families.create("main", Tree::new(4, 4, Mode::Test)).is_ok());
+        assert!(families.create("other", Tree::new(4, 4, Mode::Test)).is_ok());
+        let batches = || {
+            let mut main = WriteBatch::new();
+            let mut other = WriteBatch::new();
+            for i in 0..20 {
+                main.assign(format!("key{:02}", i).as_bytes(), b"1");
+                other.assign(format!("key{:02}", i).as_bytes(), b"2");
+            }
+            vec![("main", main), ("other", other)]
+        };
+        store.writes_left = 3;
+        assert!(families.write(&mut store, batches()).is_err());
+        assert!(store.store.blocks.blocks.is_empty());
+        assert_eq!(None, families.tree("main").unwrap().root);
+        store.writes_left = usize::MAX;
+        assert!(families.write(&mut store, batches()).is_ok());
+        assert!(store.store.blocks.blocks.len() > 3);
+    }
+
+    #[test]
+    fn reopen_from_manifest() {
+        let (mut families, mut store) = setup();
+        let mut main = WriteBatch::new();
+        main.assign(b"k", b"v");
+        let mut index = WriteBatch::new();
+        index.assign(b"v", b"k");
+        assert!(families.write(&mut store, vec![("main", main), ("index", index)]).is_ok());
+        let mut data = vec![];
+        assert!(families.manifest().serialize(&mut data).is_ok());
+
+        let manifest = FamilyManifest::deserialize(&data).unwrap();
+        let mut reopened = match Families::open(&manifest, &store) {
+            Ok(reopened) => reopened,
+            Err(_) => panic!("open failed"),
+        };
+        assert!(reopened.open_tree("index", Arc::new(Bytewise), 4, 4, Mode::Test).is_err());
+        assert!(reopened.open_tree("index", Arc::new(Reverse), 4, 4, Mode::Test).is_ok());
+        assert!(reopened.open_tree("main", Arc::new(Bytewise), 4, 4, Mode::Test).is_ok());
+        assert_eq!(Some(b"k".to_vec()), get(&reopened, &store, "index", b"v"));
+        assert_eq!(manifest, reopened.manifest());
+
+        // new nodes must not reuse the ids of existing ones
+        let mut batch = WriteBatch::new();
+        batch.assign(b"k2", b"v2");
+        assert!(reopened.write(&mut store, vec![("main", batch)]).is_ok());
+        assert_eq!(Some(b"v".to_vec()), get(&reopened, &store, "main", b"k"));
+        assert_eq!(Some(b"k".to_vec()), get(&reopened, &store, "index", b"v"));
+    }
+
+    fn keys(pairs: Vec<Entry>) -> Vec<Vec<u8>> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn maintain_index() {
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut families = families(&store);
+        assert!(families.create("users", Tree::new(4, 4, Mode::Test)).is_ok());
+        assert!(families.create("by_tag", Tree::new(4, 4, Mode::Test)).is_ok());
+        // values are comma separated tags
+        let tags = |val: &[u8]| val.split(|c| *c == b',').map(|tag| tag.to_vec()).collect();
+        assert!(families.register_index("users", "by_tag", Box::new(tags)).is_ok());
+        assert!(families.register_index("users", "missing", Box::new(|_: &[u8]| vec![])).is_err());
+
+        for i in 0..30 {
+            let mut batch = WriteBatch::new();
+            let val = if i % 3 == 0 { "admin,dev" } else { "dev" };
+            batch.assign(format!("user{:02}", i).as_bytes(), val.as_bytes());
+            assert!(families.write(&mut store, vec![("users", batch)]).is_ok());
+        }
+        let query = |families: &Families, store: &NodeStore<MemStore>, tag: &[u8]| match families.query(store, "by_tag", tag) {
+            Ok(pairs) => pairs,
+            Err(_) => panic!("query failed"),
+        };
+        assert_eq!(30, query(&families, &store, b"dev").len());
+        let admins = query(&families, &store, b"admin");
+        assert_eq!(10, admins.len());
+        assert_eq!((b"user03".to_vec(), b"admin,dev".to_vec()), admins[1]);
+
+        let mut batch = WriteBatch::new();
+        batch
+            .assign(b"user00", b"dev")
+            .delete(b"user03")
+            .assign(b"user01", b"ops")
+            .merge(b"user01", b",admin");
+        assert!(families.write(&mut store, vec![("users", batch)]).is_ok());
+        let expected: Vec<Vec<u8>> = vec![b"user01".to_vec(), b"user06".to_vec()];
+        assert_eq!(expected, keys(query(&families, &store, b"admin"))[..2].to_vec());
+        assert_eq!(9, query(&families, &store, b"admin").len());
+        assert_eq!(28, query(&families, &store, b"dev").len());
+        assert_eq!(vec![b"user01".to_vec()], keys(query(&families, &store, b"ops")));
+        assert!(query(&families, &store, b"none").is_empty());
+    }
+
+    #[test]
+    fn query_deep_index() {
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut families = families(&store);
+        assert!(families.create("users", Tree::new(4, 4, Mode::Test)).is_ok());
+        assert!(families.create("by_tag", Tree::new(4, 4, Mode::Test)).is_ok());
+        let tags = |val: &[u8]| vec![val.to_vec()];
+        assert!(families.register_index("users", "by_tag", Box::new(tags)).is_ok());
+        // "t1" is a byte prefix of "t10" and "t11"
+        for chunk in 0..20 {
+            let mut batch = WriteBatch::new();
+            for i in chunk * 10..chunk * 10 + 10 {
+                batch.assign(format!("user{:03}", i).as_bytes(), format!("t{}", i % 12).as_bytes());
+            }
+            assert!(families.write(&mut store, vec![("users", batch)]).is_ok());
+        }
+        let root = families.tree("by_tag").unwrap().root.unwrap();
+        assert!(Store::read(&store, root).unwrap().body.level() > 1);
+
+        let store = CountingStore {
+            store: store,
+            leaf_reads: Cell::new(0),
+        };
+        let found = match families.query(&store, "by_tag", b"t1") {
+            Ok(pairs) => pairs,
+            Err(_) => panic!("query failed"),
+        };
+        let expected: Vec<Vec<u8>> = (0..200).filter(|i| i % 12 == 1).map(|i| format!("user{:03}", i).into_bytes()).collect();
+        assert_eq!(expected, keys(found));
+        // one leaf of the primary tree per match and a few of the index
+        assert!(store.leaf_reads.get() < expected.len() + 10, "{} leaf reads", store.leaf_reads.get());
+        match families.query(&store, "by_tag", b"t11") {
+            Ok(pairs) => assert_eq!(16, pairs.len()),
+            Err(_) => panic!("query failed"),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use super::leaf::Leaf;
+
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+/// How the filters kept for each leaf in its parent are built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterPolicy {
+    /// Bits of the key filter per leaf entry. About 1% of lookups for
+    /// absent keys read the leaf with 10 bits.
+    pub bits_per_key: usize,
+    /// Length of the key prefixes recorded for prefix scans, or 0 to
+    /// keep no prefix filter.
+    pub prefix_len: usize,
+}
+
+/// A Bloom filter over byte strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bloom {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+/// The filters of one leaf: its keys, and the prefixes of its keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeafFilter {
+    pub keys: Bloom,
+    /// Length of the prefixes in `prefixes`, or 0 without them.
+    pub prefix_len: usize,
+    pub prefixes: Option<Bloom>,
+}
+
+// 64-bit FNV-1a with the murmur3 finalizer to mix the low bits. It
+// must not change once filters have been written.
+fn hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in key {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+impl Bloom {
+    pub fn new(count: usize, bits_per_key: usize) -> Bloom {
+        let bits = (count * bits_per_key).max(64);
+        Bloom {
+            hashes: (bits_per_key * 69 / 100).clamp(1, 30) as u32,
+            bits: vec![0; bits.div_ceil(8)],
+        }
+    }
+
+    // the bits of a key, by double hashing
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = hash(key);
+        let (lo, hi) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = 8 * self.bits.len() as u64;
+        (0..u64::from(self.hashes)).map(move |i| (lo.wrapping_add(i.wrapping_mul(hi)) % len) as usize)
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for pos in self.positions(key).collect::<Vec<_>>() {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+
+    /// False if `key` was certainly not inserted.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.positions(key).all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+
+    pub fn size(&self) -> usize {
+        size_of::<u32>() + size_of::<u64>() + self.bits.len()
+    }
+
+    pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
+        wtr.write_u32::<LittleEndian>(self.hashes)?;
+        wtr.write_u64::<LittleEndian>(self.bits.len() as u64)?;
+        wtr.write_all(&self.bits)
+    }
+
+    pub fn deserialize(rdr: &mut Cursor<Vec<u8>>) -> io::Result<Bloom> {
+        let hashes = rdr.read_u32::<LittleEndian>()?;
+        let mut bits = vec![0; rdr.read_u64::<LittleEndian>()? as usize];
+        rdr.read_exact(&mut bits)?;
+        if hashes == 0 || bits.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty filter"));
+        }
+        Ok(Bloom {
+            hashes: hashes,
+            bits: bits,
+        })
+    }
+}
+
+impl FilterPolicy {
+    pub fn build(&self, leaf: &Leaf) -> LeafFilter {
+        let mut keys = Bloom::new(leaf.keys.len(), self.bits_per_key);
+        for key in &leaf.keys {
+            keys.insert(key.bytes());
+        }
+        let prefixes = if self.prefix_len == 0 {
+            None
+        } else {
+            let mut prefixes = Bloom::new(leaf.keys.len(), self.bits_per_key);
+            for key in &leaf.keys {
+                // shorter keys cannot match a prefix scan that is checked
+                if key.len() >= self.prefix_len {
+                    prefixes.insert(&key.bytes()[..self.prefix_len]);
+                }
+            }
+            Some(prefixes)
+        };
+        LeafFilter {
+            keys: keys,
+            prefix_len: self.prefix_len,
+            prefixes: prefixes,
+        }
+    }
+}
+
+impl LeafFilter {
+    /// False if the leaf certainly holds no entry for `key`.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// False if the leaf certainly holds no key that starts with
+    /// `prefix`. Prefixes shorter than the recorded ones always pass.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        match self.prefixes {
+            Some(ref prefixes) if prefix.len() >= self.prefix_len => {
+                prefixes.contains(&prefix[..self.prefix_len])
+            }
+            _ => true,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        let prefixes = self.prefixes.as_ref().map_or(0, |prefixes| prefixes.size());
+        size_of::<u32>() + self.keys.size() + prefixes
+    }
+
+    pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
+        wtr.write_u32::<LittleEndian>(self.prefix_len as u32)?;
+        self.keys.serialize(wtr)?;
+        if let Some(ref prefixes) = self.prefixes {
+            prefixes.serialize(wtr)?;
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(rdr: &mut Cursor<Vec<u8>>) -> io::Result<LeafFilter> {
+        let prefix_len = rdr.read_u32::<LittleEndian>()? as usize;
+        let keys = Bloom::deserialize(rdr)?;
+        let prefixes = if prefix_len == 0 {
+            None
+        } else {
+            Some(Bloom::deserialize(rdr)?)
+        };
+        Ok(LeafFilter {
+            keys: keys,
+            prefix_len: prefix_len,
+            prefixes: prefixes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::buf::Buf;
+
+    #[test]
+    fn leaf_filter() {
+        let keys: Vec<Buf> = (0..100).map(|i| Buf::Owned(format!("key{:03}", i).into_bytes())).collect();
+        let vals = keys.iter().map(|_| Buf::Owned(vec![])).collect();
+        let leaf = Leaf::new(keys, vals);
+        let policy = FilterPolicy {
+            bits_per_key: 10,
+            prefix_len: 4,
+        };
+        let filter = policy.build(&leaf);
+        for i in 0..100 {
+            assert!(filter.may_contain(format!("key{:03}", i).as_bytes()));
+        }
+        let misses = (100..1100).filter(|i| filter.may_contain(format!("key{:03}", i).as_bytes())).count();
+        assert!(misses < 50, "{} false positives", misses);
+        assert!(filter.may_contain_prefix(b"key0"));
+        assert!(filter.may_contain_prefix(b"key05"));
+        assert!(filter.may_contain_prefix(b"k"));
+        assert!(!filter.may_contain_prefix(b"nope"));
+
+        let mut wtr = vec![];
+        assert!(filter.serialize(&mut wtr).is_ok());
+        assert_eq!(filter.size(), wtr.len());
+        let output = LeafFilter::deserialize(&mut Cursor::new(wtr.clone())).unwrap();
+        assert_eq!(filter, output);
+        wtr.truncate(wtr.len() - 1);
+        assert!(LeafFilter::deserialize(&mut Cursor::new(wtr)).is_err());
+    }
+}
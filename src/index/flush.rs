@@ -0,0 +1,201 @@
+/// Buffered messages of an internal node bound for one child.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pending {
+    pub child: usize,
+    pub count: usize,
+    pub bytes: usize,
+    /// Arrival order of the oldest message, smaller is older.
+    pub oldest: usize,
+}
+
+/// Chooses which children receive buffered messages when an
+/// internal node flushes. `pending` holds one entry for each
+/// child with buffered messages, ordered by child index.
+pub trait FlushPolicy: Send {
+    fn select(&mut self, pending: &[Pending]) -> Vec<usize>;
+
+    /// A policy of the same kind for a flush worker.
+    fn fork(&self) -> Box<FlushPolicy>;
+}
+
+/// Flushes the child with the most buffered messages.
+pub struct LargestRun;
+
+/// Flushes the child with the most buffered bytes.
+pub struct LargestBytes;
+
+/// Flushes each child in turn.
+pub struct RoundRobin {
+    pub next: usize,
+}
+
+/// Flushes the child that holds the oldest buffered message.
+pub struct OldestFirst;
+
+/// Flushes every child with buffered messages.
+pub struct FlushAll;
+
+/// Counters used to compare the write amplification of flush policies.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FlushStats {
+    pub flushes: u64,
+    pub children: u64,
+    pub messages: u64,
+    pub message_bytes: u64,
+    pub nodes_written: u64,
+    pub node_bytes: u64,
+}
+
+impl FlushPolicy for LargestRun {
+    fn select(&mut self, pending: &[Pending]) -> Vec<usize> {
+        match pending.iter().max_by_key(|p| p.count) {
+            Some(p) => vec![p.child],
+            None => vec![],
+        }
+    }
+
+    fn fork(&self) -> Box<FlushPolicy> {
+        Box::new(LargestRun)
+    }
+}
+
+impl FlushPolicy for LargestBytes {
+    fn select(&mut self, pending: &[Pending]) -> Vec<usize> {
+        match pending.iter().max_by_key(|p| p.bytes) {
+            Some(p) => vec![p.child],
+            None => vec![],
+        }
+    }
+
+    fn fork(&self) -> Box<FlushPolicy> {
+        Box::new(LargestBytes)
+    }
+}
+
+impl FlushPolicy for RoundRobin {
+    fn select(&mut self, pending: &[Pending]) -> Vec<usize> {
+        let next = self.next;
+        let choice = pending
+            .iter()
+            .find(|p| p.child >= next)
+            .or_else(|| pending.first());
+        match choice {
+            Some(p) => {
+                self.next = p.child + 1;
+                vec![p.child]
+            }
+            None => vec![],
+        }
+    }
+
+    fn fork(&self) -> Box<FlushPolicy> {
+        Box::new(RoundRobin { next: self.next })
+    }
+}
+
+impl FlushPolicy for OldestFirst {
+    fn select(&mut self, pending: &[Pending]) -> Vec<usize> {
+        match pending.iter().min_by_key(|p| p.oldest) {
+            Some(p) => vec![p.child],
+            None => vec![],
+        }
+    }
+
+    fn fork(&self) -> Box<FlushPolicy> {
+        Box::new(OldestFirst)
+    }
+}
+
+impl FlushPolicy for FlushAll {
+    fn select(&mut self, pending: &[Pending]) -> Vec<usize> {
+        pending.iter().map(|p| p.child).collect()
+    }
+
+    fn fork(&self) -> Box<FlushPolicy> {
+        Box::new(FlushAll)
+    }
+}
+
+impl FlushStats {
+    /// Adds the counters of a flush worker.
+    pub fn merge(&mut self, other: &FlushStats) {
+        self.flushes += other.flushes;
+        self.children += other.children;
+        self.messages += other.messages;
+        self.message_bytes += other.message_bytes;
+        self.nodes_written += other.nodes_written;
+        self.node_bytes += other.node_bytes;
+    }
+
+    pub fn record_write(&mut self, bytes: usize) {
+        self.nodes_written += 1;
+        self.node_bytes += bytes as u64;
+    }
+
+    /// Bytes written to nodes for each byte of flushed messages.
+    pub fn write_amplification(&self) -> f64 {
+        if self.message_bytes == 0 {
+            0.0
+        } else {
+            self.node_bytes as f64 / self.message_bytes as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending() -> Vec<Pending> {
+        vec![
+            Pending {
+                child: 0,
+                count: 3,
+                bytes: 30,
+                oldest: 2,
+            },
+            Pending {
+                child: 2,
+                count: 1,
+                bytes: 100,
+                oldest: 0,
+            },
+            Pending {
+                child: 3,
+                count: 3,
+                bytes: 6,
+                oldest: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn select_children() {
+        let input = pending();
+        assert_eq!(vec![3], LargestRun.select(&input));
+        assert_eq!(vec![2], LargestBytes.select(&input));
+        assert_eq!(vec![2], OldestFirst.select(&input));
+        assert_eq!(vec![0, 2, 3], FlushAll.select(&input));
+        assert!(LargestRun.select(&[]).is_empty());
+        assert!(FlushAll.select(&[]).is_empty());
+    }
+
+    #[test]
+    fn round_robin() {
+        let input = pending();
+        let mut policy = RoundRobin { next: 0 };
+        assert_eq!(vec![0], policy.select(&input));
+        assert_eq!(vec![2], policy.select(&input));
+        assert_eq!(vec![3], policy.select(&input));
+        assert_eq!(vec![0], policy.select(&input));
+    }
+
+    #[test]
+    fn write_amplification() {
+        let mut stats = FlushStats::default();
+        assert_eq!(0.0, stats.write_amplification());
+        stats.message_bytes = 10;
+        stats.node_bytes = 40;
+        assert_eq!(4.0, stats.write_amplification());
+    }
+}
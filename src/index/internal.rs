@@ -1,19 +1,36 @@
+use super::blob::BlobRef;
 use super::buf::Buf;
+use super::buffer::Buffer;
+use super::comparator::Comparator;
+use super::comparator::KeyRange;
+use super::filter::LeafFilter;
+use super::flush::Pending;
 use super::message::BufMessage;
 use super::message::Message;
+use super::node::NewChild;
 use super::node::NewSibling;
 use super::node::Body;
+use super::node::Node;
 use super::operation::Operation;
+use super::store::Overlay;
 use super::store::Store;
 use super::transaction::Transaction;
+use super::tree::Capacity;
 use super::tree::Tree;
 
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::io;
 use std::io::Cursor;
 use std::io::Write;
 use std::mem::size_of;
+use std::ops::Bound;
+use std::ops::Range;
 use std::slice::from_raw_parts_mut;
+use std::thread;
 
+use byteorder::ByteOrder;
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
@@ -23,12 +40,151 @@ pub struct Internal<'a> {
     #[allow(dead_code)]
     pub data: Vec<u8>,
     pub keys: Vec<Buf<'a>>,
-    pub buffer: Vec<BufMessage<'a>>,
+    pub buffer: Buffer<'a>,
     pub children: Vec<u64>,
+    /// Filters of the leaf children by id, kept in nodes of level 1
+    /// when the tree has a filter policy.
+    pub filters: BTreeMap<u64, LeafFilter>,
     pub serde: bool,
 }
 
+impl<'a> Clone for Internal<'a> {
+    fn clone(&self) -> Internal<'a> {
+        Internal {
+            level: self.level,
+            data: vec![],
+            keys: self.keys.clone(),
+            buffer: self.buffer.clone(),
+            children: self.children.clone(),
+            filters: self.filters.clone(),
+            serde: false,
+        }
+    }
+}
+
+// messages tagged with an epoch are serialized with this bit set in
+// their operation and the epoch in front of their data
+const TAGGED: u32 = 0x100;
+
+fn op_code(msg: &BufMessage) -> u32 {
+    if msg.epoch == 0 {
+        msg.op.serialize()
+    } else {
+        msg.op.serialize() | TAGGED
+    }
+}
+
+fn tag_len(msg: &BufMessage) -> usize {
+    if msg.epoch == 0 {
+        0
+    } else {
+        size_of::<u64>()
+    }
+}
+
+/// A child to flush on a worker: its index, id and messages.
+type Job = (usize, u64, Vec<Message>);
+
+/// A child after a worker has flushed into it.
+struct FlushedChild {
+    idx: usize,
+    id: u64,
+    newchild: Option<NewChild>,
+    filter: Option<LeafFilter>,
+    underflow: bool,
+}
+
+/// What a flush worker hands back to the parent.
+struct Flushed<'a> {
+    nodes: HashMap<u64, Node<'a>>,
+    deletes: Vec<u64>,
+    replaced: Vec<u64>,
+    children: Vec<FlushedChild>,
+}
+
+// flushes the messages of each job into its child, and compacts the
+// child as well when a range is given
+fn flush_group<'a>(
+    tree: &mut Tree,
+    base: &(Store<'a> + Sync),
+    jobs: Vec<Job>,
+    range: Option<&KeyRange>,
+) -> io::Result<Flushed<'a>> {
+    let mut overlay = Overlay::new(base);
+    let mut txn = Transaction::new(tree.epoch, tree.comparator.clone());
+    let mut children = vec![];
+    for (idx, id, msgs) in jobs {
+        let mut child = overlay.read(id)?;
+        let newchild = match range {
+            Some(range) => {
+                if !child.needs_compaction(tree, &msgs) {
+                    continue;
+                }
+                child.compact_msgs(tree, &mut overlay, &mut txn, msgs, range)?
+            }
+            None => child.upsert_msgs(tree, &mut overlay, &mut txn, msgs)?,
+        };
+        let underflow = newchild.is_none() && child.body.underflow(tree);
+        child.copy_on_write(tree, &mut txn);
+        overlay.write(&child)?;
+        tree.stats.record_write(child.size());
+        let filter = match child.body {
+            Body::Leaf(ref leaf) => tree.filter_policy().map(|policy| policy.build(leaf)),
+            Body::Internal(_) => None,
+        };
+        children.push(FlushedChild {
+            idx: idx,
+            id: child.id(),
+            newchild: newchild,
+            filter: filter,
+            underflow: underflow,
+        });
+    }
+    // blobs the worker dropped are deleted with the nodes it replaced
+    txn.delete.extend(tree.take_retired());
+    Ok(Flushed {
+        nodes: overlay.nodes,
+        deletes: overlay.deletes,
+        replaced: txn.delete,
+        children: children,
+    })
+}
+
 impl<'a> Internal<'a> {
+    pub fn new(
+        cmp: &Comparator,
+        level: u32,
+        keys: Vec<Buf<'a>>,
+        buffer: Vec<BufMessage<'a>>,
+        children: Vec<u64>,
+    ) -> Internal<'a> {
+        let buffer = Buffer::partition(cmp, &keys, children.len(), buffer);
+        Internal {
+            level: level,
+            data: vec![],
+            keys: keys,
+            buffer: buffer,
+            children: children,
+            filters: BTreeMap::new(),
+            serde: false,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        let mut total = size_of::<u32>() + 4 * size_of::<u64>();
+        total += self.keys.len() * size_of::<u64>();
+        total += 3 * self.children.len() * size_of::<u64>();
+        for key in &self.keys {
+            total += key.len();
+        }
+        total + self.buffer_size() + self.filter_size()
+    }
+
+    /// Serialized size of the leaf filters in bytes.
+    pub fn filter_size(&self) -> usize {
+        self.filters.values().map(|filter| size_of::<u64>() + filter.size()).sum()
+    }
+
     pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
         if self.serde {
             wtr.write_all(&self.data)?;
@@ -41,6 +197,7 @@ impl<'a> Internal<'a> {
         wtr.write_u64::<LittleEndian>(key_size as u64)?;
         wtr.write_u64::<LittleEndian>(buf_size as u64)?;
         wtr.write_u64::<LittleEndian>(child_size as u64)?;
+        wtr.write_u64::<LittleEndian>(self.filters.len() as u64)?;
 
         for key in &self.keys {
             let len = key.bytes().len();
@@ -51,29 +208,45 @@ impl<'a> Internal<'a> {
             wtr.write_u64::<LittleEndian>(*child)?;
         }
 
-        for msg in &self.buffer {
-            wtr.write_u32::<LittleEndian>(msg.op.serialize())?;
+        for part in &self.buffer.parts {
+            wtr.write_u64::<LittleEndian>(part.msgs.len() as u64)?;
+        }
+
+        for part in &self.buffer.parts {
+            wtr.write_u64::<LittleEndian>(part.oldest)?;
+        }
+
+        for msg in self.buffer.iter() {
+            wtr.write_u32::<LittleEndian>(op_code(msg))?;
         }
 
-        for msg in &self.buffer {
+        for msg in self.buffer.iter() {
             let len = msg.key.bytes().len();
             wtr.write_u64::<LittleEndian>(len as u64)?;
         }
 
-        for msg in &self.buffer {
-            let len = msg.data.bytes().len();
+        for msg in self.buffer.iter() {
+            let len = tag_len(msg) + msg.data.bytes().len();
             wtr.write_u64::<LittleEndian>(len as u64)?;
         }
 
         for key in &self.keys {
             wtr.write_all(key.bytes())?;
         }
-        for msg in &self.buffer {
+        for msg in self.buffer.iter() {
             wtr.write_all(msg.key.bytes())?;
         }
-        for msg in &self.buffer {
+        for msg in self.buffer.iter() {
+            if msg.epoch != 0 {
+                wtr.write_u64::<LittleEndian>(msg.epoch)?;
+            }
             wtr.write_all(msg.data.bytes())?;
         }
+        // filters follow the messages, counted in the header
+        for (id, filter) in &self.filters {
+            wtr.write_u64::<LittleEndian>(*id)?;
+            filter.serialize(wtr)?;
+        }
         Ok(())
     }
 
@@ -84,14 +257,15 @@ impl<'a> Internal<'a> {
         let key_size = rdr.read_u64::<LittleEndian>()? as usize;
         let buf_size = rdr.read_u64::<LittleEndian>()? as usize;
         let child_size = rdr.read_u64::<LittleEndian>()? as usize;
+        let filter_count = rdr.read_u64::<LittleEndian>()?;
 
         let mut keys = Vec::with_capacity(key_size);
         let mut buffer = Vec::with_capacity(buf_size);
         let mut children = Vec::with_capacity(child_size);
 
-        let mut offset = (size_of::<u32>() + 3 * size_of::<u64>()) as isize;
+        let mut offset = (size_of::<u32>() + 4 * size_of::<u64>()) as isize;
         offset += (key_size * size_of::<u64>()) as isize;
-        offset += (child_size * size_of::<u64>()) as isize;
+        offset += (3 * child_size * size_of::<u64>()) as isize;
         offset += (2 * buf_size * size_of::<u64>()) as isize;
         offset += (buf_size * size_of::<u32>()) as isize;
 
@@ -109,12 +283,21 @@ impl<'a> Internal<'a> {
             children.push(rdr.read_u64::<LittleEndian>()?)
         }
 
+        let mut counts = Vec::with_capacity(child_size);
+        for _ in 0..child_size {
+            counts.push(rdr.read_u64::<LittleEndian>()? as usize);
+        }
+
+        let mut oldest = Vec::with_capacity(child_size);
+        for _ in 0..child_size {
+            oldest.push(rdr.read_u64::<LittleEndian>()?);
+        }
+
+        let mut tagged = Vec::with_capacity(buf_size);
         for _ in 0..buf_size {
-            let msg = BufMessage {
-                op: Operation::deserialize(rdr.read_u32::<LittleEndian>()?),
-                key: Buf::Owned(vec![]),
-                data: Buf::Owned(vec![]),
-            };
+            let code = rdr.read_u32::<LittleEndian>()?;
+            tagged.push(code & TAGGED != 0);
+            let msg = BufMessage::new(Operation::deserialize(code & !TAGGED), Buf::Owned(vec![]), Buf::Owned(vec![]));
             buffer.push(msg);
         }
 
@@ -126,61 +309,124 @@ impl<'a> Internal<'a> {
             offset += len as isize;
         }
 
-        for buf in &mut buffer {
-            let len = rdr.read_u64::<LittleEndian>()? as usize;
+        for (buf, tagged) in buffer.iter_mut().zip(tagged) {
+            let mut len = rdr.read_u64::<LittleEndian>()? as usize;
+            if tagged {
+                if len < size_of::<u64>() || offset as usize + len > rdr.get_ref().len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated message epoch"));
+                }
+                buf.epoch = LittleEndian::read_u64(&rdr.get_ref()[offset as usize..]);
+                offset += size_of::<u64>() as isize;
+                len -= size_of::<u64>();
+            }
+            if buf.op.expiring() && len < size_of::<u64>() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated message expiry"));
+            }
             unsafe {
                 buf.data = Buf::Shared(from_raw_parts_mut(input_ptr.offset(offset), len));
             }
             offset += len as isize;
         }
 
+        let mut filters = BTreeMap::new();
+        rdr.set_position(offset as u64);
+        for _ in 0..filter_count {
+            let id = rdr.read_u64::<LittleEndian>()?;
+            filters.insert(id, LeafFilter::deserialize(&mut rdr)?);
+        }
+
+        let buffer = Buffer::from_counts(&counts, &oldest, buffer);
         Ok(Internal {
             level: level,
             data: rdr.into_inner(),
             keys: keys,
             buffer: buffer,
             children: children,
+            filters: filters,
             serde: false,
         })
     }
 
-    fn upsert(&mut self, msg: Message) {
-        self.buffer.push(msg.into_buf_message());
+    /// Serialized size of the buffered messages in bytes.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.size(size_of::<u32>() + 2 * size_of::<u64>())
     }
 
-    pub fn max_run(values: &[usize]) -> (usize, usize, usize) {
-        // This implementation could be replaced with a prefix scan
-        let state = (values[0], 0);
-        let count = values.iter().scan(state, |state, &val| {
-            let (prev, count) = *state;
-            let next = if prev == val { count + 1 } else { 1 };
-            *state = (val, next);
-            Some(next)
-        });
-        let (idx, len) = count.enumerate().max_by_key(|x| x.1).unwrap();
-        (idx - (len - 1), len, values[idx])
+    pub fn buffer_full(&self, tree: &Tree) -> bool {
+        match tree.capacity {
+            Capacity::Count => self.buffer.len() >= tree.max_buffer,
+            Capacity::Bytes { buffer, .. } => {
+                !self.buffer.is_empty() && self.buffer_size() >= buffer
+            }
+        }
     }
 
-    fn split(&mut self) -> NewSibling<'a> {
-        let key_size = self.keys.len();
-        let split = key_size / 2;
+    // serialized size of the pivots and children
+    fn pivot_size(&self) -> usize {
+        self.size() - self.buffer_size() - self.filter_size()
+    }
 
-        let mut right_msgs = vec![];
+    pub fn pivots_full(&self, tree: &Tree) -> bool {
+        match tree.capacity {
+            Capacity::Count => self.keys.len() >= tree.max_pivots,
+            Capacity::Bytes { pivots, .. } => {
+                self.keys.len() > 2 && self.pivot_size() >= pivots
+            }
+        }
+    }
 
-        // TODO: replace with drain_filter when it lands in stable
-        let mut i = 0;
-        while i < self.buffer.len() {
-            if self.buffer[i].key >= self.keys[split] {
-                right_msgs.push(self.buffer.swap_remove(i));
-            } else {
-                i += 1;
+    pub fn underflow(&self, tree: &Tree) -> bool {
+        match tree.capacity {
+            Capacity::Count => self.keys.len() * 100 < tree.low_water * tree.max_pivots,
+            Capacity::Bytes { pivots, .. } => {
+                self.pivot_size() * 100 < tree.low_water * pivots
             }
         }
+    }
+
+    /// Appends the pivots, children and buffered messages
+    /// of the right sibling, joined by the parent's separator.
+    pub fn merge(&mut self, separator: Vec<u8>, right: Internal<'a>) {
+        self.keys.push(Buf::Owned(separator));
+        self.keys.extend(right.keys.iter().cloned());
+        self.children.extend_from_slice(&right.children);
+        self.buffer.append(right.buffer);
+        self.filters.extend(right.filters);
+        self.serde = false;
+    }
+
+    // returns the blobs of the buffered assignments the message replaced
+    fn upsert(&mut self, cmp: &Comparator, msg: Message) -> Vec<BlobRef> {
+        let child = self.route(cmp, &msg.key);
+        self.buffer
+            .insert(cmp, child, msg.into_buf_message())
+            .iter()
+            .filter(|old| old.op.blob())
+            .filter_map(|old| BlobRef::decode(old.value()).ok())
+            .collect()
+    }
+
+    /// Buffered messages for `key`, oldest first.
+    pub fn buffered(&self, cmp: &Comparator, key: &[u8]) -> &[BufMessage<'a>] {
+        self.buffer.get(cmp, self.route(cmp, key), key)
+    }
+
+    /// Moves the upper half of the pivots to a new right sibling. The
+    /// middle pivot moves up as is: the left half may hold keys up to
+    /// it, and pivots are already shortened when leaves split.
+    pub fn split(&mut self) -> NewSibling<'a> {
+        let key_size = self.keys.len();
+        let split = key_size / 2;
+
+        let right = self.buffer.split_off(split + 1);
+        let counts: Vec<usize> = right.parts.iter().map(|part| part.msgs.len()).collect();
+        let oldest: Vec<u64> = right.parts.iter().map(|part| part.oldest).collect();
+        let right_msgs: Vec<BufMessage<'a>> = right.iter().cloned().collect();
 
         let mut total = size_of::<u32>();
-        total += 3 * size_of::<u64>();
+        total += 4 * size_of::<u64>();
         total += (key_size - split - 1) * size_of::<u64>();
-        total += (key_size - split) * size_of::<u64>();
+        total += 3 * (key_size - split) * size_of::<u64>();
         total += right_msgs.len() * size_of::<u32>();
         total += 2 * right_msgs.len() * size_of::<u64>();
 
@@ -193,7 +439,7 @@ impl<'a> Internal<'a> {
         }
 
         for msg in &right_msgs {
-            total += msg.data.bytes().len();
+            total += tag_len(msg) + msg.data.bytes().len();
         }
 
         let mut sib_data = Vec::with_capacity(total);
@@ -204,11 +450,12 @@ impl<'a> Internal<'a> {
             .write_u64::<LittleEndian>((key_size - split - 1) as u64)
             .unwrap();
         sib_data
-            .write_u64::<LittleEndian>((key_size - split) as u64)
+            .write_u64::<LittleEndian>(right_msgs.len() as u64)
             .unwrap();
         sib_data
-            .write_u64::<LittleEndian>(right_msgs.len() as u64)
+            .write_u64::<LittleEndian>((key_size - split) as u64)
             .unwrap();
+        sib_data.write_u64::<LittleEndian>(0).unwrap();
 
         for i in (split + 1)..key_size {
             sib_data
@@ -222,10 +469,16 @@ impl<'a> Internal<'a> {
                 .unwrap();
         }
 
+        for count in &counts {
+            sib_data.write_u64::<LittleEndian>(*count as u64).unwrap();
+        }
+
+        for seq in &oldest {
+            sib_data.write_u64::<LittleEndian>(*seq).unwrap();
+        }
+
         for msg in &right_msgs {
-            sib_data
-                .write_u32::<LittleEndian>(msg.op.serialize())
-                .unwrap();
+            sib_data.write_u32::<LittleEndian>(op_code(msg)).unwrap();
         }
 
         for msg in &right_msgs {
@@ -236,7 +489,7 @@ impl<'a> Internal<'a> {
 
         for msg in &right_msgs {
             sib_data
-                .write_u64::<LittleEndian>(msg.data.bytes().len() as u64)
+                .write_u64::<LittleEndian>((tag_len(msg) + msg.data.bytes().len()) as u64)
                 .unwrap();
         }
 
@@ -247,6 +500,9 @@ impl<'a> Internal<'a> {
             sib_data.write_all(msg.key.bytes()).unwrap();
         }
         for msg in &right_msgs {
+            if msg.epoch != 0 {
+                sib_data.write_u64::<LittleEndian>(msg.epoch).unwrap();
+            }
             sib_data.write_all(msg.data.bytes()).unwrap();
         }
 
@@ -258,9 +514,9 @@ impl<'a> Internal<'a> {
             sib_children.push(self.children[i]);
         }
 
-        let mut offset = (size_of::<u32>() + 3 * size_of::<u64>()) as isize;
+        let mut offset = (size_of::<u32>() + 4 * size_of::<u64>()) as isize;
         offset += ((key_size - split - 1) * size_of::<u64>()) as isize;
-        offset += ((key_size - split) * size_of::<u64>()) as isize;
+        offset += (3 * (key_size - split) * size_of::<u64>()) as isize;
         offset += (2 * right_msgs.len() * size_of::<u64>()) as isize;
         offset += (right_msgs.len() * size_of::<u32>()) as isize;
 
@@ -279,6 +535,7 @@ impl<'a> Internal<'a> {
                 op: msg.op,
                 key: Buf::Owned(vec![]),
                 data: Buf::Owned(vec![]),
+                epoch: msg.epoch,
             };
             sib_buffer.push(msg);
         }
@@ -293,6 +550,7 @@ impl<'a> Internal<'a> {
         }
 
         for i in 0..right_msgs.len() {
+            offset += tag_len(&right_msgs[i]) as isize;
             let len = right_msgs[i].data.bytes().len();
             sib_buffer[i].data = unsafe {
                 let data = from_raw_parts_mut(sib_ptr.offset(offset), len);
@@ -304,14 +562,22 @@ impl<'a> Internal<'a> {
         let split_key = self.keys[split].to_vec();
         self.keys.truncate(split);
         self.children.truncate(split + 1);
+        let sib_buffer = Buffer::from_counts(&counts, &oldest, sib_buffer);
+        let sib_filters: BTreeMap<u64, LeafFilter> = sib_children
+            .iter()
+            .filter_map(|id| self.filters.remove(id).map(|filter| (*id, filter)))
+            .collect();
 
+        // the data of the sibling holds no filters
+        let serde = sib_filters.is_empty();
         let body = Internal {
             level: self.level,
             data: sib_data,
             keys: sib_keys,
             buffer: sib_buffer,
             children: sib_children,
-            serde: true,
+            filters: sib_filters,
+            serde: serde,
         };
         NewSibling {
             key: split_key,
@@ -319,64 +585,397 @@ impl<'a> Internal<'a> {
         }
     }
 
+    /// Index of the child whose key range contains `key`.
+    pub fn route(&self, cmp: &Comparator, key: &[u8]) -> usize {
+        let pos = self.keys.binary_search_by(|probe| cmp.compare(probe.bytes(), key));
+        match pos {
+            Ok(val) => val + 1,
+            Err(val) => val,
+        }
+    }
+
+    /// Indices of the first and last child whose key ranges overlap `range`,
+    /// or `None` if the range is empty.
+    pub fn span(&self, cmp: &Comparator, range: &KeyRange) -> Option<(usize, usize)> {
+        let lo = match range.0 {
+            Bound::Included(key) | Bound::Excluded(key) => self.route(cmp, key),
+            Bound::Unbounded => 0,
+        };
+        let hi = match range.1 {
+            Bound::Included(key) => self.route(cmp, key),
+            Bound::Excluded(key) => {
+                // a child whose range starts at `key` is not included
+                let idx = self.route(cmp, key);
+                if idx > 0 && cmp.compare(self.keys[idx - 1].bytes(), key) == Ordering::Equal {
+                    idx - 1
+                } else {
+                    idx
+                }
+            }
+            Bound::Unbounded => self.children.len() - 1,
+        };
+        if lo <= hi {
+            Some((lo, hi))
+        } else {
+            None
+        }
+    }
+
+    /// Pushes every buffered message in `range` down to the leaves and
+    /// rewrites the children that received them or that hold expired
+    /// values. Buffers are not flushed on the way down, so the node
+    /// splits at most once, after all of its children have been
+    /// compacted.
+    pub fn compact(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        range: &KeyRange,
+    ) -> io::Result<Option<NewSibling<'a>>> {
+        let cmp = tree.comparator.clone();
+        let (lo, hi) = match self.span(&*cmp, range) {
+            Some(span) => span,
+            None => return Ok(None),
+        };
+        let mut jobs = vec![];
+        for child_idx in lo..(hi + 1) {
+            let mut msgs = vec![];
+            for msg in self.buffer.take_range(&*cmp, child_idx, range) {
+                tree.stats.message_bytes += (msg.key.len() + msg.data.len()) as u64;
+                msgs.push(msg.into_message());
+            }
+            if !msgs.is_empty() {
+                tree.stats.children += 1;
+                tree.stats.messages += msgs.len() as u64;
+            }
+            jobs.push((child_idx, self.children[child_idx], msgs));
+        }
+        if tree.flush_workers > 1 && jobs.len() > 1 && store.concurrent().is_some() {
+            self.flush_parallel(tree, store, txn, jobs, Some(range))?;
+        } else {
+            for (child_idx, id, msgs) in jobs.into_iter().rev() {
+                let mut child = store.read(id)?;
+                if !child.needs_compaction(tree, &msgs) {
+                    continue;
+                }
+                let newchild = child.compact_msgs(tree, store, txn, msgs, range)?;
+                self.replace_child(tree, store, txn, child_idx, child, newchild)?;
+            }
+        }
+        self.prune_filters();
+        if self.pivots_full(tree) {
+            Ok(Some(self.split()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Records the filter of a child that was just written, if it is
+    /// a leaf and the tree keeps filters.
+    pub fn filter_child(&mut self, tree: &Tree, child: &Node) {
+        if let Body::Leaf(ref leaf) = child.body {
+            if let Some(policy) = tree.filter_policy() {
+                self.filters.insert(child.id(), policy.build(leaf));
+            }
+        }
+    }
+
+    /// Records the filter, if any, of a child added after a split.
+    pub fn add_filter(&mut self, id: u64, filter: Option<LeafFilter>) {
+        if let Some(filter) = filter {
+            self.filters.insert(id, filter);
+        }
+    }
+
+    /// Drops the filters of leaves that are no longer children.
+    fn prune_filters(&mut self) {
+        let children = &self.children;
+        self.filters.retain(|id, _| children.contains(id));
+    }
+
+    /// False if the filter of `child` rules out `key`.
+    pub fn may_contain(&self, child: u64, key: &[u8]) -> bool {
+        self.filters.get(&child).is_none_or(|filter| filter.may_contain(key))
+    }
+
+    /// Indices of the children whose key ranges can hold keys starting
+    /// with `prefix`, which is every child unless the comparator groups
+    /// prefixes.
+    pub fn prefix_children(&self, cmp: &Comparator, prefix: &[u8]) -> Range<usize> {
+        if !cmp.groups_prefixes() {
+            return 0..self.children.len();
+        }
+        // pivots that do not start with the prefix sort on the same side
+        // of every key that does as of the prefix itself
+        let outside = |key: &Buf, side: Ordering| {
+            !key.bytes().starts_with(prefix) && cmp.compare(key.bytes(), prefix) == side
+        };
+        let lo = self.keys.partition_point(|key| outside(key, Ordering::Less));
+        let hi = self.keys.partition_point(|key| !outside(key, Ordering::Greater));
+        lo..hi + 1
+    }
+
+    /// False if the filter of `child` rules out keys starting with `prefix`.
+    pub fn may_contain_prefix(&self, child: u64, prefix: &[u8]) -> bool {
+        self.filters.get(&child).is_none_or(|filter| filter.may_contain_prefix(prefix))
+    }
+
+    /// Summarizes the buffered messages for each child.
+    pub fn pending(&self) -> Vec<Pending> {
+        self.buffer.pending()
+    }
+
     pub fn parent_to_child(
         &mut self,
         tree: &mut Tree,
-        store: &mut Store,
+        store: &mut Store<'a>,
         txn: &mut Transaction,
     ) -> io::Result<Option<NewSibling<'a>>> {
-        self.buffer.sort_by(|a, b| a.key.bytes().cmp(b.key.bytes()));
-        let mut indices = Vec::with_capacity(self.buffer.len());
-        for msg in &self.buffer {
-            let pos = self.keys.binary_search_by(
-                |probe| msg.key.bytes().cmp(probe.bytes()),
-            );
-            let pos = match pos {
-                Ok(val) | Err(val) => val,
-            };
-            indices.push(pos);
+        let pending = self.pending();
+        let mut selected = tree.flush.select(&pending);
+        selected.sort();
+        selected.dedup();
+        tree.stats.flushes += 1;
+        if tree.flush_workers > 1 && selected.len() > 1 && store.concurrent().is_some() {
+            let mut jobs = vec![];
+            for child_idx in selected {
+                let msgs = self.take_msgs(tree, child_idx);
+                if !msgs.is_empty() {
+                    jobs.push((child_idx, self.children[child_idx], msgs));
+                }
+            }
+            self.flush_parallel(tree, store, txn, jobs, None)?;
+        } else {
+            // flushing from the right keeps the indices of the
+            // remaining children stable as pivots are added or removed
+            for child_idx in selected.into_iter().rev() {
+                self.flush_child(tree, store, txn, child_idx)?;
+            }
         }
-        let (buff_idx, len, child_idx) = Internal::max_run(&indices);
-        let mut msgs = self.buffer.split_off(buff_idx);
-        let mut tail = msgs.split_off(len);
-        self.buffer.append(&mut tail);
-        let mut owned_msgs = Vec::with_capacity(len);
-        for msg in msgs {
-            owned_msgs.push(msg.into_message());
+        self.prune_filters();
+
+        if self.pivots_full(tree) {
+            Ok(Some(self.split()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Moves the buffered messages for one child into that child,
+    /// preserving the order in which they arrived.
+    fn flush_child(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        child_idx: usize,
+    ) -> io::Result<()> {
+        let msgs = self.take_msgs(tree, child_idx);
+        if msgs.is_empty() {
+            return Ok(());
         }
+
         let child_id = self.children[child_idx];
-        let mut child = store.read(child_id).unwrap();
-        let newchild = child.upsert_msgs(tree, store, txn, owned_msgs)?;
-        if child.header.epoch != tree.epoch {
-            txn.delete.push(child.header.id);
-            child.header.id = tree.next_id();
-            child.header.epoch = tree.epoch;
+        let mut child = store.read(child_id)?;
+        let newchild = child.upsert_msgs(tree, store, txn, msgs)?;
+        self.replace_child(tree, store, txn, child_idx, child, newchild)
+    }
+
+    /// Writes a child that was changed in place, or merges it with a
+    /// sibling if it was left underfull, and adds the sibling it split
+    /// off, if any.
+    fn replace_child(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        child_idx: usize,
+        mut child: Node<'a>,
+        newchild: Option<NewChild>,
+    ) -> io::Result<()> {
+        if newchild.is_none() && child.body.underflow(tree) && self.children.len() > 1 {
+            self.rebalance(tree, store, txn, child_idx, child)?;
+        } else {
+            child.copy_on_write(tree, txn);
+            store.write(&child)?;
+            tree.stats.record_write(child.size());
+            self.children[child_idx] = child.id();
+            self.filter_child(tree, &child);
         }
-        let child_id = child.header.id;
-        store.write(&child)?;
-        self.children[child_idx] = child_id;
 
         if let Some(newchild) = newchild {
             self.keys.insert(child_idx, Buf::Owned(newchild.key));
             self.children.insert(child_idx + 1, newchild.id);
+            self.buffer.insert_child(child_idx + 1);
+            self.add_filter(newchild.id, newchild.filter);
         }
+        Ok(())
+    }
 
-        if self.keys.len() < tree.max_pivots {
-            Ok(None)
+    /// Removes the buffered messages for one child and counts them as flushed.
+    fn take_msgs(&mut self, tree: &mut Tree, child_idx: usize) -> Vec<Message> {
+        let mut msgs = vec![];
+        for msg in self.buffer.take(child_idx) {
+            tree.stats.message_bytes += (msg.key.len() + msg.data.len()) as u64;
+            msgs.push(msg.into_message());
+        }
+        if !msgs.is_empty() {
+            tree.stats.children += 1;
+            tree.stats.messages += msgs.len() as u64;
+        }
+        msgs
+    }
+
+    /// Flushes the messages of each job into its child on
+    /// `tree.flush_workers` threads, compacting the children too when a
+    /// range is given. Each worker writes into its own overlay of the
+    /// store, and the nodes, splits and underflows it reports are
+    /// applied here once all workers are done.
+    fn flush_parallel(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        jobs: Vec<Job>,
+        range: Option<&KeyRange>,
+    ) -> io::Result<()> {
+        let workers = tree.flush_workers.min(jobs.len()).max(1);
+        let mut groups: Vec<Vec<Job>> = (0..workers).map(|_| vec![]).collect();
+        for (count, job) in jobs.into_iter().enumerate() {
+            groups[count % workers].push(job);
+        }
+        let ids = tree.id_counter();
+        let results = match store.concurrent() {
+            Some(base) => thread::scope(|scope| {
+                let handles = groups
+                    .into_iter()
+                    .map(|group| {
+                        let mut worker = tree.fork(ids.clone());
+                        scope.spawn(move || {
+                            let flushed = flush_group(&mut worker, base, group, range);
+                            (worker.stats, flushed)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("flush worker panicked"))
+                    .collect::<Vec<_>>()
+            }),
+            None => return Err(io::Error::other("store cannot be read concurrently")),
+        };
+
+        let mut children = vec![];
+        for (stats, flushed) in results {
+            tree.stats.merge(&stats);
+            let flushed = flushed?;
+            for node in flushed.nodes.values() {
+                store.write(node)?;
+            }
+            for id in flushed.deletes {
+                store.schedule_delete(id)?;
+            }
+            txn.delete.extend(flushed.replaced);
+            children.extend(flushed.children);
+        }
+        for child in &mut children {
+            self.children[child.idx] = child.id;
+            if let Some(filter) = child.filter.take() {
+                self.filters.insert(child.id, filter);
+            }
+        }
+        // splits are added from the right so that the
+        // indices of the children to their left stay valid
+        children.sort_by_key(|child| child.idx);
+        let mut underflow = vec![];
+        for child in children.into_iter().rev() {
+            if let Some(newchild) = child.newchild {
+                self.keys.insert(child.idx, Buf::Owned(newchild.key));
+                self.children.insert(child.idx + 1, newchild.id);
+                self.buffer.insert_child(child.idx + 1);
+                self.add_filter(newchild.id, newchild.filter);
+            } else if child.underflow {
+                underflow.push(child.id);
+            }
+        }
+        for id in underflow {
+            if self.children.len() < 2 {
+                break;
+            }
+            // an earlier rebalance may have merged the child away
+            let child_idx = match self.children.iter().position(|&child| child == id) {
+                Some(child_idx) => child_idx,
+                None => continue,
+            };
+            let child = store.read(id)?;
+            if child.body.underflow(tree) {
+                self.rebalance(tree, store, txn, child_idx, child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges an underfull child with an adjacent sibling, or moves
+    /// entries between them when the merged node would be too large.
+    fn rebalance(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        child_idx: usize,
+        child: Node<'a>,
+    ) -> io::Result<()> {
+        let (left_idx, mut left, right) = if child_idx + 1 < self.children.len() {
+            let right = store.read(self.children[child_idx + 1])?;
+            (child_idx, child, right)
         } else {
-            Ok(Some(self.split()))
+            let left = store.read(self.children[child_idx - 1])?;
+            (child_idx - 1, left, child)
+        };
+        let Node { header, body } = right;
+        let separator = self.keys[left_idx].to_vec();
+        let sibling = left.body.merge(tree, separator, body);
+        left.copy_on_write(tree, txn);
+        store.write(&left)?;
+        tree.stats.record_write(left.size());
+        self.children[left_idx] = left.id();
+        self.filter_child(tree, &left);
+        match sibling {
+            Some(sibling) => {
+                let mut right = Node {
+                    header: header,
+                    body: sibling.body,
+                };
+                right.copy_on_write(tree, txn);
+                store.write(&right)?;
+                tree.stats.record_write(right.size());
+                self.children[left_idx + 1] = right.id();
+                self.filter_child(tree, &right);
+                self.buffer.repartition(&*tree.comparator, left_idx, &sibling.key);
+                self.keys[left_idx] = Buf::Owned(sibling.key);
+            }
+            None => {
+                txn.delete.push(header.id);
+                self.keys.remove(left_idx);
+                self.children.remove(left_idx + 1);
+                self.buffer.merge_children(left_idx);
+            }
         }
+        Ok(())
     }
 
     pub fn upsert_msg(
         &mut self,
         tree: &mut Tree,
-        store: &mut Store,
+        store: &mut Store<'a>,
         txn: &mut Transaction,
         msg: Message,
     ) -> io::Result<Option<NewSibling<'a>>> {
-        self.upsert(msg);
-        if self.children.len() < tree.max_buffer {
+        for blob in self.upsert(&*tree.comparator, msg) {
+            tree.retire(blob);
+        }
+        if tree.defer_flush || !self.buffer_full(tree) {
             return Ok(None);
         }
         self.parent_to_child(tree, store, txn)
@@ -385,14 +984,16 @@ impl<'a> Internal<'a> {
     pub fn upsert_msgs(
         &mut self,
         tree: &mut Tree,
-        store: &mut Store,
+        store: &mut Store<'a>,
         txn: &mut Transaction,
         msgs: Vec<Message>,
     ) -> io::Result<Option<NewSibling<'a>>> {
         for msg in msgs {
-            self.upsert(msg);
+            for blob in self.upsert(&*tree.comparator, msg) {
+                tree.retire(blob);
+            }
         }
-        if self.children.len() < tree.max_buffer {
+        if tree.defer_flush || !self.buffer_full(tree) {
             return Ok(None);
         }
         self.parent_to_child(tree, store, txn)
@@ -403,16 +1004,64 @@ impl<'a> Internal<'a> {
 mod tests {
     use super::*;
 
+    use index::comparator::Bytewise;
+    use index::comparator::CaseInsensitive;
+    use index::comparator::Reverse;
+    use index::compression::LevelCompression;
+    use index::filter::FilterPolicy;
+    use index::flush::FlushAll;
+    use index::flush::FlushPolicy;
+    use index::flush::OldestFirst;
+    use index::leaf::Leaf;
+    use index::mode::Mode;
+    use index::node::Header;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    fn leaf_node<'a>(id: u64, keys: Vec<String>) -> Node<'a> {
+        let vals = keys.iter().map(|_| Buf::Owned(b"x".to_vec())).collect();
+        let keys = keys.into_iter().map(|key| Buf::Owned(key.into_bytes())).collect();
+        Node {
+            header: Header { id: id, epoch: 0 },
+            body: Body::Leaf(Leaf::new(keys, vals)),
+        }
+    }
+
+    fn delete<'b>(key: &[u8]) -> BufMessage<'b> {
+        BufMessage::new(Operation::Delete, Buf::Owned(key.to_vec()), Buf::Owned(vec![]))
+    }
+
+    fn rebalance_leaves(right: usize) -> (Internal<'static>, NodeStore<MemStore>, Transaction) {
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        tree.id = 100;
+        let mut txn = tree.begin_txn().ok().unwrap();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let left = (0..6).map(|i| format!("a{:02}", i)).collect();
+        let right = (0..right).map(|i| format!("b{:02}", i)).collect();
+        store.write(&leaf_node(1, left)).unwrap();
+        store.write(&leaf_node(2, right)).unwrap();
+        let mut input = Internal::new(
+            &Bytewise,
+            1,
+            vec![Buf::Owned(b"b".to_vec())],
+            vec![delete(b"a00"), delete(b"a01")],
+            vec![1, 2],
+        );
+        let result = input.parent_to_child(&mut tree, &mut store, &mut txn);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+        (input, store, txn)
+    }
+
     #[test]
     fn roundtrip_empty_internal() {
-        let input = Internal {
-            level: 1,
-            data: vec![],
-            keys: vec![],
-            buffer: vec![],
-            children: vec![],
-            serde: false,
-        };
+        let input = Internal::new(
+            &Bytewise,
+            1,
+            vec![],
+            vec![],
+            vec![],
+        );
         let mut wtr = vec![];
         let result = input.serialize(&mut wtr);
         assert!(result.is_ok());
@@ -426,27 +1075,23 @@ mod tests {
 
     #[test]
     fn roundtrip_nonempty_internal() {
-        let input = Internal {
-            level: 1,
-            data: vec![],
-            keys: vec![Buf::Owned(b"hello".to_vec())],
-            buffer: vec![
-                BufMessage {
-                    op: Operation::Assign,
-                    key: Buf::Owned(b"foo".to_vec()),
-                    data: Buf::Owned(b"bar".to_vec()),
-                },
+        let input = Internal::new(
+            &Bytewise,
+            1,
+            vec![Buf::Owned(b"hello".to_vec())],
+            vec![
+                BufMessage::new(Operation::Assign, Buf::Owned(b"foo".to_vec()), Buf::Owned(b"bar".to_vec())),
             ],
-            children: vec![0, 1],
-            serde: false,
-        };
+            vec![0, 1],
+        );
         let mut wtr = vec![];
         let result = input.serialize(&mut wtr);
         assert!(result.is_ok());
         assert_eq!(
-            8 * size_of::<u64>() + 2 * size_of::<u32>() + "hello".len() + "foo".len() + "bar".len(),
+            13 * size_of::<u64>() + 2 * size_of::<u32>() + "hello".len() + "foo".len() + "bar".len(),
             wtr.len()
         );
+        assert_eq!(input.size(), wtr.len());
         let output = Internal::deserialize(wtr);
         assert!(output.is_ok());
         let output = output.unwrap();
@@ -458,70 +1103,146 @@ mod tests {
     }
 
     #[test]
-    fn max_run() {
-        let input = vec![1, 2, 3, 4];
-        let (idx, len, val) = Internal::max_run(&input);
-        assert_eq!(3, idx);
-        assert_eq!(1, len);
-        assert_eq!(4, val);
-        let input = vec![1, 1, 3, 4];
-        let (idx, len, val) = Internal::max_run(&input);
-        assert_eq!(0, idx);
-        assert_eq!(2, len);
-        assert_eq!(1, val);
-        let input = vec![1, 2, 2, 4];
-        let (idx, len, val) = Internal::max_run(&input);
-        assert_eq!(1, idx);
-        assert_eq!(2, len);
-        assert_eq!(2, val);
-        let input = vec![1, 2, 3, 3];
-        let (idx, len, val) = Internal::max_run(&input);
-        assert_eq!(2, idx);
-        assert_eq!(2, len);
-        assert_eq!(3, val);
-        let input = vec![1, 1, 1, 1];
-        let (idx, len, val) = Internal::max_run(&input);
-        assert_eq!(0, idx);
-        assert_eq!(4, len);
-        assert_eq!(1, val);
+    fn roundtrip_oldest_first() {
+        let mut input = Internal::new(
+            &Bytewise,
+            1,
+            vec![Buf::Owned(b"m".to_vec())],
+            vec![],
+            vec![1, 2],
+        );
+        for key in &["x", "a", "b"] {
+            input.upsert(&Bytewise, Message::new(Operation::Assign, key.as_bytes().to_vec(), b"v".to_vec()));
+        }
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        assert_eq!(input.size(), wtr.len());
+        let mut output = Internal::deserialize(wtr).unwrap();
+        assert_eq!(vec![1], OldestFirst.select(&output.buffer.pending()));
+        // newer messages stay newer than those read back
+        output.buffer.take(1);
+        output.upsert(&Bytewise, Message::new(Operation::Assign, b"y".to_vec(), b"v".to_vec()));
+        assert_eq!(vec![0], OldestFirst.select(&output.buffer.pending()));
+        output.buffer.merge_children(0);
+        assert_eq!(2, output.buffer.parts[0].oldest);
+    }
+
+    #[test]
+    fn roundtrip_filters() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.filters = Some(FilterPolicy {
+            bits_per_key: 8,
+            prefix_len: 0,
+        });
+        let leaf = Node {
+            header: Header { id: 2, epoch: 0 },
+            body: Body::Leaf(Leaf::new(vec![Buf::Owned(b"a".to_vec())], vec![Buf::Owned(b"x".to_vec())])),
+        };
+        let mut input = Internal::new(&Bytewise, 1, vec![Buf::Owned(b"b".to_vec())], vec![], vec![2, 3]);
+        input.filter_child(&tree, &leaf);
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        assert_eq!(input.size(), wtr.len());
+        let output = Internal::deserialize(wtr).unwrap();
+        assert_eq!(input.filters, output.filters);
+        assert!(output.may_contain(2, b"a"));
+        assert!(!output.may_contain(2, b"c"));
+        assert!(output.may_contain(3, b"c"));
+    }
+
+    #[test]
+    fn roundtrip_tagged_messages() {
+        let msg = |key: &[u8], epoch: u64| BufMessage {
+            op: Operation::Merge,
+            key: Buf::Owned(key.to_vec()),
+            data: Buf::Owned(b"x".to_vec()),
+            epoch: epoch,
+        };
+        let input = Internal::new(&Bytewise, 1, vec![Buf::Owned(b"b".to_vec())], vec![msg(b"a", 7), msg(b"c", 0)], vec![0, 1]);
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        assert_eq!(input.size(), wtr.len());
+        let output = Internal::deserialize(wtr).unwrap();
+        assert_eq!(7, output.buffer[0].epoch);
+        assert_eq!(0, output.buffer[1].epoch);
+        assert_eq!(b"x", output.buffer[0].data.bytes());
+        assert_eq!(b"c", output.buffer[1].key.bytes());
+        assert_eq!(Operation::Merge, output.buffer[0].op);
+    }
+
+    #[test]
+    fn truncated_expiry() {
+        let input = Internal::new(
+            &Bytewise,
+            1,
+            vec![],
+            vec![BufMessage::new(Operation::AssignExpiring, Buf::Owned(b"foo".to_vec()), Buf::Owned(vec![1, 2, 3]))],
+            vec![0],
+        );
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        match Internal::deserialize(wtr) {
+            Err(err) => assert_eq!(io::ErrorKind::InvalidData, err.kind()),
+            Ok(_) => panic!("truncated expiry was read"),
+        }
+    }
+
+    #[test]
+    fn route_to_pivots() {
+        // a key equal to a pivot belongs to the child on its right
+        let mut input = Internal::new(
+            &Bytewise,
+            1,
+            vec![Buf::Owned(b"b".to_vec()), Buf::Owned(b"d".to_vec())],
+            vec![],
+            vec![1, 2, 3],
+        );
+        let routes: Vec<usize> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|key| input.route(&Bytewise, key.as_bytes()))
+            .collect();
+        assert_eq!(vec![0, 1, 1, 2, 2], routes);
+        for key in &["a", "b", "d"] {
+            input.upsert(&Bytewise, Message::new(Operation::Assign, key.as_bytes().to_vec(), b"x".to_vec()));
+        }
+        let counts: Vec<usize> = input.buffer.parts.iter().map(|part| part.msgs.len()).collect();
+        assert_eq!(vec![1, 1, 1], counts);
+    }
+
+    #[test]
+    fn prefix_children() {
+        let pivots = |keys: &[&str]| keys.iter().map(|key| Buf::Owned(key.as_bytes().to_vec())).collect();
+        let input = Internal::new(&Bytewise, 1, pivots(&["a", "ab", "b", "c"]), vec![], vec![1, 2, 3, 4, 5]);
+        assert_eq!(0..3, input.prefix_children(&Bytewise, b"a"));
+        assert_eq!(1..3, input.prefix_children(&Bytewise, b"ab"));
+        assert_eq!(2..4, input.prefix_children(&Bytewise, b"b"));
+        assert_eq!(4..5, input.prefix_children(&Bytewise, b"d"));
+        assert_eq!(0..5, input.prefix_children(&Bytewise, b""));
+        assert_eq!(0..5, input.prefix_children(&CaseInsensitive, b"b"));
+        let input = Internal::new(&Reverse, 1, pivots(&["c", "b", "ab", "a"]), vec![], vec![1, 2, 3, 4, 5]);
+        assert_eq!(2..5, input.prefix_children(&Reverse, b"a"));
+        assert_eq!(1..3, input.prefix_children(&Reverse, b"b"));
     }
 
     #[test]
     fn split_internal() {
-        let mut input = Internal {
-            level: 1,
-            data: vec![],
-            keys: vec![
+        let mut input = Internal::new(
+            &Bytewise,
+            1,
+            vec![
                 Buf::Owned(b"a".to_vec()),
                 Buf::Owned(b"b".to_vec()),
                 Buf::Owned(b"c".to_vec()),
                 Buf::Owned(b"d".to_vec()),
             ],
-            buffer: vec![
-                BufMessage {
-                    op: Operation::Assign,
-                    key: Buf::Owned(b"a".to_vec()),
-                    data: Buf::Owned(b"w".to_vec()),
-                },
-                BufMessage {
-                    op: Operation::Assign,
-                    key: Buf::Owned(b"b".to_vec()),
-                    data: Buf::Owned(b"x".to_vec()),
-                },
-                BufMessage {
-                    op: Operation::Assign,
-                    key: Buf::Owned(b"c".to_vec()),
-                    data: Buf::Owned(b"y".to_vec()),
-                },
-                BufMessage {
-                    op: Operation::Assign,
-                    key: Buf::Owned(b"d".to_vec()),
-                    data: Buf::Owned(b"z".to_vec()),
-                },
+            vec![
+                BufMessage::new(Operation::Assign, Buf::Owned(b"a".to_vec()), Buf::Owned(b"w".to_vec())),
+                BufMessage::new(Operation::Assign, Buf::Owned(b"b".to_vec()), Buf::Owned(b"x".to_vec())),
+                BufMessage::new(Operation::Assign, Buf::Owned(b"c".to_vec()), Buf::Owned(b"y".to_vec())),
+                BufMessage::new(Operation::Assign, Buf::Owned(b"d".to_vec()), Buf::Owned(b"z".to_vec())),
             ],
-            children: vec![0, 1, 2, 3, 4],
-            serde: false,
-        };
+            vec![0, 1, 2, 3, 4],
+        );
         let sibling = input.split();
 
         assert_eq!(b"c".to_vec(), sibling.key);
@@ -567,4 +1288,175 @@ mod tests {
         assert_eq!(b"z", output.buffer[1].data.bytes());
         assert_eq!(vec![3, 4], output.children);
     }
+
+    #[test]
+    fn merge_underflow() {
+        let (input, store, txn) = rebalance_leaves(6);
+        assert_eq!(0, input.keys.len());
+        assert_eq!(0, input.buffer.len());
+        assert_eq!(1, input.children.len());
+        assert_eq!(vec![1, 2], txn.delete);
+        let child = store.read(input.children[0]).unwrap();
+        let leaf = child.body.leaf();
+        assert_eq!(10, leaf.keys.len());
+        assert_eq!(b"a02", leaf.keys[0].bytes());
+        assert_eq!(b"b05", leaf.keys[9].bytes());
+    }
+
+    #[test]
+    fn borrow_underflow() {
+        let (input, store, txn) = rebalance_leaves(18);
+        assert_eq!(1, input.keys.len());
+        assert_eq!(b"b07", input.keys[0].bytes());
+        assert_eq!(2, input.children.len());
+        assert_eq!(vec![1, 2], txn.delete);
+        let left = store.read(input.children[0]).unwrap();
+        let right = store.read(input.children[1]).unwrap();
+        assert_eq!(11, left.body.leaf().keys.len());
+        assert_eq!(11, right.body.leaf().keys.len());
+        assert_eq!(b"b07", right.body.leaf().keys[0].bytes());
+    }
+
+    #[test]
+    fn collapse_root() {
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        tree.id = 100;
+        let mut txn = tree.begin_txn().ok().unwrap();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        store.write(&leaf_node(1, vec!["a".to_string()])).unwrap();
+        let root = Node {
+            header: Header { id: 2, epoch: 1 },
+            body: Body::Internal(Internal::new(
+                &Bytewise,
+                1,
+                vec![],
+                vec![delete(b"a")],
+                vec![1],
+            )),
+        };
+        store.write(&root).unwrap();
+        let root = root.collapse(&mut tree, &mut store, &mut txn);
+        assert!(root.is_ok());
+        let root = root.unwrap();
+        assert_eq!(0, root.body.level());
+        assert_eq!(0, root.body.leaf().keys.len());
+        assert_eq!(vec![1, 2], txn.delete);
+        assert!(store.read(root.id()).is_ok());
+    }
+
+    #[test]
+    fn flush_all_children() {
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        tree.id = 100;
+        tree.flush = Box::new(FlushAll);
+        let mut txn = tree.begin_txn().ok().unwrap();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let left = (0..6).map(|i| format!("a{:02}", i)).collect();
+        let right = (0..6).map(|i| format!("b{:02}", i)).collect();
+        store.write(&leaf_node(1, left)).unwrap();
+        store.write(&leaf_node(2, right)).unwrap();
+        let assign = |key: &[u8]| BufMessage::new(Operation::Assign, Buf::Owned(key.to_vec()), Buf::Owned(b"y".to_vec()));
+        let mut input = Internal::new(
+            &Bytewise,
+            1,
+            vec![Buf::Owned(b"b".to_vec())],
+            vec![assign(b"b10"), assign(b"a10"), assign(b"b")],
+            vec![1, 2],
+        );
+        let pending = input.pending();
+        assert_eq!(2, pending.len());
+        assert_eq!((0, 1, 4), (pending[0].child, pending[0].count, pending[0].bytes));
+        assert_eq!((1, 2, 6), (pending[1].child, pending[1].count, pending[1].bytes));
+        let result = input.parent_to_child(&mut tree, &mut store, &mut txn);
+        assert!(result.is_ok());
+        assert_eq!(0, input.buffer.len());
+        assert_eq!(1, tree.stats.flushes);
+        assert_eq!(2, tree.stats.children);
+        assert_eq!(3, tree.stats.messages);
+        assert_eq!(2, tree.stats.nodes_written);
+        let left = store.read(input.children[0]).unwrap();
+        let right = store.read(input.children[1]).unwrap();
+        assert_eq!(Some(&b"y"[..]), left.body.leaf().get(&Bytewise, b"a10"));
+        assert_eq!(Some(&b"y"[..]), right.body.leaf().get(&Bytewise, b"b"));
+        assert_eq!(Some(&b"y"[..]), right.body.leaf().get(&Bytewise, b"b10"));
+    }
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    fn flush_four_children(workers: usize) -> (Tree, Vec<Vec<u8>>, Pairs) {
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        tree.id = 100;
+        tree.flush = Box::new(FlushAll);
+        tree.flush_workers = workers;
+        let mut txn = tree.begin_txn().ok().unwrap();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for &(id, prefix) in &[(1, "a"), (2, "b"), (3, "c"), (4, "d")] {
+            let keys = (0..6).map(|i| format!("{}{:02}", prefix, i)).collect();
+            store.write(&leaf_node(id, keys)).unwrap();
+        }
+        let msg = |op: Operation, key: String, data: &[u8]| BufMessage::new(op, Buf::Owned(key.into_bytes()), Buf::Owned(data.to_vec()));
+        let mut msgs = vec![];
+        for i in 6..12 {
+            msgs.push(msg(Operation::Assign, format!("a{:02}", i), b"y"));
+        }
+        for i in 1..6 {
+            msgs.push(msg(Operation::Delete, format!("c{:02}", i), b""));
+        }
+        msgs.push(msg(Operation::Merge, "b00".to_string(), b"+"));
+        msgs.push(msg(Operation::Merge, "d05".to_string(), b"+"));
+        let keys = vec!["b", "c", "d"].into_iter().map(|key| Buf::Owned(key.as_bytes().to_vec())).collect();
+        let mut input = Internal::new(&Bytewise, 1, keys, msgs, vec![1, 2, 3, 4]);
+        assert!(input.parent_to_child(&mut tree, &mut store, &mut txn).is_ok());
+        assert_eq!(0, input.buffer.len());
+        let pivots = input.keys.iter().map(|key| key.to_vec()).collect();
+        let mut pairs = vec![];
+        for id in &input.children {
+            assert!(*id <= tree.last_id());
+            let node = store.read(*id).unwrap();
+            let leaf = node.body.leaf();
+            for i in 0..leaf.keys.len() {
+                pairs.push((leaf.keys[i].to_vec(), leaf.vals[i].to_vec()));
+            }
+        }
+        let mut ids = input.children.clone();
+        ids.dedup();
+        assert_eq!(input.children.len(), ids.len());
+        (tree, pivots, pairs)
+    }
+
+    #[test]
+    fn parallel_flush() {
+        let (serial, serial_pivots, serial_pairs) = flush_four_children(1);
+        let (parallel, parallel_pivots, parallel_pairs) = flush_four_children(3);
+        assert_eq!(serial_pairs, parallel_pairs);
+        assert_eq!(serial_pivots, parallel_pivots);
+        assert_eq!(serial.stats.children, parallel.stats.children);
+        assert_eq!(serial.stats.messages, parallel.stats.messages);
+        assert_eq!(4, parallel.stats.children);
+        assert_eq!(25, parallel_pairs.len());
+        assert!(parallel_pairs.contains(&(b"b00".to_vec(), b"x+".to_vec())));
+    }
+
+    #[test]
+    fn coalesce_buffer() {
+        let mut input = Internal::new(
+            &Bytewise,
+            1,
+            vec![],
+            vec![],
+            vec![1],
+        );
+        for i in 0..10 {
+            input.upsert(&Bytewise, Message::new(Operation::Assign, b"hot".to_vec(), vec![i]));
+        }
+        input.upsert(&Bytewise, Message::new(Operation::Assign, b"cold".to_vec(), b"x".to_vec()));
+        assert_eq!(2, input.buffer.len());
+        assert_eq!(b"cold", input.buffer[0].key.bytes());
+        assert_eq!(b"hot", input.buffer[1].key.bytes());
+        assert_eq!(&[9], input.buffer[1].data.bytes());
+        input.upsert(&Bytewise, Message::new(Operation::Delete, b"hot".to_vec(), vec![]));
+        assert_eq!(2, input.buffer.len());
+        assert_eq!(b"cold", input.buffer[0].key.bytes());
+        assert_eq!(Operation::Delete, input.buffer[1].op);
+    }
 }
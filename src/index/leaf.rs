@@ -1,11 +1,21 @@
+use super::blob::BlobRef;
 use super::buf::Buf;
+use super::comparator::separator;
+use super::comparator::Comparator;
 use super::message::Message;
+use super::message::Stored;
 use super::node::NewSibling;
 use super::node::Body;
+use super::operation::Operation;
+use super::tree::Capacity;
 use super::tree::Tree;
+use super::version::History;
+use super::version::Retention;
+use super::version::Version;
 
 use std::io;
 use std::io::Cursor;
+use std::io::Read;
 use std::io::Write;
 use std::mem::size_of;
 use std::slice::from_raw_parts_mut;
@@ -14,24 +24,78 @@ use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 
+// set in the serialized length of a value that starts with a blob reference
+const BLOB_LEN: u64 = 1 << 63;
+
 pub struct Leaf<'a> {
     #[allow(dead_code)]
     pub data: Vec<u8>,
     pub keys: Vec<Buf<'a>>,
     pub vals: Vec<Buf<'a>>,
+    /// When each value expires, or 0 if it never does.
+    pub expires: Vec<u64>,
+    /// Whether each value starts with a blob reference, as described
+    /// for `Stored::blob`.
+    pub blobs: Vec<bool>,
+    /// The versions of each entry when the tree keeps them, else empty.
+    pub history: Vec<History>,
+}
+
+impl<'a> Clone for Leaf<'a> {
+    fn clone(&self) -> Leaf<'a> {
+        Leaf {
+            data: vec![],
+            keys: self.keys.clone(),
+            vals: self.vals.clone(),
+            expires: self.expires.clone(),
+            blobs: self.blobs.clone(),
+            history: self.history.clone(),
+        }
+    }
 }
 
 impl<'a> Leaf<'a> {
+    /// A leaf whose values never expire and are kept inline.
+    pub fn new(keys: Vec<Buf<'a>>, vals: Vec<Buf<'a>>) -> Leaf<'a> {
+        Leaf {
+            data: vec![],
+            expires: vec![0; keys.len()],
+            blobs: vec![false; keys.len()],
+            keys: keys,
+            vals: vals,
+            history: vec![],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        let mut total = 2 * size_of::<u64>() + 3 * self.keys.len() * size_of::<u64>();
+        for i in 0..self.keys.len() {
+            total += self.keys[i].len() + self.vals[i].len();
+        }
+        for history in &self.history {
+            total += 2 * size_of::<u64>() + 1;
+            for version in &history.older {
+                total += 2 * size_of::<u64>() + 1;
+                total += version.val.as_ref().map_or(0, |val| val.len());
+            }
+        }
+        total
+    }
+
     pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
         let size = self.keys.len();
         wtr.write_u64::<LittleEndian>(size as u64)?;
+        wtr.write_u64::<LittleEndian>(self.history.len() as u64)?;
         for key in &self.keys {
             let len = key.bytes().len();
             wtr.write_u64::<LittleEndian>(len as u64)?;
         }
-        for val in &self.vals {
-            let len = val.bytes().len();
-            wtr.write_u64::<LittleEndian>(len as u64)?;
+        for (val, blob) in self.vals.iter().zip(&self.blobs) {
+            let len = val.bytes().len() as u64;
+            wtr.write_u64::<LittleEndian>(if *blob { len | BLOB_LEN } else { len })?;
+        }
+        for expires in &self.expires {
+            wtr.write_u64::<LittleEndian>(*expires)?;
         }
         for key in &self.keys {
             wtr.write_all(key.bytes())?;
@@ -39,6 +103,26 @@ impl<'a> Leaf<'a> {
         for val in &self.vals {
             wtr.write_all(val.bytes())?;
         }
+        // versions follow the values, counted in the header
+        for history in &self.history {
+            wtr.write_u64::<LittleEndian>(history.epoch)?;
+            wtr.write_u8(history.deleted as u8)?;
+            wtr.write_u64::<LittleEndian>(history.older.len() as u64)?;
+            for version in &history.older {
+                wtr.write_u64::<LittleEndian>(version.epoch)?;
+                match version.val {
+                    Some(ref val) => {
+                        wtr.write_u8(1)?;
+                        wtr.write_u64::<LittleEndian>(val.len() as u64)?;
+                        wtr.write_all(val)?;
+                    }
+                    None => {
+                        wtr.write_u8(0)?;
+                        wtr.write_u64::<LittleEndian>(0)?;
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
@@ -46,11 +130,16 @@ impl<'a> Leaf<'a> {
         let input_ptr = input.as_mut_ptr();
         let mut rdr = Cursor::new(input);
         let size = rdr.read_u64::<LittleEndian>()? as usize;
+        // leaves that keep versions have one history per entry
+        let versioned = rdr.read_u64::<LittleEndian>()? as usize;
+        if versioned != 0 && versioned != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "history count does not match entries"));
+        }
 
         let mut keys = Vec::with_capacity(size);
         let mut vals = Vec::with_capacity(size);
 
-        let mut offset = (size_of::<u64>() + 2 * size * size_of::<u64>()) as isize;
+        let mut offset = (2 * size_of::<u64>() + 3 * size * size_of::<u64>()) as isize;
 
         for _ in 0..size {
             let len = rdr.read_u64::<LittleEndian>()? as usize;
@@ -62,8 +151,15 @@ impl<'a> Leaf<'a> {
             offset += len as isize;
         }
 
+        let mut blobs = Vec::with_capacity(size);
         for _ in 0..size {
-            let len = rdr.read_u64::<LittleEndian>()? as usize;
+            let len = rdr.read_u64::<LittleEndian>()?;
+            let blob = len & BLOB_LEN != 0;
+            let len = (len & !BLOB_LEN) as usize;
+            if blob && len < BlobRef::SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated blob reference"));
+            }
+            blobs.push(blob);
             unsafe {
                 vals.push(Buf::Shared(
                     from_raw_parts_mut(input_ptr.offset(offset), len),
@@ -72,27 +168,244 @@ impl<'a> Leaf<'a> {
             offset += len as isize;
         }
 
+        let mut expires = Vec::with_capacity(size);
+        for _ in 0..size {
+            expires.push(rdr.read_u64::<LittleEndian>()?);
+        }
+
+        let mut history = Vec::with_capacity(versioned);
+        rdr.set_position(offset as u64);
+        for _ in 0..versioned {
+            history.push(Leaf::read_history(&mut rdr)?);
+        }
+
         Ok(Leaf {
             data: rdr.into_inner(),
             keys: keys,
             vals: vals,
+            expires: expires,
+            blobs: blobs,
+            history: history,
+        })
+    }
+
+    fn read_history(rdr: &mut Cursor<Vec<u8>>) -> io::Result<History> {
+        let epoch = rdr.read_u64::<LittleEndian>()?;
+        let deleted = rdr.read_u8()? != 0;
+        let count = rdr.read_u64::<LittleEndian>()? as usize;
+        let mut older = vec![];
+        for _ in 0..count {
+            let epoch = rdr.read_u64::<LittleEndian>()?;
+            let present = rdr.read_u8()? != 0;
+            let mut val = vec![0; rdr.read_u64::<LittleEndian>()? as usize];
+            rdr.read_exact(&mut val)?;
+            older.push(Version {
+                epoch: epoch,
+                val: if present { Some(val) } else { None },
+            });
+        }
+        Ok(History {
+            epoch: epoch,
+            deleted: deleted,
+            older: older,
         })
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
-        let loc = self.keys.binary_search_by_key(&key, |buf| buf.bytes());
+    pub fn get(&self, cmp: &Comparator, key: &[u8]) -> Option<&[u8]> {
+        let loc = self.keys.binary_search_by(|buf| cmp.compare(buf.bytes(), key));
         match loc {
-            Ok(pos) => {
+            Ok(pos) if !self.deleted(pos) => {
                 let buf = &self.vals[pos];
                 Some(buf.bytes())
             }
-            Err(_) => None,
+            _ => None,
+        }
+    }
+
+    /// The value of `key` and when it expires, or 0 if it never does.
+    pub fn get_expiring(&self, cmp: &Comparator, key: &[u8]) -> Option<(&[u8], u64)> {
+        match self.keys.binary_search_by(|buf| cmp.compare(buf.bytes(), key)) {
+            Ok(pos) if !self.deleted(pos) => Some((self.vals[pos].bytes(), self.expires[pos])),
+            _ => None,
+        }
+    }
+
+    /// The stored value of `key`, whose blob, if any, is not read.
+    pub fn get_stored(&self, cmp: &Comparator, key: &[u8]) -> Option<Stored> {
+        match self.keys.binary_search_by(|buf| cmp.compare(buf.bytes(), key)) {
+            Ok(pos) if !self.deleted(pos) => Some(self.stored(pos)),
+            _ => None,
+        }
+    }
+
+    pub fn stored(&self, pos: usize) -> Stored {
+        Stored {
+            val: self.vals[pos].to_vec(),
+            expires: self.expires[pos],
+            blob: self.blobs[pos],
+        }
+    }
+
+    /// Whether the entry at `pos` is only kept for its older versions.
+    pub fn deleted(&self, pos: usize) -> bool {
+        self.history.get(pos).is_some_and(|history| history.deleted)
+    }
+
+    /// The versions of `key`, newest first. Entries of a leaf that does
+    /// not keep versions have a single one from epoch 0.
+    pub fn versions(&self, cmp: &Comparator, key: &[u8]) -> Vec<Version> {
+        let pos = match self.keys.binary_search_by(|buf| cmp.compare(buf.bytes(), key)) {
+            Ok(pos) => pos,
+            Err(_) => return vec![],
+        };
+        let current = if self.deleted(pos) {
+            None
+        } else {
+            Some(self.vals[pos].to_vec())
+        };
+        match self.history.get(pos) {
+            Some(history) => {
+                let mut versions = vec![Version {
+                    epoch: history.epoch,
+                    val: current,
+                }];
+                versions.extend(history.older.iter().cloned());
+                versions
+            }
+            None => vec![Version {
+                epoch: 0,
+                val: current,
+            }],
         }
     }
 
-    fn split(&mut self) -> NewSibling<'a> {
+    fn expired(&self, pos: usize, now: u64) -> bool {
+        self.expires[pos] != 0 && self.expires[pos] <= now
+    }
+
+    /// Whether any value expired at or before `now`.
+    pub fn has_expired(&self, now: u64) -> bool {
+        (0..self.keys.len()).any(|pos| self.expired(pos, now))
+    }
+
+    /// Drops the values that expired by the tree's clock. A leaf that
+    /// keeps versions deletes them in the tree's epoch instead, so that
+    /// their history is kept. The chunks of dropped blobs are retired.
+    pub fn drop_expired(&mut self, tree: &mut Tree) {
+        let now = tree.clock.now();
+        let mut pos = 0;
+        while pos < self.keys.len() {
+            if !self.expired(pos, now) {
+                pos += 1;
+                continue;
+            }
+            match tree.versions {
+                Some(retention) => {
+                    let mut msg = Message::new(Operation::Delete, self.keys[pos].to_vec(), vec![]);
+                    msg.epoch = tree.epoch;
+                    self.upsert_versioned(&*tree.comparator, msg, retention, now);
+                }
+                None => {
+                    if self.blobs[pos] {
+                        if let Ok(blob) = BlobRef::decode(self.vals[pos].bytes()) {
+                            tree.retire(blob);
+                        }
+                    }
+                    self.remove(pos)
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, pos: usize) {
+        self.keys.remove(pos);
+        self.vals.remove(pos);
+        self.expires.remove(pos);
+        self.blobs.remove(pos);
+        if !self.history.is_empty() {
+            self.history.remove(pos);
+        }
+    }
+
+    /// Starts or stops keeping versions.
+    fn track(&mut self, versioned: bool) {
+        if versioned {
+            if self.history.len() != self.keys.len() {
+                self.history = vec![History::default(); self.keys.len()];
+            }
+        } else if !self.history.is_empty() {
+            // deleted entries were only kept for their versions
+            let mut pos = 0;
+            while pos < self.keys.len() {
+                if self.history[pos].deleted {
+                    self.remove(pos);
+                } else {
+                    pos += 1;
+                }
+            }
+            self.history.clear();
+        }
+    }
+
+    pub fn full(&self, tree: &Tree) -> bool {
+        match tree.capacity {
+            Capacity::Count => self.keys.len() >= (tree.max_pivots + tree.max_buffer),
+            Capacity::Bytes { leaf, .. } => self.keys.len() > 1 && self.size() >= leaf,
+        }
+    }
+
+    pub fn underflow(&self, tree: &Tree) -> bool {
+        match tree.capacity {
+            Capacity::Count => {
+                self.keys.len() * 100 < tree.low_water * (tree.max_pivots + tree.max_buffer)
+            }
+            Capacity::Bytes { leaf, .. } => self.size() * 100 < tree.low_water * leaf,
+        }
+    }
+
+    /// Appends the entries of the right sibling.
+    pub fn merge(&mut self, mut right: Leaf<'a>) {
+        if !self.history.is_empty() || !right.history.is_empty() {
+            self.track(true);
+            right.track(true);
+        }
+        for key in &right.keys {
+            self.keys.push(Buf::Owned(key.to_vec()));
+        }
+        for val in &right.vals {
+            self.vals.push(Buf::Owned(val.to_vec()));
+        }
+        self.expires.extend(right.expires);
+        self.blobs.extend(right.blobs);
+        self.history.extend(right.history);
+    }
+
+    pub fn midpoint(&self, tree: &Tree) -> usize {
         let size = self.keys.len();
-        let split = size / 2;
+        if let Capacity::Count = tree.capacity {
+            return size / 2;
+        }
+        let mut total = 0;
+        for i in 0..size {
+            total += self.keys[i].len() + self.vals[i].len();
+        }
+        let mut prefix = 0;
+        for i in 0..size {
+            prefix += self.keys[i].len() + self.vals[i].len();
+            if 2 * prefix >= total {
+                return (i + 1).min(size - 1).max(1);
+            }
+        }
+        size / 2
+    }
+
+    /// Moves the entries from `split` on to a new right sibling, which
+    /// is keyed by the shortest separator from the left entries. Both
+    /// halves keep at least one entry, so the leaf must hold two.
+    pub fn split(&mut self, cmp: &Comparator, split: usize) -> NewSibling<'a> {
+        let size = self.keys.len();
+        assert!(size > 1, "attempt to split a leaf with fewer than two entries");
+        let split = split.max(1).min(size - 1);
         let mut total = 0 as usize;
         for i in split..size {
             total += self.keys[i].bytes().len();
@@ -131,12 +444,22 @@ impl<'a> Leaf<'a> {
 
         self.keys.truncate(split);
         self.vals.truncate(split);
+        let sib_expires = self.expires.split_off(split);
+        let sib_blobs = self.blobs.split_off(split);
+        let sib_history = if self.history.is_empty() {
+            vec![]
+        } else {
+            self.history.split_off(split)
+        };
 
-        let key = sib_keys[0].bytes().to_vec();
+        let key = separator(cmp, self.keys[split - 1].bytes(), sib_keys[0].bytes());
         let body = Leaf {
             data: sib_data,
             keys: sib_keys,
             vals: sib_vals,
+            expires: sib_expires,
+            blobs: sib_blobs,
+            history: sib_history,
         };
         NewSibling {
             key: key,
@@ -144,40 +467,149 @@ impl<'a> Leaf<'a> {
         }
     }
 
-    pub fn upsert(&mut self, msg: Message) {
-        let loc = self.keys.binary_search_by_key(
-            &msg.key.as_slice(),
-            |buf| buf.bytes(),
-        );
-        match loc {
-            Ok(pos) => {
+    /// Applies a message to the leaf. Merges keep the expiry of the
+    /// value they extend, and start over on a value that expired at
+    /// `now`. Returns the blob of a value that the message replaced,
+    /// which is no longer referenced.
+    pub fn upsert(&mut self, cmp: &Comparator, mut msg: Message, now: u64) -> Option<BlobRef> {
+        let loc = self.keys.binary_search_by(|buf| cmp.compare(buf.bytes(), &msg.key));
+        if let Ok(pos) = loc {
+            self.restart_expired(pos, &mut msg, now);
+        }
+        let replaced = match loc {
+            Ok(pos) if self.blobs[pos] && msg.op != Operation::Merge => BlobRef::decode(self.vals[pos].bytes()).ok(),
+            _ => None,
+        };
+        let blob = msg.op.blob();
+        match (loc, msg.op) {
+            (Ok(pos), Operation::Delete) => self.remove(pos),
+            (Err(_), Operation::Delete) => {}
+            (Ok(pos), op) => {
+                if op != Operation::Merge {
+                    self.expires[pos] = msg.expires();
+                    self.blobs[pos] = blob;
+                }
                 msg.apply(&mut self.vals[pos]);
             }
+            (Err(pos), _) => {
+                let expires = msg.expires();
+                let (key, val) = msg.create();
+                self.keys.insert(pos, Buf::Owned(key));
+                self.vals.insert(pos, Buf::Owned(val));
+                self.expires.insert(pos, expires);
+                self.blobs.insert(pos, blob);
+            }
+        };
+        replaced
+    }
+
+    // a merge onto a value that expired at `now` assigns the merged
+    // bytes, as if the value had already been dropped
+    fn restart_expired(&self, pos: usize, msg: &mut Message, now: u64) {
+        if msg.op == Operation::Merge && self.expired(pos, now) {
+            msg.op = Operation::Assign;
+        }
+    }
+
+    /// Applies a message to a leaf that keeps versions. A message from
+    /// a later epoch than the current value turns that value into an
+    /// older version, and versions are dropped as `retention` allows.
+    /// Merges onto a value that expired at `now` start over.
+    pub fn upsert_versioned(&mut self, cmp: &Comparator, mut msg: Message, retention: Retention, now: u64) {
+        let epoch = msg.epoch;
+        let blob = msg.op.blob();
+        let pos = match self.keys.binary_search_by(|buf| cmp.compare(buf.bytes(), &msg.key)) {
+            Ok(pos) => pos,
+            Err(_) if msg.op == Operation::Delete => return,
             Err(pos) => {
+                let expires = msg.expires();
                 let (key, val) = msg.create();
                 self.keys.insert(pos, Buf::Owned(key));
                 self.vals.insert(pos, Buf::Owned(val));
+                self.expires.insert(pos, expires);
+                self.blobs.insert(pos, blob);
+                self.history.insert(pos, History {
+                    epoch: epoch,
+                    deleted: false,
+                    older: vec![],
+                });
+                return;
             }
         };
+        if msg.op == Operation::Delete && self.deleted(pos) {
+            return;
+        }
+        self.restart_expired(pos, &mut msg, now);
+        if self.history[pos].epoch != epoch {
+            let current = Version {
+                epoch: self.history[pos].epoch,
+                val: if self.deleted(pos) {
+                    None
+                } else {
+                    Some(self.vals[pos].to_vec())
+                },
+            };
+            self.history[pos].older.insert(0, current);
+            self.history[pos].epoch = epoch;
+        }
+        match msg.op {
+            Operation::Delete => {
+                self.vals[pos] = Buf::Owned(vec![]);
+                self.expires[pos] = 0;
+                self.blobs[pos] = false;
+                self.history[pos].deleted = true;
+            }
+            op => {
+                if op != Operation::Merge {
+                    self.expires[pos] = msg.expires();
+                    self.blobs[pos] = blob;
+                }
+                // the value of a deleted entry is empty
+                msg.apply(&mut self.vals[pos]);
+                self.history[pos].deleted = false;
+            }
+        }
+        retention.prune(epoch, &mut self.history[pos].older);
+        if self.history[pos].deleted && self.history[pos].older.is_empty() {
+            self.remove(pos);
+        }
+    }
+
+    fn apply(&mut self, tree: &mut Tree, msg: Message) {
+        let now = tree.clock.now();
+        match tree.versions {
+            Some(retention) => self.upsert_versioned(&*tree.comparator, msg, retention, now),
+            None => {
+                if let Some(blob) = self.upsert(&*tree.comparator, msg, now) {
+                    tree.retire(blob);
+                }
+            }
+        }
     }
 
     pub fn upsert_msg(&mut self, tree: &mut Tree, msg: Message) -> Option<NewSibling<'a>> {
-        self.upsert(msg);
-        if self.keys.len() < (tree.max_pivots + tree.max_buffer) {
-            None
+        self.track(tree.versions.is_some());
+        self.apply(tree, msg);
+        self.drop_expired(tree);
+        if self.full(tree) {
+            let split = self.midpoint(tree);
+            Some(self.split(&*tree.comparator, split))
         } else {
-            Some(self.split())
+            None
         }
     }
 
     pub fn upsert_msgs(&mut self, tree: &mut Tree, msgs: Vec<Message>) -> Option<NewSibling<'a>> {
+        self.track(tree.versions.is_some());
         for msg in msgs {
-            self.upsert(msg);
+            self.apply(tree, msg);
         }
-        if self.keys.len() < (tree.max_pivots + tree.max_buffer) {
-            None
+        self.drop_expired(tree);
+        if self.full(tree) {
+            let split = self.midpoint(tree);
+            Some(self.split(&*tree.comparator, split))
         } else {
-            Some(self.split())
+            None
         }
     }
 }
@@ -186,63 +618,140 @@ impl<'a> Leaf<'a> {
 mod tests {
     use super::*;
 
+    use index::clock::ManualClock;
+    use index::comparator::Bytewise;
+    use index::message::with_expiry;
     use index::mode::Mode;
-    use index::operation::Operation;
+    use index::tree::Capacity;
+
+    use std::sync::Arc;
 
     #[test]
     fn get_leaf() {
-        let input = Leaf {
-            data: vec![],
-            keys: vec![],
-            vals: vec![],
-        };
-        assert_eq!(input.get(b"hello"), None);
-        let input = Leaf {
-            data: vec![],
-            keys: vec![Buf::Owned(b"hello".to_vec())],
-            vals: vec![Buf::Owned(b"world".to_vec())],
-        };
-        assert_eq!(input.get(b"hello"), Some(&b"world"[..]));
+        let input = Leaf::new(vec![], vec![]);
+        assert_eq!(input.get(&Bytewise, b"hello"), None);
+        let input = Leaf::new(
+            vec![Buf::Owned(b"hello".to_vec())],
+            vec![Buf::Owned(b"world".to_vec())],
+        );
+        assert_eq!(input.get(&Bytewise, b"hello"), Some(&b"world"[..]));
     }
 
     #[test]
     fn upsert_leaf() {
         let mut tree = Tree::new(4, 16, Mode::Test);
-        let mut input = Leaf {
-            data: vec![],
-            keys: vec![],
-            vals: vec![],
-        };
-        let msg = Message {
-            op: Operation::Assign,
-            key: b"hello".to_vec(),
-            data: b"world".to_vec(),
-        };
+        let mut input = Leaf::new(vec![], vec![]);
+        let msg = Message::new(Operation::Assign, b"hello".to_vec(), b"world".to_vec());
         input.upsert_msg(&mut tree, msg);
-        assert_eq!(input.get(b"hello"), Some(&b"world"[..]));
-        let msg = Message {
-            op: Operation::Assign,
-            key: b"hello".to_vec(),
-            data: b"hello".to_vec(),
-        };
+        assert_eq!(input.get(&Bytewise, b"hello"), Some(&b"world"[..]));
+        let msg = Message::new(Operation::Assign, b"hello".to_vec(), b"hello".to_vec());
         input.upsert_msg(&mut tree, msg);
-        assert_eq!(input.get(b"hello"), Some(&b"hello"[..]));
-        let msg = Message {
-            op: Operation::Assign,
-            key: b"hello".to_vec(),
-            data: b"worlds".to_vec(),
-        };
+        assert_eq!(input.get(&Bytewise, b"hello"), Some(&b"hello"[..]));
+        let msg = Message::new(Operation::Assign, b"hello".to_vec(), b"worlds".to_vec());
         input.upsert_msg(&mut tree, msg);
-        assert_eq!(input.get(b"hello"), Some(&b"worlds"[..]));
+        assert_eq!(input.get(&Bytewise, b"hello"), Some(&b"worlds"[..]));
     }
 
     #[test]
-    fn roundtrip_empty_leaf() {
-        let input = Leaf {
-            data: vec![],
-            keys: vec![],
-            vals: vec![],
+    fn upsert_expired() {
+        let mut input = Leaf::new(vec![], vec![]);
+        input.upsert(&Bytewise, Message::new(Operation::AssignExpiring, b"a".to_vec(), with_expiry(20, b"x")), 0);
+        input.upsert(&Bytewise, Message::new(Operation::Merge, b"a".to_vec(), b"y".to_vec()), 0);
+        assert_eq!(Some((&b"xy"[..], 20)), input.get_expiring(&Bytewise, b"a"));
+        assert!(!input.has_expired(19));
+        assert!(input.has_expired(20));
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        tree.clock = Arc::new(ManualClock::new(20));
+        input.drop_expired(&mut tree);
+        assert!(input.keys.is_empty());
+        input.upsert(&Bytewise, Message::new(Operation::Merge, b"a".to_vec(), b"z".to_vec()), 0);
+        assert_eq!(Some((&b"z"[..], 0)), input.get_expiring(&Bytewise, b"a"));
+    }
+
+    #[test]
+    fn expire_versions() {
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        tree.clock = Arc::new(ManualClock::new(20));
+        tree.versions = Some(Retention::Last(3));
+        tree.epoch = 2;
+        let mut input = Leaf::new(vec![], vec![]);
+        let mut msg = Message::new(Operation::AssignExpiring, b"a".to_vec(), with_expiry(10, b"x"));
+        msg.epoch = 1;
+        input.upsert_msg(&mut tree, msg);
+        assert_eq!(None, input.get(&Bytewise, b"a"));
+        let epochs: Vec<(u64, Option<Vec<u8>>)> = input
+            .versions(&Bytewise, b"a")
+            .into_iter()
+            .map(|version| (version.epoch, version.val))
+            .collect();
+        assert_eq!(vec![(2, None), (1, Some(b"x".to_vec()))], epochs);
+    }
+
+    #[test]
+    fn upsert_versions() {
+        let msg = |op: Operation, data: &[u8], epoch: u64| Message {
+            op: op,
+            key: b"a".to_vec(),
+            data: data.to_vec(),
+            epoch: epoch,
+        };
+        let mut input = Leaf::new(vec![], vec![]);
+        input.track(true);
+        let retention = Retention::Last(3);
+        input.upsert_versioned(&Bytewise, msg(Operation::Assign, b"x", 1), retention, 0);
+        input.upsert_versioned(&Bytewise, msg(Operation::Merge, b"y", 1), retention, 0);
+        input.upsert_versioned(&Bytewise, msg(Operation::Delete, b"", 2), retention, 0);
+        assert_eq!(None, input.get(&Bytewise, b"a"));
+        input.upsert_versioned(&Bytewise, msg(Operation::Merge, b"z", 3), retention, 0);
+        let epochs: Vec<(u64, Option<Vec<u8>>)> = input
+            .versions(&Bytewise, b"a")
+            .into_iter()
+            .map(|version| (version.epoch, version.val))
+            .collect();
+        assert_eq!(vec![(3, Some(b"z".to_vec())), (2, None), (1, Some(b"xy".to_vec()))], epochs);
+        input.upsert_versioned(&Bytewise, msg(Operation::Delete, b"", 4), Retention::Last(1), 0);
+        assert!(input.keys.is_empty());
+
+        input.upsert_versioned(&Bytewise, msg(Operation::Assign, b"x", 5), retention, 0);
+        input.upsert_versioned(&Bytewise, msg(Operation::Assign, b"y", 6), retention, 0);
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        assert_eq!(input.size(), wtr.len());
+        let mut output = Leaf::deserialize(wtr).unwrap();
+        assert_eq!(input.versions(&Bytewise, b"a"), output.versions(&Bytewise, b"a"));
+        output.track(false);
+        assert!(output.history.is_empty());
+        assert_eq!(Some(&b"y"[..]), output.get(&Bytewise, b"a"));
+    }
+
+    #[test]
+    fn upsert_blobs() {
+        let blob = BlobRef {
+            id: 3,
+            len: 100,
+            chunk: 64,
         };
+        let mut input = Leaf::new(vec![], vec![]);
+        let replaced = input.upsert(&Bytewise, Message::new(Operation::AssignBlob, b"a".to_vec(), blob.encode()), 0);
+        assert_eq!(None, replaced);
+        input.upsert(&Bytewise, Message::new(Operation::Merge, b"a".to_vec(), b"+".to_vec()), 0);
+        let stored = input.get_stored(&Bytewise, b"a").unwrap();
+        assert!(stored.blob);
+        assert_eq!(b"+", &stored.val[BlobRef::SIZE..]);
+
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        assert_eq!(input.size(), wtr.len());
+        let mut output = Leaf::deserialize(wtr).unwrap();
+        assert_eq!(Some(stored), output.get_stored(&Bytewise, b"a"));
+        let replaced = output.upsert(&Bytewise, Message::new(Operation::Assign, b"a".to_vec(), b"inline".to_vec()), 0);
+        assert_eq!(Some(blob), replaced);
+        assert!(!output.get_stored(&Bytewise, b"a").unwrap().blob);
+    }
+
+    #[test]
+    fn roundtrip_empty_leaf() {
+        let input = Leaf::new(vec![], vec![]);
         let mut wtr = vec![];
         let result = input.serialize(&mut wtr);
         assert!(result.is_ok());
@@ -255,18 +764,18 @@ mod tests {
 
     #[test]
     fn roundtrip_nonempty_leaf() {
-        let input = Leaf {
-            data: vec![],
-            keys: vec![Buf::Owned(b"hello".to_vec())],
-            vals: vec![Buf::Owned(b"world".to_vec())],
-        };
+        let input = Leaf::new(
+            vec![Buf::Owned(b"hello".to_vec())],
+            vec![Buf::Owned(b"world".to_vec())],
+        );
         let mut wtr = vec![];
         let result = input.serialize(&mut wtr);
         assert!(result.is_ok());
         assert_eq!(
-            3 * size_of::<u64>() + "hello".len() + "world".len(),
+            5 * size_of::<u64>() + "hello".len() + "world".len(),
             wtr.len()
         );
+        assert_eq!(input.size(), wtr.len());
         let output = Leaf::deserialize(wtr);
         assert!(output.is_ok());
         let output = output.unwrap();
@@ -277,36 +786,77 @@ mod tests {
     #[test]
     fn split_leaf() {
         let mut tree = Tree::new(1, 1, Mode::Test);
-        let mut input = Leaf {
-            data: vec![],
-            keys: vec![],
-            vals: vec![],
-        };
+        let mut input = Leaf::new(vec![], vec![]);
         {
-            let msg = Message {
-                op: Operation::Assign,
-                key: b"foo".to_vec(),
-                data: b"abc".to_vec(),
-            };
+            let msg = Message::new(Operation::Assign, b"foo".to_vec(), b"abc".to_vec());
             let sibling = input.upsert_msg(&mut tree, msg);
             assert!(sibling.is_none());
         }
         {
-            let msg = Message {
-                op: Operation::Assign,
-                key: b"bar".to_vec(),
-                data: b"xyz".to_vec(),
-            };
+            let msg = Message::new(Operation::Assign, b"bar".to_vec(), b"xyz".to_vec());
             let sibling = input.upsert_msg(&mut tree, msg);
             assert!(sibling.is_some());
             let sibling = match sibling.unwrap().body {
                 Body::Leaf(node) => node,
                 Body::Internal(_) => panic!("expected leaf node"),
             };
-            assert_eq!(sibling.get(b"foo"), Some(&b"abc"[..]));
-            assert_eq!(sibling.get(b"bar"), None);
-            assert_eq!(input.get(b"foo"), None);
-            assert_eq!(input.get(b"bar"), Some(&b"xyz"[..]));
+            assert_eq!(sibling.get(&Bytewise, b"foo"), Some(&b"abc"[..]));
+            assert_eq!(sibling.get(&Bytewise, b"bar"), None);
+            assert_eq!(input.get(&Bytewise, b"foo"), None);
+            assert_eq!(input.get(&Bytewise, b"bar"), Some(&b"xyz"[..]));
+        }
+    }
+
+    #[test]
+    fn split_leaf_at_edges() {
+        for &split in &[0, 3] {
+            let mut input = Leaf::new(
+                vec![Buf::Owned(b"a".to_vec()), Buf::Owned(b"b".to_vec()), Buf::Owned(b"c".to_vec())],
+                vec![Buf::Owned(b"x".to_vec()), Buf::Owned(b"y".to_vec()), Buf::Owned(b"z".to_vec())],
+            );
+            let sibling = input.split(&Bytewise, split);
+            assert!(!input.keys.is_empty());
+            assert!(!sibling.body.leaf().keys.is_empty());
+            assert_eq!(3, input.keys.len() + sibling.body.leaf().keys.len());
+        }
+    }
+
+    #[test]
+    fn split_leaf_by_bytes() {
+        let mut tree = Tree::new(1, 1, Mode::Test);
+        tree.capacity = Capacity::Bytes {
+            leaf: 256,
+            buffer: 256,
+            pivots: 256,
+        };
+        let mut input = Leaf::new(vec![], vec![]);
+        for key in &[b"a", b"b", b"c"] {
+            let msg = Message::new(Operation::Assign, key.to_vec(), b"x".to_vec());
+            assert!(input.upsert_msg(&mut tree, msg).is_none());
         }
+        let msg = Message::new(Operation::Assign, b"d".to_vec(), vec![0; 200]);
+        let sibling = input.upsert_msg(&mut tree, msg);
+        assert!(sibling.is_some());
+        let sibling = sibling.unwrap();
+        assert_eq!(b"d".to_vec(), sibling.key);
+        assert_eq!(3, input.keys.len());
+        assert_eq!(1, sibling.body.leaf().keys.len());
+    }
+
+    #[test]
+    fn delete_leaf() {
+        let mut tree = Tree::new(4, 16, Mode::Test);
+        let mut input = Leaf::new(
+            vec![Buf::Owned(b"hello".to_vec())],
+            vec![Buf::Owned(b"world".to_vec())],
+        );
+        let msg = Message::new(Operation::Delete, b"foo".to_vec(), vec![]);
+        input.upsert_msg(&mut tree, msg);
+        assert_eq!(1, input.keys.len());
+        let msg = Message::new(Operation::Delete, b"hello".to_vec(), vec![]);
+        input.upsert_msg(&mut tree, msg);
+        assert_eq!(input.get(&Bytewise, b"hello"), None);
+        assert_eq!(0, input.keys.len());
+        assert_eq!(0, input.vals.len());
     }
 }
@@ -0,0 +1,129 @@
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+/// What is needed to reopen a tree: the latest committed epoch and
+/// root, the next node id, and the name of the key comparator.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub comparator: String,
+    pub epoch: u64,
+    pub id: u64,
+    pub root: Option<u64>,
+}
+
+impl Manifest {
+    pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
+        wtr.write_u64::<LittleEndian>(self.epoch)?;
+        wtr.write_u64::<LittleEndian>(self.id)?;
+        match self.root {
+            Some(root) => {
+                wtr.write_u8(1)?;
+                wtr.write_u64::<LittleEndian>(root)?;
+            }
+            None => wtr.write_u8(0)?,
+        }
+        write_string(wtr, &self.comparator)
+    }
+
+    pub fn deserialize(data: &[u8]) -> io::Result<Manifest> {
+        Manifest::read(&mut Cursor::new(data))
+    }
+
+    fn read(rdr: &mut Cursor<&[u8]>) -> io::Result<Manifest> {
+        let epoch = rdr.read_u64::<LittleEndian>()?;
+        let id = rdr.read_u64::<LittleEndian>()?;
+        let root = match rdr.read_u8()? {
+            0 => None,
+            _ => Some(rdr.read_u64::<LittleEndian>()?),
+        };
+        Ok(Manifest {
+            comparator: read_string(rdr)?,
+            epoch: epoch,
+            id: id,
+            root: root,
+        })
+    }
+}
+
+/// The manifests of several trees that share a store, recorded
+/// together so that a commit covering several trees is atomic.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FamilyManifest {
+    pub trees: Vec<(String, Manifest)>,
+}
+
+impl FamilyManifest {
+    pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
+        wtr.write_u32::<LittleEndian>(self.trees.len() as u32)?;
+        for (name, manifest) in &self.trees {
+            write_string(wtr, name)?;
+            manifest.serialize(wtr)?;
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(data: &[u8]) -> io::Result<FamilyManifest> {
+        let mut rdr = Cursor::new(data);
+        let count = rdr.read_u32::<LittleEndian>()?;
+        let mut trees = vec![];
+        for _ in 0..count {
+            let name = read_string(&mut rdr)?;
+            trees.push((name, Manifest::read(&mut rdr)?));
+        }
+        Ok(FamilyManifest { trees: trees })
+    }
+}
+
+fn write_string(wtr: &mut Write, val: &str) -> io::Result<()> {
+    wtr.write_u32::<LittleEndian>(val.len() as u32)?;
+    wtr.write_all(val.as_bytes())
+}
+
+fn read_string(rdr: &mut Cursor<&[u8]>) -> io::Result<String> {
+    let len = rdr.read_u32::<LittleEndian>()? as usize;
+    let mut data = vec![0; len];
+    rdr.read_exact(&mut data)?;
+    String::from_utf8(data).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "name is not utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_manifest() {
+        let input = Manifest {
+            comparator: "reverse".to_string(),
+            epoch: 7,
+            id: 42,
+            root: Some(40),
+        };
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        let output = Manifest::deserialize(&wtr).unwrap();
+        assert_eq!(input, output);
+        assert!(Manifest::deserialize(&wtr[..wtr.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn roundtrip_family_manifest() {
+        let tree = Manifest {
+            comparator: "bytewise".to_string(),
+            epoch: 3,
+            id: 0,
+            root: None,
+        };
+        let input = FamilyManifest {
+            trees: vec![("main".to_string(), tree.clone()), ("index".to_string(), tree)],
+        };
+        let mut wtr = vec![];
+        assert!(input.serialize(&mut wtr).is_ok());
+        assert_eq!(input, FamilyManifest::deserialize(&wtr).unwrap());
+    }
+}
@@ -1,20 +1,87 @@
 use super::operation::Operation;
 use super::buf::Buf;
 
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+/// The data of an `AssignExpiring` or `AssignExpiringBlob` message:
+/// the expiry time followed by the value.
+pub fn with_expiry(expires: u64, val: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; 8];
+    LittleEndian::write_u64(&mut data, expires);
+    data.extend_from_slice(val);
+    data
+}
+
+// splits the data of a message into its expiry, or 0 if it never
+// expires, and its value
+fn split_expiry(op: Operation, data: &[u8]) -> (u64, &[u8]) {
+    if op.expiring() {
+        (LittleEndian::read_u64(&data[..8]), &data[8..])
+    } else {
+        (0, data)
+    }
+}
+
+/// A value as the tree keeps it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stored {
+    pub val: Vec<u8>,
+    /// When the value expires, or 0 if it never does.
+    pub expires: u64,
+    /// Whether `val` starts with the `blob::BlobRef` of a value kept
+    /// outside the nodes, followed by the bytes merged onto it.
+    pub blob: bool,
+}
+
+impl Stored {
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
 #[derive(Debug)]
 pub struct Message {
     pub op: Operation,
     pub key: Vec<u8>,
     pub data: Vec<u8>,
+    /// Epoch of the commit that wrote the message when the tree keeps
+    /// versions, or 0.
+    pub epoch: u64,
 }
 
 impl<'a> Message {
+    /// A message that is not tied to an epoch.
+    pub fn new(op: Operation, key: Vec<u8>, data: Vec<u8>) -> Message {
+        Message {
+            op: op,
+            key: key,
+            data: data,
+            epoch: 0,
+        }
+    }
+
     pub fn create(self) -> (Vec<u8>, Vec<u8>) {
         match self.op {
-            Operation::Assign => (self.key, self.data),
+            Operation::Assign | Operation::Merge | Operation::AssignBlob => (self.key, self.data),
+            Operation::AssignExpiring | Operation::AssignExpiringBlob => {
+                let val = self.value().to_vec();
+                (self.key, val)
+            }
+            Operation::Delete => panic!("delete cannot create a value"),
         }
     }
 
+    /// When the value assigned by the message expires, or 0 if never.
+    pub fn expires(&self) -> u64 {
+        split_expiry(self.op, &self.data).0
+    }
+
+    /// The value assigned or appended by the message.
+    pub fn value(&self) -> &[u8] {
+        split_expiry(self.op, &self.data).1
+    }
+
     fn apply_assign(self, buf: &mut Buf) {
         if let Buf::Shared(ref mut val) = *buf {
             if val.len() == self.data.len() {
@@ -25,29 +92,67 @@ impl<'a> Message {
         *buf = Buf::Owned(self.data);
     }
 
+    fn apply_merge(self, buf: &mut Buf) {
+        let mut val = buf.to_vec();
+        val.extend_from_slice(&self.data);
+        *buf = Buf::Owned(val);
+    }
+
     pub fn apply(self, buf: &mut Buf) {
         match self.op {
-            Operation::Assign => self.apply_assign(buf),
+            Operation::Assign | Operation::AssignBlob => self.apply_assign(buf),
+            Operation::AssignExpiring | Operation::AssignExpiringBlob => *buf = Buf::Owned(self.value().to_vec()),
+            Operation::Delete => panic!("delete cannot be applied to a value"),
+            Operation::Merge => self.apply_merge(buf),
         };
     }
 
+    /// Applies the message to the current value of its key,
+    /// where `None` means that the key is absent. Expiry is ignored.
+    pub fn resolve(&self, val: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        match self.op {
+            Operation::Assign | Operation::AssignBlob => Some(self.data.clone()),
+            Operation::AssignExpiring | Operation::AssignExpiringBlob => Some(self.value().to_vec()),
+            Operation::Delete => None,
+            Operation::Merge => {
+                let mut val = val.unwrap_or_default();
+                val.extend_from_slice(&self.data);
+                Some(val)
+            }
+        }
+    }
+
     pub fn into_buf_message(self) -> BufMessage<'a> {
         BufMessage {
             op: self.op,
             key: Buf::Owned(self.key),
             data: Buf::Owned(self.data),
+            epoch: self.epoch,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BufMessage<'a> {
     pub op: Operation,
     pub key: Buf<'a>,
     pub data: Buf<'a>,
+    /// Epoch of the commit that wrote the message when the tree keeps
+    /// versions, or 0.
+    pub epoch: u64,
 }
 
 impl<'a> BufMessage<'a> {
+    /// A buffered message that is not tied to an epoch.
+    pub fn new(op: Operation, key: Buf<'a>, data: Buf<'a>) -> BufMessage<'a> {
+        BufMessage {
+            op: op,
+            key: key,
+            data: data,
+            epoch: 0,
+        }
+    }
+
     fn apply_assign(&self, buf: &mut Buf) {
         if let Buf::Owned(ref mut val) = *buf {
             val.clear();
@@ -63,10 +168,64 @@ impl<'a> BufMessage<'a> {
         *buf = Buf::Owned(self.data.to_vec());
     }
 
+    fn apply_merge(&self, buf: &mut Buf) {
+        if let Buf::Owned(ref mut val) = *buf {
+            val.extend_from_slice(self.data.bytes());
+            return;
+        }
+        let mut val = buf.to_vec();
+        val.extend_from_slice(self.data.bytes());
+        *buf = Buf::Owned(val);
+    }
+
     pub fn apply(&self, buf: &mut Buf) {
         match self.op {
-            Operation::Assign => self.apply_assign(buf),
+            Operation::Assign | Operation::AssignBlob => self.apply_assign(buf),
+            Operation::AssignExpiring | Operation::AssignExpiringBlob => *buf = Buf::Owned(self.value().to_vec()),
+            Operation::Delete => panic!("delete cannot be applied to a value"),
+            Operation::Merge => self.apply_merge(buf),
+        };
+    }
+
+    /// When the value assigned by the message expires, or 0 if never.
+    pub fn expires(&self) -> u64 {
+        split_expiry(self.op, self.data.bytes()).0
+    }
+
+    /// The value assigned or appended by the message.
+    pub fn value(&self) -> &[u8] {
+        split_expiry(self.op, self.data.bytes()).1
+    }
+
+    /// Applies the message to the current value of its key,
+    /// where `None` means that the key is absent. Expiry is ignored.
+    pub fn resolve(&self, val: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        match self.op {
+            Operation::Assign | Operation::AssignBlob => Some(self.data.to_vec()),
+            Operation::AssignExpiring | Operation::AssignExpiringBlob => Some(self.value().to_vec()),
+            Operation::Delete => None,
+            Operation::Merge => {
+                let mut val = val.unwrap_or_default();
+                val.extend_from_slice(self.data.bytes());
+                Some(val)
+            }
+        }
+    }
+
+    /// Like `resolve` for a stored value. A value that expired at `now`
+    /// is absent. Merges keep the expiry of the value they extend, and
+    /// append to a blob after its reference.
+    pub fn resolve_stored(&self, stored: Option<Stored>, now: u64) -> Option<Stored> {
+        let stored = stored.filter(|stored| !stored.expired(now));
+        let (expires, blob) = match (self.op, &stored) {
+            (Operation::Merge, Some(stored)) => (stored.expires, stored.blob),
+            _ => (self.expires(), self.op.blob()),
         };
+        self.resolve(stored.map(|stored| stored.val)).map(|val| Stored {
+            val: val,
+            expires: expires,
+            blob: blob,
+        })
     }
 
     pub fn into_message(self) -> Message {
@@ -82,6 +241,7 @@ impl<'a> BufMessage<'a> {
             op: self.op,
             key: key,
             data: data,
+            epoch: self.epoch,
         }
     }
 }
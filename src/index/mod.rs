@@ -1,11 +1,28 @@
+pub mod batch;
+pub mod blob;
 pub mod buf;
+pub mod buffer;
+pub mod cache;
+pub mod clock;
+pub mod codec;
+pub mod comparator;
+pub mod compression;
+pub mod encryption;
 pub mod error;
+pub mod families;
+pub mod filter;
+pub mod flush;
 pub mod internal;
 pub mod leaf;
+pub mod manifest;
 pub mod message;
 pub mod mode;
 pub mod node;
 pub mod operation;
+pub mod secondary;
+pub mod shared;
 pub mod store;
 pub mod transaction;
 pub mod tree;
+pub mod typed;
+pub mod version;
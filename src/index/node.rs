@@ -1,4 +1,8 @@
+use super::buf::Buf;
+use super::compression::Compression;
+use super::filter::LeafFilter;
 use super::internal::Internal;
+use super::comparator::KeyRange;
 use super::leaf::Leaf;
 use super::message::Message;
 use super::store::Store;
@@ -9,21 +13,25 @@ use std::io;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
+use std::mem::size_of;
 
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 
+#[derive(Clone)]
 pub struct Header {
     pub id: u64,
     pub epoch: u64,
 }
 
+#[derive(Clone)]
 pub enum Body<'a> {
     Leaf(Leaf<'a>),
     Internal(Internal<'a>),
 }
 
+#[derive(Clone)]
 pub struct Node<'a> {
     pub header: Header,
     pub body: Body<'a>,
@@ -37,6 +45,8 @@ pub struct NewSibling<'a> {
 pub struct NewChild {
     pub key: Vec<u8>,
     pub id: u64,
+    /// The filter of the new child if it is a leaf and filters are kept.
+    pub filter: Option<LeafFilter>,
 }
 
 impl<'a> Body<'a> {
@@ -47,6 +57,59 @@ impl<'a> Body<'a> {
         }
     }
 
+    /// Whether the node is a leaf holding values that expired at or
+    /// before `now`.
+    pub fn has_expired(&self, now: u64) -> bool {
+        match *self {
+            Body::Leaf(ref leaf) => leaf.has_expired(now),
+            Body::Internal(_) => false,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match *self {
+            Body::Leaf(ref leaf) => leaf.size(),
+            Body::Internal(ref internal) => internal.size(),
+        }
+    }
+
+    pub fn underflow(&self, tree: &Tree) -> bool {
+        match *self {
+            Body::Leaf(ref leaf) => leaf.underflow(tree),
+            Body::Internal(ref internal) => internal.underflow(tree),
+        }
+    }
+
+    /// Merges the right sibling into this node. If the merged node is
+    /// too large it is split again and the new right half is returned.
+    pub fn merge(
+        &mut self,
+        tree: &Tree,
+        separator: Vec<u8>,
+        right: Body<'a>,
+    ) -> Option<NewSibling<'a>> {
+        match (self, right) {
+            (&mut Body::Leaf(ref mut left), Body::Leaf(right)) => {
+                left.merge(right);
+                if left.full(tree) {
+                    let split = left.midpoint(tree);
+                    Some(left.split(&*tree.comparator, split))
+                } else {
+                    None
+                }
+            }
+            (&mut Body::Internal(ref mut left), Body::Internal(right)) => {
+                left.merge(separator, right);
+                if left.pivots_full(tree) {
+                    Some(left.split())
+                } else {
+                    None
+                }
+            }
+            _ => panic!("attempt to merge nodes of different levels"),
+        }
+    }
+
     pub fn leaf(&self) -> &Leaf<'a> {
         match *self {
             Body::Leaf(ref leaf) => leaf,
@@ -60,19 +123,27 @@ impl<'a> Node<'a> {
         self.header.id
     }
 
-    pub fn serialize(&self, wtr: &mut Write) -> io::Result<()> {
+    /// Uncompressed size of the serialized node in bytes.
+    pub fn size(&self) -> usize {
+        2 * size_of::<u64>() + 2 + self.body.size()
+    }
+
+    pub fn serialize(&self, wtr: &mut Write, compression: Compression) -> io::Result<()> {
         wtr.write_u64::<LittleEndian>(self.header.id)?;
         wtr.write_u64::<LittleEndian>(self.header.epoch)?;
+        wtr.write_all(&[compression.serialize()])?;
+        let mut body = vec![];
         match self.body {
             Body::Leaf(ref node) => {
-                wtr.write_all(&[0 as u8])?;
-                node.serialize(wtr)
+                body.write_all(&[0 as u8])?;
+                node.serialize(&mut body)?;
             }
             Body::Internal(ref node) => {
-                wtr.write_all(&[1 as u8])?;
-                node.serialize(wtr)
+                body.write_all(&[1 as u8])?;
+                node.serialize(&mut body)?;
             }
         }
+        wtr.write_all(&compression.compress(&body))
     }
 
     pub fn deserialize(input: Vec<u8>) -> io::Result<Node<'a>> {
@@ -85,9 +156,19 @@ impl<'a> Node<'a> {
         };
         let mut byte = [0 as u8];
         rdr.read_exact(&mut byte)?;
-        let body = match byte[0] {
-            0 => Body::Leaf(Leaf::deserialize(rdr.into_inner())?),
-            1 => Body::Internal(Internal::deserialize(rdr.into_inner())?),
+        let compression = Compression::deserialize(byte[0])?;
+        let offset = rdr.position() as usize;
+        let mut body = compression.decompress(&rdr.into_inner()[offset..])?;
+        if body.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "missing node type",
+            ));
+        }
+        let payload = body.split_off(1);
+        let body = match body[0] {
+            0 => Body::Leaf(Leaf::deserialize(payload)?),
+            1 => Body::Internal(Internal::deserialize(payload)?),
             _ => panic!("unknown node type"),
         };
         Ok(Node {
@@ -96,6 +177,65 @@ impl<'a> Node<'a> {
         })
     }
 
+    /// Assigns a new id in the current epoch to a node from an older
+    /// epoch, and schedules the old node for deletion.
+    pub fn copy_on_write(&mut self, tree: &mut Tree, txn: &mut Transaction) {
+        if self.header.epoch != tree.epoch {
+            txn.delete.push(self.header.id);
+            self.header.id = tree.next_id();
+            self.header.epoch = tree.epoch;
+        }
+    }
+
+    /// Removes root levels that are left with a single child and returns
+    /// the new root. Buffered messages are pushed into the child first;
+    /// if the child splits, the root keeps both halves. The given root
+    /// must already be written to the store, and so is the returned root.
+    pub fn collapse(
+        mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+    ) -> io::Result<Node<'a>> {
+        loop {
+            let (child_id, msgs) = match self.body {
+                Body::Internal(ref mut node) if node.children.len() == 1 => {
+                    let msgs = node.buffer
+                        .take(0)
+                        .into_iter()
+                        .map(|msg| msg.into_message())
+                        .collect::<Vec<_>>();
+                    (node.children[0], msgs)
+                }
+                _ => return Ok(self),
+            };
+            let mut child = store.read(child_id)?;
+            let newchild = if msgs.is_empty() {
+                None
+            } else {
+                child.upsert_msgs(tree, store, txn, msgs)?
+            };
+            child.copy_on_write(tree, txn);
+            store.write(&child)?;
+            if let Some(newchild) = newchild {
+                if let Body::Internal(ref mut node) = self.body {
+                    node.children[0] = child.id();
+                    node.keys.push(Buf::Owned(newchild.key));
+                    node.children.push(newchild.id);
+                    node.buffer.insert_child(1);
+                    node.filter_child(tree, &child);
+                    node.add_filter(newchild.id, newchild.filter);
+                    node.serde = false;
+                }
+                self.copy_on_write(tree, txn);
+                store.write(&self)?;
+                return Ok(self);
+            }
+            txn.delete.push(self.header.id);
+            self = child;
+        }
+    }
+
     fn upsert(
         &mut self,
         body: Option<NewSibling<'a>>,
@@ -104,6 +244,10 @@ impl<'a> Node<'a> {
     ) -> io::Result<Option<NewChild>> {
         if let Some(inner) = body {
             let (key, body) = (inner.key, inner.body);
+            let filter = match body {
+                Body::Leaf(ref leaf) => tree.filter_policy().map(|policy| policy.build(leaf)),
+                Body::Internal(_) => None,
+            };
 
             let id = tree.next_id();
             let header = Header {
@@ -115,12 +259,76 @@ impl<'a> Node<'a> {
                 body: body,
             };
             store.write(&sibling)?;
-            Ok(Some(NewChild { id: id, key: key }))
+            tree.stats.record_write(sibling.size());
+            Ok(Some(NewChild {
+                id: id,
+                key: key,
+                filter: filter,
+            }))
         } else {
             Ok(None)
         }
     }
 
+    /// Pushes the buffered messages in `range` down to the leaves
+    /// and drops expired values from the leaves it reaches.
+    pub fn compact(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        range: &KeyRange,
+    ) -> io::Result<Option<NewChild>> {
+        let body = match self.body {
+            Body::Internal(ref mut node) => node.compact(tree, store, txn, range)?,
+            Body::Leaf(ref mut node) => node.upsert_msgs(tree, vec![]),
+        };
+        self.upsert(body, tree, store)
+    }
+
+    /// Whether compacting the node with `msgs` changes it: leaves are
+    /// only rewritten to apply messages or drop expired values.
+    pub fn needs_compaction(&self, tree: &Tree, msgs: &[Message]) -> bool {
+        !msgs.is_empty() || self.body.level() > 0 || self.body.has_expired(tree.clock.now())
+    }
+
+    /// Applies `msgs` to the node, then compacts it if it did not split.
+    pub fn compact_msgs(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+        msgs: Vec<Message>,
+        range: &KeyRange,
+    ) -> io::Result<Option<NewChild>> {
+        let newchild = if msgs.is_empty() {
+            None
+        } else {
+            self.upsert_msgs(tree, store, txn, msgs)?
+        };
+        match newchild {
+            Some(newchild) => Ok(Some(newchild)),
+            None => self.compact(tree, store, txn, range),
+        }
+    }
+
+    /// Flushes the buffered messages of an internal node
+    /// to the children chosen by the flush policy.
+    pub fn flush(
+        &mut self,
+        tree: &mut Tree,
+        store: &mut Store<'a>,
+        txn: &mut Transaction,
+    ) -> io::Result<Option<NewChild>> {
+        let body = match self.body {
+            Body::Internal(ref mut node) if !node.buffer.is_empty() => {
+                node.parent_to_child(tree, store, txn)?
+            }
+            _ => None,
+        };
+        self.upsert(body, tree, store)
+    }
+
     pub fn upsert_msg(
         &mut self,
         tree: &mut Tree,
@@ -2,18 +2,64 @@
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Operation {
     Assign,
+    Delete,
+    /// Appends the data to the current value.
+    Merge,
+    /// Assigns a value that is treated as absent from the time in the
+    /// first eight bytes of the data on, see `message::with_expiry`.
+    AssignExpiring,
+    /// Assigns a value kept outside the nodes. The data is the
+    /// `blob::BlobRef` of the value.
+    AssignBlob,
+    /// Assigns an expiring value kept outside the nodes. The data is
+    /// the expiry time followed by the `blob::BlobRef` of the value.
+    AssignExpiringBlob,
 }
 
 impl Operation {
     pub fn serialize(self) -> u32 {
         match self {
             Operation::Assign => 1,
+            Operation::Delete => 2,
+            Operation::Merge => 3,
+            Operation::AssignExpiring => 4,
+            Operation::AssignBlob => 5,
+            Operation::AssignExpiringBlob => 6,
         }
     }
 
+    /// True when the operation replaces any earlier
+    /// message for the same key, so that those can be dropped.
+    pub fn supersedes(self) -> bool {
+        match self {
+            Operation::Assign
+            | Operation::Delete
+            | Operation::AssignExpiring
+            | Operation::AssignBlob
+            | Operation::AssignExpiringBlob => true,
+            Operation::Merge => false,
+        }
+    }
+
+    /// True when the value the operation assigns is the
+    /// `blob::BlobRef` of a value kept outside the nodes.
+    pub fn blob(self) -> bool {
+        self == Operation::AssignBlob || self == Operation::AssignExpiringBlob
+    }
+
+    /// True when the data starts with an expiry time.
+    pub fn expiring(self) -> bool {
+        self == Operation::AssignExpiring || self == Operation::AssignExpiringBlob
+    }
+
     pub fn deserialize(val: u32) -> Operation {
         match val {
             1 => Operation::Assign,
+            2 => Operation::Delete,
+            3 => Operation::Merge,
+            4 => Operation::AssignExpiring,
+            5 => Operation::AssignBlob,
+            6 => Operation::AssignExpiringBlob,
             _ => panic!("unknown operation"),
         }
     }
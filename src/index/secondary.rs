@@ -0,0 +1,110 @@
+use super::codec::OrderedKey;
+use super::error::ErrorType;
+use super::families::Entry;
+use super::store::Store;
+use super::transaction::Transaction;
+use super::tree::Tree;
+
+use std::cmp::Ordering;
+
+/// Maps a primary value to the secondary keys it is indexed under.
+pub type IndexFn = Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync;
+
+/// A secondary index kept in its own tree. Each pair of a secondary
+/// key and a primary key whose value produces it is stored as one
+/// empty entry, keyed by the `OrderedKey` encoding of the pair, so a
+/// write only touches the entries of the key it changes.
+pub struct Index {
+    pub primary: String,
+    pub name: String,
+    pub keys: Box<IndexFn>,
+}
+
+impl Index {
+    /// Adds the index writes matching the writes of `txn` on the
+    /// primary tree to `entries`, a transaction on the index tree.
+    pub fn update<'a>(
+        &self,
+        primary: &Tree,
+        store: &Store<'a>,
+        txn: &mut Transaction,
+        entries: &mut Transaction,
+    ) -> Result<(), ErrorType> {
+        let cmp = primary.comparator.clone();
+        let mut written: Vec<Vec<u8>> = txn.writes.msgs.iter().map(|msg| msg.key.clone()).collect();
+        written.sort_by(|a, b| cmp.compare(a, b));
+        written.dedup_by(|a, b| cmp.compare(a, b) == Ordering::Equal);
+        for key in written {
+            let new = txn.get_with(&key, |key| primary.get(store, key))?;
+            // get_with has recorded the committed value in the read set
+            let old = txn.reads.get(&key).cloned().expect("key was read");
+            let old_keys = self.secondary_keys(old);
+            let new_keys = self.secondary_keys(new);
+            for stale in old_keys.iter().filter(|sk| !new_keys.contains(sk)) {
+                entries.delete(&entry_key(stale, &key));
+            }
+            for added in new_keys.iter().filter(|sk| !old_keys.contains(sk)) {
+                entries.assign(&entry_key(added, &key), b"");
+            }
+        }
+        Ok(())
+    }
+
+    /// The primary keys indexed under `key` and their values, in the
+    /// order of the primary tree's comparator.
+    pub fn query<'a>(&self, primary: &Tree, index: &Tree, store: &Store<'a>, key: &[u8]) -> Result<Vec<Entry>, ErrorType> {
+        let mut prefix = vec![];
+        key.to_vec().write_key(&mut prefix);
+        let mut pairs = vec![];
+        for (entry, _) in index.scan_prefix(store, &prefix)? {
+            let mut rest = &entry[prefix.len()..];
+            let primary_key = Vec::read_key(&mut rest).map_err(ErrorType::IO)?;
+            if let Some(val) = primary.get(store, &primary_key)? {
+                pairs.push((primary_key, val));
+            }
+        }
+        let cmp = &primary.comparator;
+        pairs.sort_by(|a, b| cmp.compare(&a.0, &b.0));
+        Ok(pairs)
+    }
+
+    fn secondary_keys(&self, val: Option<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut keys = match val {
+            Some(val) => (self.keys)(&val),
+            None => vec![],
+        };
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+// the index tree key of the entry for `primary` under `secondary`
+fn entry_key(secondary: &[u8], primary: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    (secondary.to_vec(), primary.to_vec()).write_key(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_keys_group_by_secondary_key() {
+        let mut prefix = vec![];
+        b"a".to_vec().write_key(&mut prefix);
+        let mut keys = [
+            entry_key(b"a\0", b"x"),
+            entry_key(b"a", b"z"),
+            entry_key(b"b", b""),
+            entry_key(b"a", b""),
+        ];
+        keys.sort();
+        let grouped: Vec<bool> = keys.iter().map(|key| key.starts_with(&prefix)).collect();
+        assert_eq!(vec![true, true, false, false], grouped);
+        let mut rest = &keys[1][prefix.len()..];
+        assert_eq!(b"z".to_vec(), Vec::read_key(&mut rest).unwrap());
+        assert!(rest.is_empty());
+    }
+}
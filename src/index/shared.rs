@@ -0,0 +1,657 @@
+use super::batch::WriteBatch;
+use super::clock::Clock;
+use super::comparator::Comparator;
+use super::error::ErrorType;
+use super::node::Node;
+use super::store::Store;
+use super::transaction::Transaction;
+use super::tree::Tree;
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread;
+
+// flushes tried on a fork of the tree before one holds the writer lock
+const FLUSH_ATTEMPTS: usize = 3;
+
+/// A committed version of the tree. Nodes reachable from a snapshot
+/// are not deleted while a reader still holds it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub epoch: u64,
+    pub root: Option<u64>,
+}
+
+struct Writer {
+    tree: Tree,
+    // nodes replaced by the commit that followed each snapshot
+    retired: VecDeque<(Arc<Snapshot>, Vec<u64>)>,
+}
+
+// state shared between writers and the background flusher
+#[derive(Default)]
+struct Backlog {
+    bytes: usize,
+    full: bool,
+    // set while a flusher is running
+    limit: Option<usize>,
+    stop: bool,
+    error: Option<String>,
+}
+
+struct Inner<S> {
+    comparator: Arc<Comparator>,
+    clock: Arc<Clock>,
+    store: RwLock<S>,
+    current: RwLock<Arc<Snapshot>>,
+    writer: Mutex<Writer>,
+    backlog: Mutex<Backlog>,
+    changed: Condvar,
+}
+
+/// A cloneable handle that lets many threads read committed snapshots
+/// while one writer at a time commits transactions. The writer works on
+/// private copies of the nodes it changes and locks the store only for
+/// each node it persists, after which the new root is swapped in.
+pub struct SharedTree<S> {
+    inner: Arc<Inner<S>>,
+}
+
+/// Flushes the root buffer of a shared tree on a background thread,
+/// so that writes only append to the root. Writes wait while more
+/// than the configured number of bytes are buffered at the root.
+/// The flusher stops when it is dropped.
+pub struct Flusher<S: Store<'static> + Send + Sync + 'static> {
+    tree: SharedTree<S>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+/// Holds the nodes and blobs written by a commit until the tree syncs
+/// them, and defers the deletes so that older snapshots stay readable.
+struct Staged<'s, S: 's> {
+    store: &'s RwLock<S>,
+    nodes: HashMap<u64, Node<'static>>,
+    blobs: HashMap<u64, Vec<u8>>,
+    retired: Vec<u64>,
+    // every node and blob chunk written, kept after the sync
+    written: Vec<u64>,
+}
+
+impl<'s, S> Staged<'s, S> {
+    fn new(store: &'s RwLock<S>) -> Staged<'s, S> {
+        Staged {
+            store: store,
+            nodes: HashMap::new(),
+            blobs: HashMap::new(),
+            retired: vec![],
+            written: vec![],
+        }
+    }
+}
+
+impl<'s, S: Store<'static>> Staged<'s, S> {
+    fn persist(&self, persisted: &mut Vec<u64>) -> io::Result<()> {
+        for (&id, data) in &self.blobs {
+            self.store.write().expect("store lock poisoned").write_blob(id, data)?;
+            persisted.push(id);
+        }
+        for node in self.nodes.values() {
+            self.store.write().expect("store lock poisoned").write(node)?;
+            persisted.push(node.id());
+        }
+        self.store.write().expect("store lock poisoned").sync()
+    }
+}
+
+// deletes nodes and blob chunks, locking the store for each
+fn delete<'a, S: Store<'a>>(store: &RwLock<S>, ids: Vec<u64>) -> io::Result<()> {
+    for id in ids {
+        store.write().expect("store lock poisoned").schedule_delete(id)?;
+    }
+    store.write().expect("store lock poisoned").sync()
+}
+
+impl<'s, S: Store<'static> + Send + Sync> Store<'static> for Staged<'s, S> {
+    fn read(&self, id: u64) -> io::Result<Node<'static>> {
+        match self.nodes.get(&id) {
+            Some(node) => Ok(node.clone()),
+            None => self.store.read().expect("store lock poisoned").read(id),
+        }
+    }
+
+    fn read_shared(&self, id: u64) -> io::Result<Arc<Node<'static>>> {
+        match self.nodes.get(&id) {
+            Some(node) => Ok(Arc::new(node.clone())),
+            None => self.store.read().expect("store lock poisoned").read_shared(id),
+        }
+    }
+
+    fn write(&mut self, node: &Node<'static>) -> io::Result<()> {
+        self.nodes.insert(node.id(), node.clone());
+        self.written.push(node.id());
+        Ok(())
+    }
+
+    fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+        if self.nodes.remove(&id).is_none() && self.blobs.remove(&id).is_none() {
+            self.retired.push(id);
+        }
+        Ok(())
+    }
+
+    fn write_blob(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.blobs.insert(id, data.to_vec());
+        self.written.push(id);
+        Ok(())
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        match self.blobs.get(&id) {
+            Some(data) => Ok(data.clone()),
+            None => self.store.read().expect("store lock poisoned").read_blob(id),
+        }
+    }
+
+    fn last_id(&self) -> io::Result<u64> {
+        let staged = self.nodes.keys().chain(self.blobs.keys()).cloned().max().unwrap_or(0);
+        Ok(self.store.read().expect("store lock poisoned").last_id()?.max(staged))
+    }
+
+    // the store is locked for each write rather than for the whole
+    // sync, so readers of earlier snapshots go on between the writes
+    fn sync(&mut self) -> io::Result<()> {
+        let mut persisted = vec![];
+        if let Err(err) = self.persist(&mut persisted) {
+            // no root refers to what was written
+            let _ = delete(self.store, persisted);
+            return Err(err);
+        }
+        self.blobs.clear();
+        self.nodes.clear();
+        Ok(())
+    }
+
+    fn concurrent(&self) -> Option<&(Store<'static> + Sync)> {
+        Some(self)
+    }
+}
+
+impl<S> Clone for SharedTree<S> {
+    fn clone(&self) -> SharedTree<S> {
+        SharedTree { inner: self.inner.clone() }
+    }
+}
+
+impl<S: Store<'static> + Send + Sync> SharedTree<S> {
+    pub fn new(tree: Tree, store: S) -> SharedTree<S> {
+        let snapshot = Snapshot {
+            epoch: tree.epoch,
+            root: tree.root,
+        };
+        SharedTree {
+            inner: Arc::new(Inner {
+                comparator: tree.comparator.clone(),
+                clock: tree.clock.clone(),
+                store: RwLock::new(store),
+                current: RwLock::new(Arc::new(snapshot)),
+                writer: Mutex::new(Writer {
+                    tree: tree,
+                    retired: VecDeque::new(),
+                }),
+                backlog: Mutex::new(Backlog::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// The most recently committed snapshot.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.inner.current.read().expect("snapshot lock poisoned").clone()
+    }
+
+    /// Reads the committed value of a key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ErrorType> {
+        self.read(&self.snapshot(), key)
+    }
+
+    /// Reads the value of a key in a snapshot.
+    pub fn read(&self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>, ErrorType> {
+        match snapshot.root {
+            Some(root) => {
+                // locks the store for each node read, not the whole lookup
+                let store = Staged::new(&self.inner.store);
+                Tree::resolve(&*self.inner.comparator, &store, root, key, self.inner.clock.now())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Starts an optimistic transaction on the latest snapshot.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.snapshot().epoch, self.inner.comparator.clone())
+    }
+
+    /// Reads a key within a transaction.
+    pub fn txn_get(&self, txn: &mut Transaction, key: &[u8]) -> Result<Option<Vec<u8>>, ErrorType> {
+        txn.get_with(key, |key| self.get(key))
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<(), ErrorType> {
+        let mut txn = self.transaction();
+        txn.writes = batch;
+        self.commit(txn)
+    }
+
+    /// Commits a transaction and publishes the new root to readers.
+    pub fn commit(&self, txn: Transaction) -> Result<(), ErrorType> {
+        self.wait_for_backlog();
+        let mut writer = self.inner.writer.lock().expect("writer lock poisoned");
+        let mut staged = Staged::new(&self.inner.store);
+        writer.tree.commit(&mut staged, txn)?;
+        self.publish(&mut writer, staged.retired).map_err(ErrorType::IO)
+    }
+
+    /// Blocks while a flusher is running and the root buffer is over its limit.
+    fn wait_for_backlog(&self) {
+        let mut backlog = self.inner.backlog.lock().expect("backlog lock poisoned");
+        while backlog.limit.is_some_and(|limit| backlog.bytes > limit) {
+            backlog = self.inner.changed.wait(backlog).expect("backlog lock poisoned");
+        }
+    }
+
+    /// Swaps in the root of the last commit and wakes the flusher
+    /// and any writers waiting on the backlog.
+    fn publish(&self, writer: &mut Writer, retired: Vec<u64>) -> io::Result<()> {
+        let snapshot = Arc::new(Snapshot {
+            epoch: writer.tree.epoch,
+            root: writer.tree.root,
+        });
+        let prev = {
+            let mut current = self.inner.current.write().expect("snapshot lock poisoned");
+            mem::replace(&mut *current, snapshot)
+        };
+        writer.retired.push_back((prev, retired));
+        {
+            let mut backlog = self.inner.backlog.lock().expect("backlog lock poisoned");
+            backlog.bytes = writer.tree.backlog;
+            backlog.full = writer.tree.backlog_full;
+        }
+        self.inner.changed.notify_all();
+        self.release(writer)
+    }
+
+    /// Starts flushing the root buffer on a background thread.
+    /// Writes wait while more than `limit` bytes are buffered at the root.
+    pub fn start_flusher(&self, limit: usize) -> Flusher<S>
+    where
+        S: 'static,
+    {
+        self.inner.writer.lock().expect("writer lock poisoned").tree.defer_flush = true;
+        {
+            let mut backlog = self.inner.backlog.lock().expect("backlog lock poisoned");
+            backlog.limit = Some(limit);
+            backlog.stop = false;
+            backlog.error = None;
+        }
+        let tree = self.clone();
+        Flusher {
+            tree: self.clone(),
+            handle: Some(thread::spawn(move || tree.run_flusher())),
+        }
+    }
+
+    fn run_flusher(&self) {
+        loop {
+            {
+                let mut backlog = self.inner.backlog.lock().expect("backlog lock poisoned");
+                while !backlog.stop && !backlog.full && backlog.limit.is_some_and(|limit| backlog.bytes <= limit) {
+                    backlog = self.inner.changed.wait(backlog).expect("backlog lock poisoned");
+                }
+                if backlog.stop {
+                    break;
+                }
+            }
+            if let Err(err) = self.flush_root() {
+                let msg = match err {
+                    ErrorType::IO(err) => err.to_string(),
+                    ErrorType::Msg(msg) => msg,
+                    ErrorType::Conflict(_) => "unexpected conflict".to_string(),
+                };
+                self.inner.backlog.lock().expect("backlog lock poisoned").error = Some(msg);
+                break;
+            }
+        }
+        // writes flush inline again once the flusher is gone
+        self.inner.writer.lock().expect("writer lock poisoned").tree.defer_flush = false;
+        self.inner.backlog.lock().expect("backlog lock poisoned").limit = None;
+        self.inner.changed.notify_all();
+    }
+
+    /// Flushes the root buffer once and publishes the result. The flush
+    /// runs on a fork of the tree so that writers can commit meanwhile;
+    /// if one did, the flush is discarded and tried again. The last
+    /// attempt holds the writer lock throughout.
+    fn flush_root(&self) -> Result<(), ErrorType> {
+        for _ in 1..FLUSH_ATTEMPTS {
+            let mut flusher = self.fork_writer();
+            let mut staged = Staged::new(&self.inner.store);
+            flusher.flush(&mut staged)?;
+            if self.install_flush(flusher, staged)? {
+                return Ok(());
+            }
+        }
+        let mut writer = self.inner.writer.lock().expect("writer lock poisoned");
+        let mut staged = Staged::new(&self.inner.store);
+        writer.tree.flush(&mut staged)?;
+        self.publish(&mut writer, staged.retired).map_err(ErrorType::IO)
+    }
+
+    // a copy of the writer's tree at its last commit, taking node ids
+    // from the same counter
+    fn fork_writer(&self) -> Tree {
+        let mut writer = self.inner.writer.lock().expect("writer lock poisoned");
+        let ids = writer.tree.id_counter();
+        let mut flusher = writer.tree.fork(ids);
+        flusher.root = writer.tree.root;
+        flusher.backlog = writer.tree.backlog;
+        flusher.backlog_full = writer.tree.backlog_full;
+        flusher.flush_workers = writer.tree.flush_workers;
+        flusher
+    }
+
+    // makes the root flushed by a fork current, unless a commit landed
+    // since the fork, in which case what the flush wrote is deleted
+    fn install_flush(&self, flusher: Tree, staged: Staged<S>) -> Result<bool, ErrorType> {
+        let mut writer = self.inner.writer.lock().expect("writer lock poisoned");
+        // the flush ran in the transaction after the forked epoch
+        if writer.tree.epoch + 1 != flusher.epoch {
+            delete(&self.inner.store, staged.written).map_err(ErrorType::IO)?;
+            return Ok(false);
+        }
+        writer.tree.epoch = flusher.epoch;
+        writer.tree.root = flusher.root;
+        writer.tree.backlog = flusher.backlog;
+        writer.tree.backlog_full = flusher.backlog_full;
+        writer.tree.stats.merge(&flusher.stats);
+        writer.tree.flush = flusher.flush;
+        self.publish(&mut writer, staged.retired).map_err(ErrorType::IO)?;
+        Ok(true)
+    }
+
+    /// Deletes the nodes retired by commits whose preceding
+    /// snapshots are no longer held by any reader.
+    fn release(&self, writer: &mut Writer) -> io::Result<()> {
+        let mut ids = vec![];
+        while let Some(front) = writer.retired.front() {
+            if Arc::strong_count(&front.0) > 1 {
+                break;
+            }
+            let (_, retired) = writer.retired.pop_front().unwrap();
+            ids.extend(retired);
+        }
+        if ids.is_empty() {
+            return Ok(());
+        }
+        delete(&self.inner.store, ids)
+    }
+}
+
+impl<S: Store<'static> + Send + Sync + 'static> Flusher<S> {
+    /// Stops the flusher and returns the error that ended it, if any.
+    /// Buffered messages stay at the root until later writes flush them.
+    pub fn stop(mut self) -> Result<(), ErrorType> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), ErrorType> {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        let inner = &self.tree.inner;
+        inner.backlog.lock().expect("backlog lock poisoned").stop = true;
+        inner.changed.notify_all();
+        if handle.join().is_err() {
+            return Err(ErrorType::Msg("flusher panicked".to_string()));
+        }
+        match inner.backlog.lock().expect("backlog lock poisoned").error.take() {
+            Some(msg) => Err(ErrorType::Msg(msg)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: Store<'static> + Send + Sync + 'static> Drop for Flusher<S> {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::compression::LevelCompression;
+    use index::mode::Mode;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    use std::thread;
+
+    fn shared() -> SharedTree<NodeStore<MemStore>> {
+        let tree = Tree::new(4, 4, Mode::Test);
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        SharedTree::new(tree, store)
+    }
+
+    fn assign_all(tree: &SharedTree<NodeStore<MemStore>>, round: usize) {
+        let mut batch = WriteBatch::new();
+        for i in 0..20 {
+            let key = format!("key{:03}", i);
+            batch.assign(key.as_bytes(), format!("{}", round).as_bytes());
+        }
+        assert!(tree.write(batch).is_ok());
+    }
+
+    // fails every write once `writes` have succeeded
+    struct FailingStore {
+        store: NodeStore<MemStore>,
+        writes: Option<usize>,
+    }
+
+    impl FailingStore {
+        fn wrote(&mut self) -> io::Result<()> {
+            match self.writes {
+                Some(0) => Err(io::Error::other("write failed")),
+                Some(ref mut n) => {
+                    *n -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Store<'static> for FailingStore {
+        fn read(&self, id: u64) -> io::Result<Node<'static>> {
+            self.store.read(id)
+        }
+
+        fn write(&mut self, node: &Node<'static>) -> io::Result<()> {
+            self.wrote()?;
+            self.store.write(node)
+        }
+
+        fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+            Store::schedule_delete(&mut self.store, id)
+        }
+
+        fn last_id(&self) -> io::Result<u64> {
+            Store::last_id(&self.store)
+        }
+    }
+
+    #[test]
+    fn snapshot_outlives_commits() {
+        let tree = shared();
+        assign_all(&tree, 0);
+        let old = tree.snapshot();
+        for round in 1..10 {
+            assign_all(&tree, round);
+        }
+        assert_eq!(Some(b"0".to_vec()), tree.read(&old, b"key005").unwrap_or(None));
+        assert_eq!(Some(b"9".to_vec()), tree.get(b"key005").unwrap_or(None));
+        let root = old.root.unwrap();
+        drop(old);
+        assign_all(&tree, 10);
+        assert!(tree.inner.store.read().unwrap().read(root).is_err());
+        assert!(tree.inner.writer.lock().unwrap().retired.len() <= 1);
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let tree = shared();
+        assign_all(&tree, 0);
+        let mut readers = vec![];
+        for _ in 0..4 {
+            let tree = tree.clone();
+            readers.push(thread::spawn(move || {
+                for _ in 0..200 {
+                    let snapshot = tree.snapshot();
+                    let first = tree.read(&snapshot, b"key000").unwrap_or(None);
+                    assert!(first.is_some());
+                    for i in 1..20 {
+                        let key = format!("key{:03}", i);
+                        let val = tree.read(&snapshot, key.as_bytes()).unwrap_or(None);
+                        assert_eq!(first, val);
+                    }
+                }
+            }));
+        }
+        let writer = {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for round in 1..50 {
+                    assign_all(&tree, round);
+                }
+            })
+        };
+        assert!(writer.join().is_ok());
+        for reader in readers {
+            assert!(reader.join().is_ok());
+        }
+        assert_eq!(Some(b"49".to_vec()), tree.get(b"key019").unwrap_or(None));
+    }
+
+    #[test]
+    fn shared_transactions() {
+        let tree = shared();
+        assign_all(&tree, 0);
+        let mut first = tree.transaction();
+        let mut second = tree.transaction();
+        assert!(tree.txn_get(&mut first, b"key001").unwrap_or(None).is_some());
+        assert!(tree.txn_get(&mut second, b"key001").unwrap_or(None).is_some());
+        first.assign(b"key001", b"a");
+        second.assign(b"key001", b"b");
+        assert!(tree.commit(first).is_ok());
+        assert!(tree.commit(second).is_err());
+        assert_eq!(Some(b"a".to_vec()), tree.get(b"key001").unwrap_or(None));
+    }
+
+    #[test]
+    fn flush_discarded_after_commit() {
+        let tree = shared();
+        for round in 0..3 {
+            assign_all(&tree, round);
+        }
+        assert!(tree.inner.writer.lock().unwrap().tree.backlog > 0);
+        let mut flusher = tree.fork_writer();
+        let mut staged = Staged::new(&tree.inner.store);
+        assert!(flusher.flush(&mut staged).unwrap_or(false));
+        let written = staged.written.clone();
+        assert!(!written.is_empty());
+        // a commit lands while the fork flushes
+        assign_all(&tree, 3);
+        assert!(tree.install_flush(flusher, staged).ok() == Some(false));
+        let store = tree.inner.store.read().unwrap();
+        assert!(written.iter().all(|id| !store.blocks.blocks.contains_key(id)));
+        drop(store);
+
+        let mut flusher = tree.fork_writer();
+        let mut staged = Staged::new(&tree.inner.store);
+        assert!(flusher.flush(&mut staged).unwrap_or(false));
+        let epoch = tree.snapshot().epoch;
+        assert!(tree.install_flush(flusher, staged).ok() == Some(true));
+        assert_eq!(epoch + 1, tree.snapshot().epoch);
+        assert!(tree.inner.writer.lock().unwrap().tree.stats.flushes > 0);
+        for i in 0..20 {
+            let key = format!("key{:03}", i);
+            assert_eq!(Some(b"3".to_vec()), tree.get(key.as_bytes()).unwrap_or(None));
+        }
+    }
+
+    #[test]
+    fn background_flusher() {
+        let tree = shared();
+        for round in 0..5 {
+            assign_all(&tree, round);
+        }
+        let flusher = tree.start_flusher(1 << 20);
+        for i in 0..200 {
+            let key = format!("other{:03}", i);
+            let mut batch = WriteBatch::new();
+            batch.assign(key.as_bytes(), b"x");
+            assert!(tree.write(batch).is_ok());
+        }
+        assert!(flusher.stop().is_ok());
+        assert!(!tree.inner.writer.lock().unwrap().tree.defer_flush);
+        for i in 0..200 {
+            let key = format!("other{:03}", i);
+            assert_eq!(Some(b"x".to_vec()), tree.get(key.as_bytes()).unwrap_or(None));
+        }
+        assert_eq!(Some(b"4".to_vec()), tree.get(b"key000").unwrap_or(None));
+    }
+
+    #[test]
+    fn flusher_backpressure() {
+        let tree = shared();
+        assign_all(&tree, 0);
+        let _flusher = tree.start_flusher(0);
+        for round in 1..20 {
+            assign_all(&tree, round);
+            tree.wait_for_backlog();
+            assert_eq!(0, tree.inner.backlog.lock().unwrap().bytes);
+        }
+        assert_eq!(Some(b"19".to_vec()), tree.get(b"key010").unwrap_or(None));
+    }
+
+    #[test]
+    fn failed_sync_deletes_written_nodes() {
+        let store = FailingStore {
+            store: NodeStore::new(MemStore::new(), LevelCompression::none()),
+            writes: None,
+        };
+        let tree = SharedTree::new(Tree::new(4, 4, Mode::Test), store);
+        let mut batch = WriteBatch::new();
+        for i in 0..20 {
+            batch.assign(format!("key{:03}", i).as_bytes(), b"0");
+        }
+        assert!(tree.write(batch).is_ok());
+        let blocks = tree.inner.store.read().unwrap().store.blocks.blocks.len();
+        tree.inner.store.write().unwrap().writes = Some(1);
+        let mut batch = WriteBatch::new();
+        for i in 0..20 {
+            batch.assign(format!("key{:03}", i).as_bytes(), b"1");
+        }
+        assert!(tree.write(batch).is_err());
+        assert_eq!(blocks, tree.inner.store.read().unwrap().store.blocks.blocks.len());
+        tree.inner.store.write().unwrap().writes = None;
+        assert_eq!(Some(b"0".to_vec()), tree.get(b"key007").unwrap_or(None));
+    }
+}
@@ -1,38 +1,315 @@
+use super::compression::LevelCompression;
 use super::node::Node;
 
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
-use std::path::Path;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 pub trait Store<'a> {
     fn read(&self, id: u64) -> io::Result<Node<'a>>;
     fn write(&mut self, node: &Node<'a>) -> io::Result<()>;
     fn schedule_delete(&mut self, id: u64) -> io::Result<()>;
+
+    /// Reads a node that is only looked at. Stores that keep decoded
+    /// nodes hand out the one they hold instead of a copy.
+    fn read_shared(&self, id: u64) -> io::Result<Arc<Node<'a>>> {
+        self.read(id).map(Arc::new)
+    }
+
+    /// Writes a chunk of a value kept outside the nodes, see
+    /// `blob::BlobRef`. Chunks are deleted with `schedule_delete`.
+    fn write_blob(&mut self, _id: u64, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store does not keep blobs"))
+    }
+
+    fn read_blob(&self, _id: u64) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store does not keep blobs"))
+    }
+
+    /// The highest id of a node or blob chunk in the store, or 0 if it
+    /// is empty. New ids are handed out after it.
+    fn last_id(&self) -> io::Result<u64> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store does not list its ids"))
+    }
+
+    /// Makes every node written so far durable.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The store for reading nodes from flush worker threads,
+    /// or `None` if it cannot be read concurrently.
+    fn concurrent(&self) -> Option<&(Store<'a> + Sync)> {
+        None
+    }
+}
+
+/// Raw storage for serialized nodes and blob chunks, addressed by id.
+pub trait Blocks: Send + Sync {
+    fn read(&self, id: u64) -> io::Result<Vec<u8>>;
+    fn write(&mut self, id: u64, data: &[u8]) -> io::Result<()>;
+    fn remove(&mut self, id: u64) -> io::Result<()>;
+    /// The highest id in use, or 0 if there is none.
+    fn last_id(&self) -> io::Result<u64>;
+}
+
+/// Serializes nodes into a block store, compressing
+/// each node with the codec configured for its level.
+pub struct NodeStore<B: Blocks> {
+    pub blocks: B,
+    pub compression: LevelCompression,
 }
 
 pub struct LocalStore {
-    pub path: Path,
+    pub path: PathBuf,
 }
 
-impl<'a> LocalStore {
-    pub fn read(&self, id: u64) -> io::Result<Node<'a>> {
+/// Reads through to a shared store and keeps its own writes and
+/// deletes, so that a flush worker can hand them back when it is done.
+pub struct Overlay<'r, 'a: 'r> {
+    pub base: &'r (Store<'a> + Sync),
+    pub nodes: HashMap<u64, Node<'a>>,
+    pub blobs: HashMap<u64, Vec<u8>>,
+    pub deletes: Vec<u64>,
+}
+
+#[derive(Default)]
+pub struct MemStore {
+    pub blocks: HashMap<u64, Vec<u8>>,
+}
+
+impl<B: Blocks> NodeStore<B> {
+    pub fn new(blocks: B, compression: LevelCompression) -> NodeStore<B> {
+        NodeStore {
+            blocks: blocks,
+            compression: compression,
+        }
+    }
+}
+
+impl<'a, B: Blocks> Store<'a> for NodeStore<B> {
+    fn read(&self, id: u64) -> io::Result<Node<'a>> {
+        Node::deserialize(self.blocks.read(id)?)
+    }
+
+    fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+        let compression = self.compression.for_level(node.body.level());
+        let mut buffer = vec![];
+        node.serialize(&mut buffer, compression)?;
+        self.blocks.write(node.id(), &buffer)
+    }
+
+    fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+        self.blocks.remove(id)
+    }
+
+    fn write_blob(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.blocks.write(id, data)
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        self.blocks.read(id)
+    }
+
+    fn last_id(&self) -> io::Result<u64> {
+        self.blocks.last_id()
+    }
+
+    fn concurrent(&self) -> Option<&(Store<'a> + Sync)> {
+        Some(self)
+    }
+}
+
+impl<'r, 'a> Overlay<'r, 'a> {
+    pub fn new(base: &'r (Store<'a> + Sync)) -> Overlay<'r, 'a> {
+        Overlay {
+            base: base,
+            nodes: HashMap::new(),
+            blobs: HashMap::new(),
+            deletes: vec![],
+        }
+    }
+}
+
+impl<'r, 'a> Store<'a> for Overlay<'r, 'a> {
+    fn read(&self, id: u64) -> io::Result<Node<'a>> {
+        match self.nodes.get(&id) {
+            Some(node) => Ok(node.clone()),
+            None => self.base.read(id),
+        }
+    }
+
+    fn read_shared(&self, id: u64) -> io::Result<Arc<Node<'a>>> {
+        match self.nodes.get(&id) {
+            Some(node) => Ok(Arc::new(node.clone())),
+            None => self.base.read_shared(id),
+        }
+    }
+
+    fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+        self.nodes.insert(node.id(), node.clone());
+        Ok(())
+    }
+
+    fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+        if self.nodes.remove(&id).is_none() && self.blobs.remove(&id).is_none() {
+            self.deletes.push(id);
+        }
+        Ok(())
+    }
+
+    fn write_blob(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.blobs.insert(id, data.to_vec());
+        Ok(())
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        match self.blobs.get(&id) {
+            Some(data) => Ok(data.clone()),
+            None => self.base.read_blob(id),
+        }
+    }
+
+    fn last_id(&self) -> io::Result<u64> {
+        let staged = self.nodes.keys().chain(self.blobs.keys()).cloned().max().unwrap_or(0);
+        Ok(self.base.last_id()?.max(staged))
+    }
+}
+
+impl LocalStore {
+    pub fn new(path: PathBuf) -> LocalStore {
+        LocalStore { path: path }
+    }
+}
+
+impl Blocks for LocalStore {
+    fn read(&self, id: u64) -> io::Result<Vec<u8>> {
         let file_path = self.path.join(id.to_string());
         let mut file = File::open(file_path)?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
-        Node::deserialize(buffer)
+        Ok(buffer)
     }
 
-    pub fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
-        let file_path = self.path.join(node.id().to_string());
+    fn write(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        let file_path = self.path.join(id.to_string());
         let mut file = File::create(file_path)?;
-        node.serialize(&mut file)
+        file.write_all(data)
     }
 
-    pub fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+    fn remove(&mut self, id: u64) -> io::Result<()> {
         let file_path = self.path.join(id.to_string());
         fs::remove_file(file_path)
     }
+
+    // files whose names are not ids are skipped
+    fn last_id(&self) -> io::Result<u64> {
+        let mut last = 0;
+        for entry in fs::read_dir(&self.path)? {
+            if let Some(id) = entry?.file_name().to_str().and_then(|name| name.parse::<u64>().ok()) {
+                last = last.max(id);
+            }
+        }
+        Ok(last)
+    }
+}
+
+impl MemStore {
+    pub fn new() -> MemStore {
+        MemStore { blocks: HashMap::new() }
+    }
+}
+
+impl Blocks for MemStore {
+    fn read(&self, id: u64) -> io::Result<Vec<u8>> {
+        match self.blocks.get(&id) {
+            Some(data) => Ok(data.clone()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("block {} not found", id),
+            )),
+        }
+    }
+
+    fn write(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.blocks.insert(id, data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, id: u64) -> io::Result<()> {
+        self.blocks.remove(&id);
+        Ok(())
+    }
+
+    fn last_id(&self) -> io::Result<u64> {
+        Ok(self.blocks.keys().cloned().max().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::buf::Buf;
+    use index::comparator::Bytewise;
+    use index::compression::Compression;
+    use index::leaf::Leaf;
+    use index::node::Body;
+    use index::node::Header;
+
+    fn leaf_node<'a>(id: u64) -> Node<'a> {
+        let mut keys = vec![];
+        let mut vals = vec![];
+        for i in 0..16 {
+            keys.push(Buf::Owned(format!("key{:02}", i).into_bytes()));
+            vals.push(Buf::Owned(b"{\"value\": \"repeated\"}".to_vec()));
+        }
+        Node {
+            header: Header { id: id, epoch: 1 },
+            body: Body::Leaf(Leaf::new(keys, vals)),
+        }
+    }
+
+    #[test]
+    fn roundtrip_compressed_node() {
+        let mut plain = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let compression = LevelCompression::new(vec![Compression::Deflate, Compression::Lz4]);
+        let mut packed = NodeStore::new(MemStore::new(), compression);
+        let node = leaf_node(7);
+        assert!(plain.write(&node).is_ok());
+        assert!(packed.write(&node).is_ok());
+        assert!(packed.blocks.blocks[&7].len() < plain.blocks.blocks[&7].len());
+        let output = packed.read(7);
+        assert!(output.is_ok());
+        let output = output.unwrap();
+        assert_eq!(7, output.id());
+        assert_eq!(1, output.header.epoch);
+        let leaf = output.body.leaf();
+        assert_eq!(16, leaf.keys.len());
+        assert_eq!(Some(&b"{\"value\": \"repeated\"}"[..]), leaf.get(&Bytewise, b"key03"));
+    }
+
+    #[test]
+    fn missing_block() {
+        let store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(store.read(1).is_err());
+    }
+
+    #[test]
+    fn last_id() {
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert_eq!(0, store.last_id().unwrap());
+        assert!(store.write(&leaf_node(7)).is_ok());
+        assert!(store.write_blob(9, b"chunk").is_ok());
+        assert!(store.write(&leaf_node(3)).is_ok());
+        assert_eq!(9, store.last_id().unwrap());
+        let mut overlay = Overlay::new(&store);
+        assert!(overlay.write(&leaf_node(12)).is_ok());
+        assert_eq!(12, overlay.last_id().unwrap());
+    }
 }
@@ -1,5 +1,138 @@
+use super::batch::WriteBatch;
+use super::comparator::Comparator;
+use super::error::ErrorType;
+use super::store::Store;
+use super::tree::Tree;
+
+use std::cmp::Ordering;
+use std::slice;
+use std::sync::Arc;
 
 pub struct Transaction {
     pub epoch: u64,
     pub delete: Vec<u64>,
+    /// Keys read by the transaction and the committed values it saw.
+    pub reads: ReadSet,
+    /// Writes held back until the transaction commits.
+    pub writes: WriteBatch,
+}
+
+/// Keys read by a transaction, in the order of the tree's comparator.
+/// Keys the comparator considers equal are recorded once.
+pub struct ReadSet {
+    comparator: Arc<Comparator>,
+    entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl ReadSet {
+    pub fn new(comparator: Arc<Comparator>) -> ReadSet {
+        ReadSet {
+            comparator: comparator,
+            entries: vec![],
+        }
+    }
+
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|entry| self.comparator.compare(&entry.0, key))
+    }
+
+    /// The committed value seen for `key`, if it was read.
+    pub fn get(&self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.search(key).ok().map(|pos| &self.entries[pos].1)
+    }
+
+    /// Records the value seen for `key`, replacing an earlier one.
+    pub fn insert(&mut self, key: Vec<u8>, val: Option<Vec<u8>>) {
+        match self.search(&key) {
+            Ok(pos) => self.entries[pos].1 = val,
+            Err(pos) => self.entries.insert(pos, (key, val)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, (Vec<u8>, Option<Vec<u8>>)> {
+        self.entries.iter()
+    }
+}
+
+impl<'r> IntoIterator for &'r ReadSet {
+    type Item = &'r (Vec<u8>, Option<Vec<u8>>);
+    type IntoIter = slice::Iter<'r, (Vec<u8>, Option<Vec<u8>>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Transaction {
+    /// A transaction whose reads are keyed by `comparator`, which must
+    /// be the one of the tree it is committed to.
+    pub fn new(epoch: u64, comparator: Arc<Comparator>) -> Transaction {
+        Transaction {
+            epoch: epoch,
+            delete: vec![],
+            reads: ReadSet::new(comparator),
+            writes: WriteBatch::new(),
+        }
+    }
+
+    /// Reads a key as seen by this transaction, including its own
+    /// writes. The committed value is recorded in the read set and
+    /// returned again by later reads of the same key.
+    pub fn get<'a>(
+        &mut self,
+        tree: &Tree,
+        store: &Store<'a>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, ErrorType> {
+        self.get_with(key, |key| tree.get(store, key))
+    }
+
+    /// Like `get`, with `read` supplying the committed value of the key.
+    pub fn get_with<F>(&mut self, key: &[u8], read: F) -> Result<Option<Vec<u8>>, ErrorType>
+    where
+        F: FnOnce(&[u8]) -> Result<Option<Vec<u8>>, ErrorType>,
+    {
+        let mut val = match self.reads.get(key) {
+            Some(val) => val.clone(),
+            None => {
+                let val = read(key)?;
+                self.reads.insert(key.to_vec(), val.clone());
+                val
+            }
+        };
+        let cmp = &self.reads.comparator;
+        for msg in self.writes.msgs.iter().filter(|msg| cmp.compare(&msg.key, key) == Ordering::Equal) {
+            val = msg.resolve(val);
+        }
+        Ok(val)
+    }
+
+    pub fn assign(&mut self, key: &[u8], data: &[u8]) -> &mut Transaction {
+        self.writes.assign(key, data);
+        self
+    }
+
+    pub fn assign_expiring(&mut self, key: &[u8], data: &[u8], expires: u64) -> &mut Transaction {
+        self.writes.assign_expiring(key, data, expires);
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Transaction {
+        self.writes.delete(key);
+        self
+    }
+
+    pub fn merge(&mut self, key: &[u8], data: &[u8]) -> &mut Transaction {
+        self.writes.merge(key, data);
+        self
+    }
 }
@@ -1,18 +1,206 @@
+use super::batch::WriteBatch;
+use super::blob::BlobPolicy;
+use super::blob::BlobRef;
+use super::blob::ValueReader;
+use super::buf::Buf;
+use super::clock::Clock;
+use super::clock::SystemClock;
+use super::comparator::separator;
+use super::comparator::Bytewise;
+use super::comparator::Comparator;
 use super::error::ErrorType;
+use super::families::Entry;
+use super::filter::FilterPolicy;
+use super::flush::FlushPolicy;
+use super::flush::FlushStats;
+use super::flush::LargestRun;
+use super::internal::Internal;
+use super::leaf::Leaf;
+use super::manifest::Manifest;
+use super::message::BufMessage;
+use super::message::Message;
+use super::message::Stored;
+use super::message::with_expiry;
 use super::mode::Mode;
+use super::node::Body;
+use super::node::Header;
+use super::node::NewChild;
+use super::node::Node;
+use super::operation::Operation;
 use super::store::Store;
 use super::transaction::Transaction;
+use super::version::Retention;
+use super::version::Version;
 
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::io;
+use std::mem;
+use std::mem::size_of;
+use std::ops::RangeBounds;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic;
+use std::sync::Arc;
+
+/// A key and its stored value, or `None` if absent.
+type Folded = (Vec<u8>, Option<Stored>);
+
+// applies messages in key order, oldest first for each key,
+// to entries in key order, treating values expired at `now` as absent
+fn fold(cmp: &Comparator, entries: Vec<Folded>, msgs: &[&BufMessage], now: u64) -> Vec<Folded> {
+    let mut out = Vec::with_capacity(entries.len() + msgs.len());
+    let mut entries = entries.into_iter().peekable();
+    let mut i = 0;
+    while i < msgs.len() {
+        let key = msgs[i].key.bytes();
+        while let Some(entry) = entries.next_if(|entry| cmp.compare(&entry.0, key) == Ordering::Less) {
+            out.push(entry);
+        }
+        let mut val = entries
+            .next_if(|entry| cmp.compare(&entry.0, key) == Ordering::Equal)
+            .and_then(|entry| entry.1);
+        while i < msgs.len() && cmp.compare(msgs[i].key.bytes(), key) == Ordering::Equal {
+            val = msgs[i].resolve_stored(val, now);
+            i += 1;
+        }
+        out.push((key.to_vec(), val));
+    }
+    out.extend(entries);
+    out
+}
+
+// reads a stored value into memory along with its blob
+fn load(store: &Store, stored: Stored) -> io::Result<Vec<u8>> {
+    if stored.blob {
+        ValueReader::new(store, stored)?.into_vec()
+    } else {
+        Ok(stored.val)
+    }
+}
+
+/// Passes a transaction's reads and writes through to a store, and
+/// keeps the size of the last version of each node written.
+struct Tally<'s, 'a: 's> {
+    store: &'s mut Store<'a>,
+    written: HashMap<u64, usize>,
+    // nodes from before the transaction deleted while it was open
+    freed: u64,
+}
+
+impl<'s, 'a> Store<'a> for Tally<'s, 'a> {
+    fn read(&self, id: u64) -> io::Result<Node<'a>> {
+        self.store.read(id)
+    }
+
+    fn read_shared(&self, id: u64) -> io::Result<Arc<Node<'a>>> {
+        self.store.read_shared(id)
+    }
+
+    fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+        self.store.write(node)?;
+        self.written.insert(node.id(), node.size());
+        Ok(())
+    }
+
+    // a node whose delete fails is still in the store
+    fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+        let size = match self.written.get(&id) {
+            Some(_) => None,
+            None => Some(self.store.read_shared(id)?.size() as u64),
+        };
+        self.store.schedule_delete(id)?;
+        self.written.remove(&id);
+        self.freed += size.unwrap_or(0);
+        Ok(())
+    }
+
+    fn write_blob(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.store.write_blob(id, data)
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        self.store.read_blob(id)
+    }
+
+    fn last_id(&self) -> io::Result<u64> {
+        self.store.last_id()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.store.sync()
+    }
+
+    fn concurrent(&self) -> Option<&(Store<'a> + Sync)> {
+        self.store.concurrent()
+    }
+}
+
+/// How full a node may grow before it splits or flushes.
+#[derive(Clone, Copy)]
+pub enum Capacity {
+    /// Entry counts bounded by `Tree::max_pivots` and `Tree::max_buffer`.
+    Count,
+    /// Serialized sizes in bytes. A leaf splits once it reaches `leaf`
+    /// bytes, an internal node flushes once its buffered messages reach
+    /// `buffer` bytes and splits once its pivots reach `pivots` bytes.
+    Bytes {
+        leaf: usize,
+        buffer: usize,
+        pivots: usize,
+    },
+}
 
 pub struct Tree {
     pub epoch: u64,
     pub id: u64,
     pub max_pivots: usize,
     pub max_buffer: usize,
-    pub leafs: Vec<u64>,
+    pub capacity: Capacity,
+    /// Percentage of capacity below which a node is merged
+    /// with or borrows from a sibling.
+    pub low_water: usize,
+    /// Orders the keys. It must not change once keys have been written.
+    pub comparator: Arc<Comparator>,
+    /// Decides when expiring values become absent.
+    pub clock: Arc<Clock>,
+    /// Keeps earlier versions of each key in the leaves, tagged with the
+    /// epoch of the commit that wrote them, when set.
+    pub versions: Option<Retention>,
+    /// Keeps a filter of each leaf in its parent when set, so that
+    /// lookups of absent keys and prefix scans can skip the leaf.
+    pub filters: Option<FilterPolicy>,
+    /// Writes assigned values over its threshold, expiring or not, as
+    /// blobs outside the nodes when set. Merges are kept inline, even
+    /// when they grow a value past the threshold, since the value they
+    /// extend is only read when they reach its leaf. Trees that keep
+    /// versions keep values inline.
+    pub blobs: Option<BlobPolicy>,
+    pub flush: Box<FlushPolicy>,
+    /// Number of threads that flush children in parallel when the
+    /// flush policy selects several of them. One flushes inline.
+    pub flush_workers: usize,
+    pub stats: FlushStats,
+    /// Leaves full buffers for `Tree::flush` instead of flushing them
+    /// during writes, as done when a background flusher is running.
+    pub defer_flush: bool,
+    /// Bytes buffered at the root after the last transaction.
+    pub backlog: usize,
+    /// Whether the root buffer was full after the last transaction.
+    pub backlog_full: bool,
+    pub root: Option<u64>,
     pub mode: Mode,
     pub txn: bool,
+    shared_ids: Option<Arc<AtomicU64>>,
+    /// Chunks of the blobs dropped by the open transaction.
+    retired: Vec<u64>,
+    /// The last id handed out before the open transaction began.
+    txn_start: u64,
+    /// Nodes and blob chunks whose delete failed after the transaction
+    /// that replaced them was synced. Deleting them is retried when the
+    /// next transaction closes.
+    undeleted: Vec<u64>,
 }
 
 impl Tree {
@@ -22,25 +210,110 @@ impl Tree {
             id: 0,
             max_pivots: max_pivots,
             max_buffer: max_buffer,
-            leafs: vec![],
+            capacity: Capacity::Count,
+            low_water: 25,
+            comparator: Arc::new(Bytewise),
+            clock: Arc::new(SystemClock),
+            versions: None,
+            filters: None,
+            blobs: None,
+            flush: Box::new(LargestRun),
+            flush_workers: 1,
+            stats: FlushStats::default(),
+            defer_flush: false,
+            backlog: 0,
+            backlog_full: false,
+            root: None,
             mode: mode,
             txn: false,
+            shared_ids: None,
+            retired: vec![],
+            txn_start: 0,
+            undeleted: vec![],
         }
     }
 
-    pub fn scan<F>(&self, store: &Store, scanner: F)
-    where
-        F: Fn(&[u8], &[u8]),
-    {
-        for id in &self.leafs {
-            let node = store.read(*id).unwrap();
-            let leaf = node.body.leaf();
-            for i in 0..leaf.keys.len() {
-                scanner(leaf.keys[i].bytes(), leaf.vals[i].bytes());
+    /// Reopens a tree from its manifest. Fails if `comparator` is not
+    /// the one the tree was written with.
+    pub fn open(
+        manifest: &Manifest,
+        comparator: Arc<Comparator>,
+        max_pivots: usize,
+        max_buffer: usize,
+        mode: Mode,
+    ) -> Result<Tree, ErrorType> {
+        if comparator.name() != manifest.comparator {
+            return Err(ErrorType::Msg(format!(
+                "tree was written with comparator {:?}, not {:?}",
+                manifest.comparator,
+                comparator.name()
+            )));
+        }
+        let mut tree = Tree::new(max_pivots, max_buffer, mode);
+        tree.epoch = manifest.epoch;
+        tree.id = manifest.id;
+        tree.root = manifest.root;
+        tree.comparator = comparator;
+        Ok(tree)
+    }
+
+    /// The policy for the filters kept for leaves. None are kept for a
+    /// comparator that is not exact.
+    pub fn filter_policy(&self) -> Option<FilterPolicy> {
+        if self.comparator.exact() {
+            self.filters
+        } else {
+            None
+        }
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        Manifest {
+            comparator: self.comparator.name().to_string(),
+            epoch: self.epoch,
+            id: self.last_id(),
+            root: self.root,
+        }
+    }
+
+    /// Takes node ids from `ids` instead of the tree's own counter,
+    /// so that several trees can write to the same store.
+    pub fn share_ids(&mut self, ids: Arc<AtomicU64>) {
+        self.shared_ids = Some(ids);
+    }
+
+    /// The counter node ids are taken from. A tree with its own counter
+    /// switches to a shared one, so that trees forked from it can hand
+    /// out ids at the same time.
+    pub fn id_counter(&mut self) -> Arc<AtomicU64> {
+        match self.shared_ids {
+            Some(ref ids) => ids.clone(),
+            None => {
+                let ids = Arc::new(AtomicU64::new(self.id));
+                self.shared_ids = Some(ids.clone());
+                ids
             }
         }
     }
 
+    /// The last node id handed out by `next_id`.
+    pub fn last_id(&self) -> u64 {
+        match self.shared_ids {
+            Some(ref ids) => ids.load(atomic::Ordering::SeqCst),
+            None => self.id,
+        }
+    }
+
+    /// Calls `scanner` on every entry of the tree in key order, with the
+    /// buffered messages folded in. Expired values are skipped and blobs
+    /// are read back in full, one entry at a time.
+    pub fn scan<'a, F>(&self, store: &Store<'a>, mut scanner: F) -> Result<(), ErrorType>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        self.scan_each(store, b"", |key, val| scanner(&key, &val))
+    }
+
     pub fn begin_txn(&mut self) -> Result<Transaction, ErrorType> {
         if self.txn {
             Result::Err(ErrorType::Msg(format!(
@@ -50,20 +323,32 @@ impl Tree {
         } else {
             self.epoch += 1;
             self.txn = true;
-            Ok(Transaction {
-                epoch: self.epoch,
-                delete: vec![],
-            })
+            self.txn_start = self.last_id();
+            Ok(Transaction::new(self.epoch, self.comparator.clone()))
         }
     }
 
+    // Once the sync succeeds the transaction is committed, and a failed
+    // delete only leaves the old node or chunk behind for a later retry.
     fn close_txn(&mut self, store: &mut Store, txn: Transaction) -> io::Result<()> {
-        for id in txn.delete {
-            store.schedule_delete(id)?;
+        store.sync()?;
+        let retired = mem::take(&mut self.retired);
+        let undeleted = mem::take(&mut self.undeleted);
+        for id in txn.delete.into_iter().chain(retired).chain(undeleted) {
+            match store.schedule_delete(id) {
+                Err(ref err) if err.kind() != io::ErrorKind::NotFound => self.undeleted.push(id),
+                _ => {}
+            }
         }
         Ok(())
     }
 
+    /// Nodes and blob chunks left in the store because deleting them
+    /// failed. They are deleted again when the next transaction closes.
+    pub fn undeleted(&self) -> &[u64] {
+        &self.undeleted
+    }
+
     pub fn end_txn(&mut self, store: &mut Store, txn: Transaction) -> Result<(),ErrorType> {
         if !self.txn {
             return Err(ErrorType::Msg(
@@ -77,11 +362,1933 @@ impl Tree {
                 txn.epoch
             )));
         }
+        self.txn = false;
         self.close_txn(store, txn).map_err(ErrorType::IO)
     }
 
+    /// Closes a transaction without deleting the nodes and blobs it
+    /// replaced. The nodes and blob chunks it wrote are deleted, as no
+    /// root refers to them.
+    pub fn abort_txn(&mut self, store: &mut Store, txn: Transaction) -> Result<(), ErrorType> {
+        if !self.txn || self.epoch != txn.epoch {
+            return Err(ErrorType::Msg(format!(
+                "transaction {} is not open",
+                txn.epoch
+            )));
+        }
+        self.txn = false;
+        self.retired.clear();
+        self.discard_since(store, self.txn_start);
+        Ok(())
+    }
+
+    // deletes whatever was written under the ids handed out after
+    // `since`; some of them may never have been written
+    fn discard_since(&self, store: &mut Store, since: u64) {
+        for id in (since + 1)..=self.last_id() {
+            let _ = store.schedule_delete(id);
+        }
+    }
+
+    /// Deletes the chunks of a blob that is no longer referenced
+    /// when the open transaction closes, along with the nodes it
+    /// replaced, so that earlier roots stay readable until then.
+    pub fn retire(&mut self, blob: BlobRef) {
+        self.retired.extend(blob.ids());
+    }
+
+    /// Removes and returns the chunks retired in a flush worker.
+    pub fn take_retired(&mut self) -> Vec<u64> {
+        mem::take(&mut self.retired)
+    }
+
+    /// Applies every message of the batch in one transaction. The new
+    /// root becomes visible only if the whole batch has been written.
+    pub fn write<'a>(&mut self, store: &mut Store<'a>, batch: WriteBatch) -> Result<(), ErrorType> {
+        let mut txn = self.transaction();
+        txn.writes = batch;
+        self.commit(store, txn)
+    }
+
+    /// Starts an optimistic transaction. Its reads and writes are
+    /// checked and applied when it is passed to `commit`.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.epoch, self.comparator.clone())
+    }
+
+    /// Applies the writes of an optimistic transaction if no key in its
+    /// read set has changed since it was read, and fails with
+    /// `ErrorType::Conflict` otherwise.
+    pub fn commit<'a>(&mut self, store: &mut Store<'a>, txn: Transaction) -> Result<(), ErrorType> {
+        let mut open = self.begin_txn()?;
+        for (key, seen) in &txn.reads {
+            let current = match self.get(store, key) {
+                Ok(val) => val,
+                Err(err) => {
+                    self.abort_txn(store, open)?;
+                    return Err(err);
+                }
+            };
+            if current != *seen {
+                self.abort_txn(store, open)?;
+                return Err(ErrorType::Conflict(key.clone()));
+            }
+        }
+        let mut msgs = txn.writes.msgs;
+        if self.versions.is_some() {
+            for msg in &mut msgs {
+                msg.epoch = open.epoch;
+            }
+        } else if let Some(policy) = self.blobs {
+            let written = msgs
+                .iter_mut()
+                .filter(|msg| msg.op == Operation::Assign || msg.op == Operation::AssignExpiring)
+                .filter(|msg| msg.value().len() > policy.threshold)
+                .try_for_each(|msg| self.write_blob(store, policy, msg));
+            if let Err(err) = written {
+                self.abort_txn(store, open)?;
+                return Err(ErrorType::IO(err));
+            }
+        }
+        let applied = self.apply(store, &mut open, |root, tree, store, txn| {
+            if msgs.is_empty() {
+                Ok(None)
+            } else {
+                root.upsert_msgs(tree, store, txn, msgs)
+            }
+        });
+        self.install(store, open, applied)
+    }
+
+    // writes the value of an assignment in chunks and makes the
+    // message assign a reference to them instead, keeping its expiry
+    fn write_blob<'a>(&mut self, store: &mut Store<'a>, policy: BlobPolicy, msg: &mut Message) -> io::Result<()> {
+        let blob = self.store_blob(store, policy, msg.value())?;
+        if msg.op == Operation::AssignExpiring {
+            msg.op = Operation::AssignExpiringBlob;
+            msg.data = with_expiry(msg.expires(), &blob.encode());
+        } else {
+            msg.op = Operation::AssignBlob;
+            msg.data = blob.encode();
+        }
+        Ok(())
+    }
+
+    // writes `data` in chunks under new ids
+    fn store_blob<'a>(&mut self, store: &mut Store<'a>, policy: BlobPolicy, data: &[u8]) -> io::Result<BlobRef> {
+        let chunk = policy.chunk.max(1);
+        let count = data.len().div_ceil(chunk) as u64;
+        let blob = BlobRef {
+            id: self.next_ids(count),
+            len: data.len() as u64,
+            chunk: chunk as u32,
+        };
+        for (id, data) in blob.ids().zip(data.chunks(chunk)) {
+            store.write_blob(id, data)?;
+        }
+        Ok(blob)
+    }
+
+    /// Flushes the buffer of the root once, cascading into lower levels
+    /// as needed, and commits the result. Returns false if there was
+    /// nothing buffered at the root.
+    pub fn flush<'a>(&mut self, store: &mut Store<'a>) -> Result<bool, ErrorType> {
+        let mut open = self.begin_txn()?;
+        if self.backlog == 0 {
+            self.abort_txn(store, open)?;
+            return Ok(false);
+        }
+        let defer = self.defer_flush;
+        self.defer_flush = false;
+        let applied = self.apply(store, &mut open, |root, tree, store, txn| root.flush(tree, store, txn));
+        self.defer_flush = defer;
+        self.install(store, open, applied).map(|_| true)
+    }
+
+    /// Pushes every buffered message with a key in `range` down to the
+    /// leaves, applying deletes and rewriting the affected leaves, and
+    /// merges away nodes left underfull. Returns the number of bytes
+    /// freed: those of the nodes and blobs the compaction replaced, less
+    /// those of the nodes it wrote.
+    pub fn compact<'a, 'k, R>(&mut self, store: &mut Store<'a>, range: R) -> Result<u64, ErrorType>
+    where
+        R: RangeBounds<&'k [u8]>,
+    {
+        if self.root.is_none() {
+            return Ok(0);
+        }
+        let bounds = (range.start_bound().map(|key| *key), range.end_bound().map(|key| *key));
+        let mut open = self.begin_txn()?;
+        let defer = self.defer_flush;
+        self.defer_flush = true;
+        let mut tally = Tally {
+            store: store,
+            written: HashMap::new(),
+            freed: 0,
+        };
+        let applied = self
+            .apply(&mut tally, &mut open, |root, tree, store, txn| root.compact(tree, store, txn, &bounds))
+            .and_then(|root| Ok((root, self.freed(&tally, &open)?)));
+        self.defer_flush = defer;
+        let freed = applied.as_ref().map_or(0, |&(_, freed)| freed);
+        self.install(tally.store, open, applied.map(|(root, _)| root))?;
+        Ok(freed)
+    }
+
+    // bytes of the nodes and blob chunks that the open transaction
+    // replaced, less those of the nodes it wrote
+    fn freed(&self, tally: &Tally, txn: &Transaction) -> io::Result<u64> {
+        let replaced: HashSet<u64> = txn.delete.iter().cloned().collect();
+        let mut freed = tally.freed;
+        for id in &replaced {
+            if !tally.written.contains_key(id) {
+                freed += tally.store.read_shared(*id)?.size() as u64;
+            }
+        }
+        for id in &self.retired {
+            freed += tally.store.read_blob(*id)?.len() as u64;
+        }
+        let written: u64 = tally
+            .written
+            .iter()
+            .filter(|&(id, _)| !replaced.contains(id))
+            .map(|(_, &size)| size as u64)
+            .sum();
+        Ok(freed.saturating_sub(written))
+    }
+
+    /// Makes the root produced by a transaction visible,
+    /// or aborts the transaction if it could not be written.
+    fn install<'a>(
+        &mut self,
+        store: &mut Store<'a>,
+        txn: Transaction,
+        applied: io::Result<u64>,
+    ) -> Result<(), ErrorType> {
+        match applied {
+            Ok(root) => {
+                let prev = self.root;
+                self.root = Some(root);
+                let result = self.end_txn(store, txn);
+                if result.is_err() {
+                    self.root = prev;
+                    self.discard_since(store, self.txn_start);
+                }
+                result
+            }
+            Err(err) => {
+                self.abort_txn(store, txn)?;
+                Err(ErrorType::IO(err))
+            }
+        }
+    }
+
+    fn apply<'a, F>(&mut self, store: &mut Store<'a>, txn: &mut Transaction, update: F) -> io::Result<u64>
+    where
+        F: FnOnce(&mut Node<'a>, &mut Tree, &mut Store<'a>, &mut Transaction) -> io::Result<Option<NewChild>>,
+    {
+        let mut root = match self.root {
+            Some(id) => store.read(id)?,
+            None => Node {
+                header: Header {
+                    id: self.next_id(),
+                    epoch: self.epoch,
+                },
+                body: Body::Leaf(Leaf::new(vec![], vec![])),
+            },
+        };
+        let newchild = update(&mut root, self, store, txn)?;
+        root.copy_on_write(self, txn);
+        store.write(&root)?;
+        if let Some(newchild) = newchild {
+            let level = root.body.level() + 1;
+            let keys = vec![Buf::Owned(newchild.key)];
+            let children = vec![root.id(), newchild.id];
+            let mut body = Internal::new(&*self.comparator, level, keys, vec![], children);
+            body.filter_child(self, &root);
+            body.add_filter(newchild.id, newchild.filter);
+            self.backlog = 0;
+            self.backlog_full = false;
+            return self.write_node(store, Body::Internal(body));
+        }
+        let root = root.collapse(self, store, txn)?;
+        match root.body {
+            Body::Internal(ref node) => {
+                self.backlog = node.buffer_size();
+                self.backlog_full = node.buffer_full(self);
+            }
+            Body::Leaf(_) => {
+                self.backlog = 0;
+                self.backlog_full = false;
+            }
+        }
+        Ok(root.id())
+    }
+
+    /// Reads the committed value of a key, applying
+    /// the messages that are still buffered above its leaf.
+    pub fn get<'a>(&self, store: &Store<'a>, key: &[u8]) -> Result<Option<Vec<u8>>, ErrorType> {
+        match self.root {
+            Some(root) => Tree::resolve(&*self.comparator, store, root, key, self.clock.now()),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens the committed value of a key for reading in pieces, so that
+    /// a value kept as a blob is read one chunk at a time.
+    pub fn value_reader<'s, 'a>(
+        &self,
+        store: &'s Store<'a>,
+        key: &[u8],
+    ) -> Result<Option<ValueReader<'s, 'a>>, ErrorType> {
+        let stored = match self.root {
+            Some(root) => Tree::resolve_stored(&*self.comparator, store, root, key, self.clock.now())?,
+            None => None,
+        };
+        match stored {
+            Some(stored) => ValueReader::new(store, stored).map(Some).map_err(ErrorType::IO),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the value of a key in the tree under `root`, treating
+    /// values that expired at or before `now` as absent.
+    pub fn resolve<'a>(
+        cmp: &Comparator,
+        store: &Store<'a>,
+        root: u64,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<Vec<u8>>, ErrorType> {
+        match Tree::resolve_stored(cmp, store, root, key, now)? {
+            Some(stored) => load(store, stored).map(Some).map_err(ErrorType::IO),
+            None => Ok(None),
+        }
+    }
+
+    // like `resolve`, without reading the blob of the value
+    fn resolve_stored<'a>(
+        cmp: &Comparator,
+        store: &Store<'a>,
+        root: u64,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<Stored>, ErrorType> {
+        let mut levels = vec![];
+        let mut node = store.read_shared(root).map_err(ErrorType::IO)?;
+        let mut val = loop {
+            let child = match node.body {
+                Body::Leaf(ref leaf) => break leaf.get_stored(cmp, key),
+                Body::Internal(ref internal) => {
+                    levels.push(internal.buffered(cmp, key).to_vec());
+                    let child = internal.children[internal.route(cmp, key)];
+                    // the filter of a leaf can rule the key out without reading it
+                    if !internal.may_contain(child, key) {
+                        break None;
+                    }
+                    child
+                }
+            };
+            node = store.read_shared(child).map_err(ErrorType::IO)?;
+        };
+        // messages in lower levels are older than those above them
+        for msgs in levels.iter().rev() {
+            for msg in msgs {
+                val = msg.resolve_stored(val, now);
+            }
+        }
+        Ok(val.filter(|stored| !stored.expired(now)))
+    }
+
+    /// Reads the value `key` had after the commit in `epoch`. Only the
+    /// versions kept by `Tree::versions` can be read, and expiry is not
+    /// applied.
+    pub fn get_at<'a>(&self, store: &Store<'a>, key: &[u8], epoch: u64) -> Result<Option<Vec<u8>>, ErrorType> {
+        let versions = self.history(store, key)?;
+        Ok(versions
+            .into_iter()
+            .find(|version| version.epoch <= epoch)
+            .and_then(|version| version.val))
+    }
+
+    /// The kept versions of `key`, newest first, including deletes.
+    /// Messages buffered above the leaf are folded in by epoch.
+    pub fn history<'a>(&self, store: &Store<'a>, key: &[u8]) -> Result<Vec<Version>, ErrorType> {
+        let cmp = &*self.comparator;
+        let mut levels = vec![];
+        let mut node = match self.root {
+            Some(root) => store.read_shared(root).map_err(ErrorType::IO)?,
+            None => return Ok(vec![]),
+        };
+        // oldest first while the buffered messages are added
+        let mut versions = loop {
+            let child = match node.body {
+                Body::Leaf(ref leaf) => {
+                    let mut versions = leaf.versions(cmp, key);
+                    versions.reverse();
+                    break versions;
+                }
+                Body::Internal(ref internal) => {
+                    levels.push(internal.buffered(cmp, key).to_vec());
+                    internal.children[internal.route(cmp, key)]
+                }
+            };
+            node = store.read_shared(child).map_err(ErrorType::IO)?;
+        };
+        for msgs in levels.iter().rev() {
+            for msg in msgs {
+                let val = versions.last().and_then(|version| version.val.clone());
+                if versions.last().map(|version| version.epoch) == Some(msg.epoch) {
+                    versions.pop();
+                } else if val.is_none() && msg.resolve(None).is_none() {
+                    continue;
+                }
+                versions.push(Version {
+                    epoch: msg.epoch,
+                    val: msg.resolve(val),
+                });
+            }
+        }
+        versions.reverse();
+        if let (Some(retention), Some(newest)) = (self.versions, versions.first().map(|version| version.epoch)) {
+            let mut older = versions.split_off(1);
+            retention.prune(newest, &mut older);
+            versions.extend(older);
+        }
+        if versions.len() == 1 && versions[0].val.is_none() {
+            versions.clear();
+        }
+        Ok(versions)
+    }
+
+    /// The entries whose keys start with `prefix`, in key order. Leaves
+    /// whose prefix filter rules the prefix out are not read.
+    pub fn scan_prefix<'a>(&self, store: &Store<'a>, prefix: &[u8]) -> Result<Vec<Entry>, ErrorType> {
+        let mut entries = vec![];
+        self.scan_each(store, prefix, |key, val| entries.push((key, val)))?;
+        Ok(entries)
+    }
+
+    // passes the live entries whose keys start with `prefix` to `f` in
+    // key order, as each leaf is folded
+    fn scan_each<'a, F>(&self, store: &Store<'a>, prefix: &[u8], mut f: F) -> Result<(), ErrorType>
+    where
+        F: FnMut(Vec<u8>, Vec<u8>),
+    {
+        let root = match self.root {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        let now = self.clock.now();
+        let mut emit = |(key, val): Folded| -> io::Result<()> {
+            match val {
+                Some(stored) if !stored.expired(now) => f(key, load(store, stored)?),
+                _ => {}
+            }
+            Ok(())
+        };
+        self.visit_prefix(store, root, prefix, &[], now, &mut emit).map_err(ErrorType::IO)
+    }
+
+    // emits the entries under `id` whose keys start with `prefix`, with
+    // the messages buffered on the way folded in. `pending` holds the
+    // messages of the ancestors bound for `id`, those of the nearest first.
+    fn visit_prefix<'a>(
+        &self,
+        store: &Store<'a>,
+        id: u64,
+        prefix: &[u8],
+        pending: &[Vec<&BufMessage<'a>>],
+        now: u64,
+        emit: &mut FnMut(Folded) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let cmp = &*self.comparator;
+        let node = store.read_shared(id)?;
+        let internal = match node.body {
+            Body::Leaf(ref leaf) => {
+                let entries = (0..leaf.keys.len())
+                    .filter(|&i| !leaf.deleted(i) && leaf.keys[i].bytes().starts_with(prefix))
+                    .map(|i| (leaf.keys[i].to_vec(), Some(leaf.stored(i))))
+                    .collect();
+                return self.emit_folded(entries, pending, now, emit);
+            }
+            Body::Internal(ref internal) => internal,
+        };
+        for idx in internal.prefix_children(cmp, prefix) {
+            let child = internal.children[idx];
+            let own = internal.buffer.parts[idx]
+                .msgs
+                .iter()
+                .filter(|msg| msg.key.bytes().starts_with(prefix))
+                .collect();
+            let below: Vec<Vec<&BufMessage>> = Some(own)
+                .into_iter()
+                .chain(pending.iter().map(|msgs| {
+                    msgs.iter()
+                        .filter(|msg| internal.route(cmp, msg.key.bytes()) == idx)
+                        .cloned()
+                        .collect()
+                }))
+                .collect();
+            if internal.may_contain_prefix(child, prefix) {
+                self.visit_prefix(store, child, prefix, &below, now, emit)?;
+            } else {
+                self.emit_folded(vec![], &below, now, emit)?;
+            }
+        }
+        Ok(())
+    }
+
+    // folds the messages of each ancestor into `entries`, the nearest
+    // ancestor's first, and emits the result
+    fn emit_folded(
+        &self,
+        mut entries: Vec<Folded>,
+        pending: &[Vec<&BufMessage>],
+        now: u64,
+        emit: &mut FnMut(Folded) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for msgs in pending {
+            entries = fold(&*self.comparator, entries, msgs, now);
+        }
+        entries.into_iter().try_for_each(emit)
+    }
+
+    /// Assigns `val` to `key` only if the key is absent.
+    /// Returns whether the value was written.
+    pub fn insert_if_absent<'a>(
+        &mut self,
+        store: &mut Store<'a>,
+        key: &[u8],
+        val: &[u8],
+    ) -> Result<bool, ErrorType> {
+        self.compare_and_swap(store, key, None, val)
+    }
+
+    /// Assigns `val` to `key` only if its current value equals `expected`,
+    /// where `None` expects the key to be absent. The current value is
+    /// resolved through the buffered messages before the write is made.
+    /// Returns whether the value was written.
+    pub fn compare_and_swap<'a>(
+        &mut self,
+        store: &mut Store<'a>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        val: &[u8],
+    ) -> Result<bool, ErrorType> {
+        if self.txn {
+            return Err(ErrorType::Msg(format!(
+                "previous transaction {} must be closed",
+                self.epoch
+            )));
+        }
+        let current = self.get(store, key)?;
+        if current.as_ref().map(|val| &val[..]) != expected {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::new();
+        batch.assign(key, val);
+        self.write(store, batch)?;
+        Ok(true)
+    }
+
+    /// Builds an empty tree from key/value pairs in ascending key order.
+    /// Nodes are filled to `fill` percent of their capacity and written
+    /// level by level, without going through the message buffers. Values
+    /// over the threshold of `Tree::blobs` are written as blobs.
+    pub fn bulk_load<'a, I>(
+        &mut self,
+        store: &mut Store<'a>,
+        fill: usize,
+        input: I,
+    ) -> Result<(), ErrorType>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        if self.root.is_some() {
+            return Err(ErrorType::Msg(
+                "bulk load requires an empty tree".to_string(),
+            ));
+        }
+        if fill == 0 || fill > 100 {
+            return Err(ErrorType::Msg(format!(
+                "fill factor {} must be between 1 and 100",
+                fill
+            )));
+        }
+        let start = self.last_id();
+        let result = self.build(store, fill, input);
+        if result.is_err() {
+            // remove the nodes and blobs written before the failure
+            self.discard_since(store, start);
+        }
+        result
+    }
+
+    fn build<'a, I>(
+        &mut self,
+        store: &mut Store<'a>,
+        fill: usize,
+        input: I,
+    ) -> Result<(), ErrorType>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut level = vec![];
+        let mut filters = BTreeMap::new();
+        // the pivot before the leaf being filled
+        let mut pivot = None;
+        let mut leaf = Leaf::new(vec![], vec![]);
+        let mut prev: Option<Vec<u8>> = None;
+        for (key, mut val) in input {
+            if let Some(ref prev) = prev {
+                if self.comparator.compare(&key, prev) != Ordering::Greater {
+                    return Err(ErrorType::Msg(format!(
+                        "bulk load keys are not sorted: {:?} follows {:?}",
+                        key,
+                        prev
+                    )));
+                }
+            }
+            let blob = match self.blobs {
+                // trees that keep versions keep values inline, as in commit
+                Some(policy) if self.versions.is_none() && val.len() > policy.threshold => {
+                    val = self.store_blob(store, policy, &val).map_err(ErrorType::IO)?.encode();
+                    true
+                }
+                _ => false,
+            };
+            if !leaf.keys.is_empty() && !self.leaf_fits(&leaf, key.len() + val.len(), fill) {
+                let full = mem::replace(&mut leaf, Leaf::new(vec![], vec![]));
+                let filter = self.filter_policy().map(|policy| policy.build(&full));
+                let next = separator(&*self.comparator, full.keys[full.keys.len() - 1].bytes(), &key);
+                let (first, id) = self.write_leaf(store, full).map_err(ErrorType::IO)?;
+                filters.insert(id, filter);
+                level.push((pivot.replace(next).unwrap_or(first), id));
+            }
+            leaf.keys.push(Buf::Owned(key.clone()));
+            leaf.vals.push(Buf::Owned(val));
+            leaf.expires.push(0);
+            leaf.blobs.push(blob);
+            prev = Some(key);
+        }
+        if !leaf.keys.is_empty() || level.is_empty() {
+            let filter = self.filter_policy().map(|policy| policy.build(&leaf));
+            let (first, id) = self.write_leaf(store, leaf).map_err(ErrorType::IO)?;
+            filters.insert(id, filter);
+            level.push((pivot.unwrap_or(first), id));
+        }
+        let mut height = 1;
+        while level.len() > 1 {
+            let mut next = vec![];
+            for group in self.group_children(&level, fill) {
+                let keys = group[1..].iter().map(|c| Buf::Owned(c.0.clone())).collect();
+                let children: Vec<u64> = group.iter().map(|c| c.1).collect();
+                let mut node = Internal::new(&*self.comparator, height, keys, vec![], children.clone());
+                for id in children {
+                    node.add_filter(id, filters.remove(&id).and_then(|filter| filter));
+                }
+                let id = self.write_node(store, Body::Internal(node))
+                    .map_err(ErrorType::IO)?;
+                next.push((group[0].0.clone(), id));
+            }
+            level = next;
+            height += 1;
+        }
+        store.sync().map_err(ErrorType::IO)?;
+        self.root = Some(level[0].1);
+        Ok(())
+    }
+
+    fn leaf_fits(&self, leaf: &Leaf, entry: usize, fill: usize) -> bool {
+        match self.capacity {
+            Capacity::Count => {
+                let limit = (self.max_pivots + self.max_buffer - 1) * fill / 100;
+                leaf.keys.len() < limit.max(1)
+            }
+            Capacity::Bytes { leaf: limit, .. } => {
+                leaf.size() + 3 * size_of::<u64>() + entry <= limit * fill / 100
+            }
+        }
+    }
+
+    /// Groups the nodes of one level under the parents of the next level.
+    fn group_children<'b>(
+        &self,
+        level: &'b [(Vec<u8>, u64)],
+        fill: usize,
+    ) -> Vec<&'b [(Vec<u8>, u64)]> {
+        let mut groups = vec![];
+        let mut start = 0;
+        let mut bytes = size_of::<u32>() + 5 * size_of::<u64>();
+        for i in 0..level.len() {
+            let count = i - start;
+            let fits = match self.capacity {
+                Capacity::Count => count < (self.max_pivots * fill / 100).max(2),
+                Capacity::Bytes { pivots, .. } => {
+                    let entry = 3 * size_of::<u64>() + level[i].0.len();
+                    count < 2 || bytes + entry <= pivots * fill / 100
+                }
+            };
+            if !fits {
+                groups.push(&level[start..i]);
+                start = i;
+                bytes = size_of::<u32>() + 5 * size_of::<u64>();
+            }
+            bytes += 3 * size_of::<u64>() + level[i].0.len();
+        }
+        let last = &level[start..];
+        // avoid a parent with a single child at the end of the level
+        if last.len() == 1 && !groups.is_empty() {
+            let prev = groups.pop().unwrap();
+            let prev_start = start - prev.len();
+            if prev.len() > 2 {
+                groups.push(&level[prev_start..start - 1]);
+                groups.push(&level[start - 1..]);
+            } else {
+                groups.push(&level[prev_start..]);
+            }
+        } else {
+            groups.push(last);
+        }
+        groups
+    }
+
+    fn write_leaf<'a>(
+        &mut self,
+        store: &mut Store<'a>,
+        leaf: Leaf<'a>,
+    ) -> io::Result<(Vec<u8>, u64)> {
+        let first = match leaf.keys.first() {
+            Some(key) => key.to_vec(),
+            None => vec![],
+        };
+        let id = self.write_node(store, Body::Leaf(leaf))?;
+        Ok((first, id))
+    }
+
+    fn write_node<'a>(&mut self, store: &mut Store<'a>, body: Body<'a>) -> io::Result<u64> {
+        let id = self.next_id();
+        let node = Node {
+            header: Header {
+                id: id,
+                epoch: self.epoch,
+            },
+            body: body,
+        };
+        store.write(&node)?;
+        Ok(id)
+    }
+
     pub fn next_id(&mut self) -> u64 {
-        self.id += 1;
-        self.id
+        self.next_ids(1)
+    }
+
+    /// Hands out `count` consecutive ids and returns the first.
+    pub fn next_ids(&mut self, count: u64) -> u64 {
+        match self.shared_ids {
+            Some(ref ids) => ids.fetch_add(count, atomic::Ordering::SeqCst) + 1,
+            None => {
+                self.id += count;
+                self.id - count + 1
+            }
+        }
+    }
+
+    /// A tree with the same settings for a flush worker. Node ids are
+    /// taken from `ids`, which the parent tree reads back once the
+    /// workers are done.
+    pub fn fork(&self, ids: Arc<AtomicU64>) -> Tree {
+        Tree {
+            epoch: self.epoch,
+            id: 0,
+            max_pivots: self.max_pivots,
+            max_buffer: self.max_buffer,
+            capacity: self.capacity,
+            low_water: self.low_water,
+            comparator: self.comparator.clone(),
+            clock: self.clock.clone(),
+            versions: self.versions,
+            filters: self.filters,
+            blobs: self.blobs,
+            flush: self.flush.fork(),
+            flush_workers: 1,
+            stats: FlushStats::default(),
+            defer_flush: false,
+            backlog: 0,
+            backlog_full: false,
+            root: None,
+            mode: self.mode,
+            txn: self.txn,
+            shared_ids: Some(ids),
+            retired: vec![],
+            txn_start: self.txn_start,
+            undeleted: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::clock::ManualClock;
+    use index::comparator::CaseInsensitive;
+    use index::comparator::Reverse;
+    use index::compression::LevelCompression;
+    use index::filter::FilterPolicy;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    use std::cell::Cell;
+    use std::io::Read;
+
+    struct FailingStore {
+        store: NodeStore<MemStore>,
+        // nodes and blob chunks written before writes start to fail
+        writes_left: usize,
+        fail_sync: bool,
+        fail_delete: bool,
+    }
+
+    impl FailingStore {
+        fn new() -> FailingStore {
+            FailingStore {
+                store: NodeStore::new(MemStore::new(), LevelCompression::none()),
+                writes_left: usize::MAX,
+                fail_sync: false,
+                fail_delete: false,
+            }
+        }
+
+        fn spend(&mut self) -> io::Result<()> {
+            if self.writes_left == 0 {
+                return Err(io::Error::other("write failed"));
+            }
+            self.writes_left -= 1;
+            Ok(())
+        }
+    }
+
+    impl<'a> Store<'a> for FailingStore {
+        fn read(&self, id: u64) -> io::Result<Node<'a>> {
+            self.store.read(id)
+        }
+
+        fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+            self.spend()?;
+            self.store.write(node)
+        }
+
+        fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+            if self.fail_delete {
+                return Err(io::Error::other("delete failed"));
+            }
+            self.store.schedule_delete(id)
+        }
+
+        fn write_blob(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+            self.spend()?;
+            self.store.write_blob(id, data)
+        }
+
+        fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+            self.store.read_blob(id)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            if self.fail_sync {
+                return Err(io::Error::other("sync failed"));
+            }
+            Ok(())
+        }
+    }
+
+    struct CountingStore {
+        store: NodeStore<MemStore>,
+        leaf_reads: Cell<usize>,
+    }
+
+    impl<'a> Store<'a> for CountingStore {
+        fn read(&self, id: u64) -> io::Result<Node<'a>> {
+            let node = self.store.read(id)?;
+            if node.body.level() == 0 {
+                self.leaf_reads.set(self.leaf_reads.get() + 1);
+            }
+            Ok(node)
+        }
+
+        fn write(&mut self, node: &Node<'a>) -> io::Result<()> {
+            self.store.write(node)
+        }
+
+        fn schedule_delete(&mut self, id: u64) -> io::Result<()> {
+            self.store.schedule_delete(id)
+        }
+    }
+
+    fn get(tree: &Tree, store: &Store, key: &[u8]) -> Option<Vec<u8>> {
+        match tree.get(store, key) {
+            Ok(val) => val,
+            Err(_) => panic!("get failed"),
+        }
+    }
+
+    fn lookup(store: &Store, root: u64, key: &[u8]) -> Option<Vec<u8>> {
+        let mut node = store.read(root).unwrap();
+        loop {
+            let child = match node.body {
+                Body::Leaf(ref leaf) => return leaf.get(&Bytewise, key).map(|val| val.to_vec()),
+                Body::Internal(ref internal) => internal.children[internal.route(&Bytewise, key)],
+            };
+            node = store.read(child).unwrap();
+        }
+    }
+
+    // the ids of the leaves under `id`, in key order
+    fn leaves(store: &Store, id: u64) -> Vec<u64> {
+        match store.read(id).unwrap().body {
+            Body::Leaf(_) => vec![id],
+            Body::Internal(internal) => internal.children.iter().flat_map(|&child| leaves(store, child)).collect(),
+        }
+    }
+
+    fn reachable(store: &Store, id: u64) -> usize {
+        match store.read(id).unwrap().body {
+            Body::Leaf(_) => 1,
+            Body::Internal(internal) => 1 + internal.children.iter().map(|&child| reachable(store, child)).sum::<usize>(),
+        }
+    }
+
+    fn scanned(tree: &Tree, store: &Store) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut output = vec![];
+        match tree.scan(store, |key, val| output.push((key.to_vec(), val.to_vec()))) {
+            Ok(()) => output,
+            Err(_) => panic!("scan failed"),
+        }
+    }
+
+    fn pairs(count: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..count)
+            .map(|i| {
+                (format!("key{:03}", i).into_bytes(), format!("val{}", i).into_bytes())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bulk_load_sorted() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(tree.bulk_load(&mut store, 100, pairs(100)).is_ok());
+        let root = tree.root.unwrap();
+        assert_eq!(15, leaves(&store, root).len());
+        assert_eq!(2, store.read(root).unwrap().body.level());
+        for (key, val) in pairs(100) {
+            assert_eq!(Some(val), lookup(&store, root, &key));
+        }
+        assert_eq!(None, lookup(&store, root, b"key1000"));
+        assert_eq!(pairs(100), scanned(&tree, &store));
+        assert!(tree.bulk_load(&mut store, 100, pairs(1)).is_err());
+    }
+
+    #[test]
+    fn bulk_load_fill_factor() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(tree.bulk_load(&mut store, 50, pairs(100)).is_ok());
+        assert_eq!(34, leaves(&store, tree.root.unwrap()).len());
+        assert!(Tree::new(4, 4, Mode::Test).bulk_load(&mut store, 0, pairs(1)).is_err());
+
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.capacity = Capacity::Bytes {
+            leaf: 256,
+            buffer: 256,
+            pivots: 128,
+        };
+        assert!(tree.bulk_load(&mut store, 100, pairs(100)).is_ok());
+        let root = tree.root.unwrap();
+        for id in leaves(&store, root) {
+            let size = store.read(id).unwrap().body.size();
+            assert!(size <= 256);
+        }
+        for (key, val) in pairs(100) {
+            assert_eq!(Some(val), lookup(&store, root, &key));
+        }
+    }
+
+    #[test]
+    fn bulk_load_unsorted() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut input = pairs(30);
+        input.swap(20, 21);
+        assert!(tree.bulk_load(&mut store, 100, input).is_err());
+        assert!(tree.root.is_none());
+        assert!(store.blocks.blocks.is_empty());
+        let mut input = pairs(10);
+        input[4].0 = input[3].0.clone();
+        assert!(tree.bulk_load(&mut store, 100, input).is_err());
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn bulk_load_failed_sync() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = FailingStore::new();
+        store.fail_sync = true;
+        // every level has been written by the time the sync fails
+        assert!(tree.bulk_load(&mut store, 100, pairs(100)).is_err());
+        assert!(tree.root.is_none());
+        assert!(store.store.blocks.blocks.is_empty());
+    }
+
+    #[test]
+    fn failed_deletes_keep_commit() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = FailingStore::new();
+        assert!(tree.bulk_load(&mut store, 100, pairs(40)).is_ok());
+        let write = |tree: &mut Tree, store: &mut FailingStore, val: &[u8]| {
+            let mut batch = WriteBatch::new();
+            batch.assign(b"key005", val);
+            assert!(tree.write(store, batch).is_ok());
+            assert!(tree.compact(store, ..).is_ok());
+        };
+        // the commit is synced before the replaced nodes are deleted
+        store.fail_delete = true;
+        write(&mut tree, &mut store, b"new");
+        assert!(!tree.undeleted().is_empty());
+        let mut expected = pairs(40);
+        expected[5].1 = b"new".to_vec();
+        assert_eq!(expected, scanned(&tree, &store));
+        assert!(store.store.blocks.blocks.len() > reachable(&store, tree.root.unwrap()));
+
+        store.fail_delete = false;
+        write(&mut tree, &mut store, b"newer");
+        assert!(tree.undeleted().is_empty());
+        expected[5].1 = b"newer".to_vec();
+        assert_eq!(expected, scanned(&tree, &store));
+        assert_eq!(reachable(&store, tree.root.unwrap()), store.store.blocks.blocks.len());
+    }
+
+    #[test]
+    fn scan_after_writes() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(tree.bulk_load(&mut store, 100, pairs(100)).is_ok());
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key005", b"new").delete(b"key006").assign(b"key1000", b"last");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let mut expected = pairs(100);
+        expected[5].1 = b"new".to_vec();
+        expected.remove(6);
+        expected.insert(99, (b"key1000".to_vec(), b"last".to_vec()));
+        // the writes are still buffered here, and reach the leaves below
+        assert_eq!(expected, scanned(&tree, &store));
+        assert!(tree.compact(&mut store, ..).is_ok());
+        assert_eq!(expected, scanned(&tree, &store));
+    }
+
+    #[test]
+    fn write_batches() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert_eq!(None, get(&tree, &store, b"key000"));
+        for chunk in pairs(100).chunks(5) {
+            let mut batch = WriteBatch::new();
+            for pair in chunk {
+                batch.assign(&pair.0, &pair.1);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+            assert!(!tree.txn);
+        }
+        assert!(store.read(tree.root.unwrap()).unwrap().body.level() > 0);
+        for (key, val) in pairs(100) {
+            assert_eq!(Some(val), get(&tree, &store, &key));
+        }
+        let mut batch = WriteBatch::new();
+        batch
+            .delete(b"key010")
+            .merge(b"key020", b"+")
+            .merge(b"key020", b"+")
+            .merge(b"new", b"x")
+            .assign(b"key030", b"a")
+            .delete(b"key030");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(None, get(&tree, &store, b"key010"));
+        assert_eq!(Some(b"val20++".to_vec()), get(&tree, &store, b"key020"));
+        assert_eq!(Some(b"x".to_vec()), get(&tree, &store, b"new"));
+        assert_eq!(None, get(&tree, &store, b"key030"));
+        assert_eq!(Some(b"val31".to_vec()), get(&tree, &store, b"key031"));
+    }
+
+    #[test]
+    fn failed_batch() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = FailingStore::new();
+        let mut batch = WriteBatch::new();
+        batch.assign(b"foo", b"1");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let root = tree.root;
+        store.writes_left = 0;
+        let mut batch = WriteBatch::new();
+        batch.assign(b"foo", b"2").assign(b"bar", b"2");
+        assert!(tree.write(&mut store, batch).is_err());
+        assert_eq!(root, tree.root);
+        assert!(!tree.txn);
+        assert_eq!(Some(b"1".to_vec()), get(&tree, &store, b"foo"));
+        assert_eq!(None, get(&tree, &store, b"bar"));
+        store.writes_left = usize::MAX;
+        let mut batch = WriteBatch::new();
+        batch.assign(b"bar", b"3");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(Some(b"3".to_vec()), get(&tree, &store, b"bar"));
+    }
+
+    #[test]
+    fn aborted_writes_are_deleted() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = FailingStore::new();
+        let mut batch = WriteBatch::new();
+        batch.assign(b"foo", b"1");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let written = |store: &FailingStore| {
+            let mut ids: Vec<u64> = store.store.blocks.blocks.keys().cloned().collect();
+            ids.sort();
+            ids
+        };
+        let batch = || {
+            let mut batch = WriteBatch::new();
+            for (key, val) in pairs(10) {
+                batch.assign(&key, &val);
+            }
+            batch
+        };
+        let ids = written(&store);
+        // the root splits, and the write of its last half fails
+        store.writes_left = 2;
+        assert!(tree.write(&mut store, batch()).is_err());
+        assert_eq!(ids, written(&store));
+        // every node is written, but the sync fails
+        store.writes_left = usize::MAX;
+        store.fail_sync = true;
+        assert!(tree.write(&mut store, batch()).is_err());
+        assert_eq!(ids, written(&store));
+        store.fail_sync = false;
+        assert!(tree.write(&mut store, batch()).is_ok());
+        assert_eq!(Some(b"1".to_vec()), get(&tree, &store, b"foo"));
+        assert_eq!(Some(b"val9".to_vec()), get(&tree, &store, b"key009"));
+    }
+
+    fn cas(tree: &mut Tree, store: &mut Store, key: &[u8], expected: Option<&[u8]>, val: &[u8]) -> bool {
+        match tree.compare_and_swap(store, key, expected, val) {
+            Ok(swapped) => swapped,
+            Err(_) => panic!("compare and swap failed"),
+        }
+    }
+
+    #[test]
+    fn conditional_writes() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(tree.insert_if_absent(&mut store, b"job", b"a").unwrap_or(false));
+        assert!(!tree.insert_if_absent(&mut store, b"job", b"b").unwrap_or(true));
+        assert_eq!(Some(b"a".to_vec()), get(&tree, &store, b"job"));
+        assert!(!cas(&mut tree, &mut store, b"job", Some(b"b"), b"c"));
+        assert!(cas(&mut tree, &mut store, b"job", Some(b"a"), b"c"));
+        assert_eq!(Some(b"c".to_vec()), get(&tree, &store, b"job"));
+        assert!(!cas(&mut tree, &mut store, b"none", Some(b""), b"x"));
+        assert_eq!(None, get(&tree, &store, b"none"));
+    }
+
+    #[test]
+    fn conditional_writes_through_buffers() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for (key, val) in pairs(50) {
+            assert!(cas(&mut tree, &mut store, &key, None, &val));
+        }
+        let root = store.read(tree.root.unwrap()).unwrap();
+        assert!(root.body.level() > 0);
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key010").merge(b"key020", b"+");
+        assert!(tree.write(&mut store, batch).is_ok());
+        for (key, val) in pairs(50) {
+            assert!(!cas(&mut tree, &mut store, &key, Some(b"x"), b"x"));
+            if key != b"key010" && key != b"key020" {
+                assert!(!cas(&mut tree, &mut store, &key, None, b"x"));
+                assert!(cas(&mut tree, &mut store, &key, Some(&val), b"y"));
+            }
+        }
+        assert!(!cas(&mut tree, &mut store, b"key020", Some(b"val20"), b"y"));
+        assert!(cas(&mut tree, &mut store, b"key020", Some(b"val20+"), b"y"));
+        assert!(!cas(&mut tree, &mut store, b"key010", Some(b"val10"), b"y"));
+        assert!(tree.insert_if_absent(&mut store, b"key010", b"y").unwrap_or(false));
+        for (key, _) in pairs(50) {
+            assert_eq!(Some(b"y".to_vec()), get(&tree, &store, &key));
+        }
+    }
+
+    fn txn_get(txn: &mut Transaction, tree: &Tree, store: &Store, key: &[u8]) -> Option<Vec<u8>> {
+        match txn.get(tree, store, key) {
+            Ok(val) => val,
+            Err(_) => panic!("get failed"),
+        }
+    }
+
+    #[test]
+    fn read_own_writes() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut batch = WriteBatch::new();
+        batch.assign(b"a", b"1").assign(b"b", b"2");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let mut txn = tree.transaction();
+        assert_eq!(Some(b"1".to_vec()), txn_get(&mut txn, &tree, &store, b"a"));
+        txn.merge(b"a", b"+").delete(b"b").assign(b"c", b"3");
+        assert_eq!(Some(b"1+".to_vec()), txn_get(&mut txn, &tree, &store, b"a"));
+        assert_eq!(None, txn_get(&mut txn, &tree, &store, b"b"));
+        assert_eq!(Some(b"3".to_vec()), txn_get(&mut txn, &tree, &store, b"c"));
+        assert_eq!(3, txn.reads.len());
+        assert_eq!(Some(b"2".to_vec()), get(&tree, &store, b"b"));
+        assert!(tree.commit(&mut store, txn).is_ok());
+        assert_eq!(Some(b"1+".to_vec()), get(&tree, &store, b"a"));
+        assert_eq!(None, get(&tree, &store, b"b"));
+        assert_eq!(Some(b"3".to_vec()), get(&tree, &store, b"c"));
+    }
+
+    #[test]
+    fn conflicting_transactions() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut batch = WriteBatch::new();
+        batch.assign(b"x", b"1").assign(b"y", b"1");
+        assert!(tree.write(&mut store, batch).is_ok());
+
+        let mut first = tree.transaction();
+        let mut second = tree.transaction();
+        let mut third = tree.transaction();
+        assert_eq!(Some(b"1".to_vec()), txn_get(&mut first, &tree, &store, b"x"));
+        first.assign(b"y", b"2");
+        assert_eq!(Some(b"1".to_vec()), txn_get(&mut second, &tree, &store, b"y"));
+        second.assign(b"x", b"2");
+        assert_eq!(None, txn_get(&mut third, &tree, &store, b"z"));
+        third.assign(b"x", b"3");
+
+        assert!(tree.commit(&mut store, first).is_ok());
+        match tree.commit(&mut store, second) {
+            Err(ErrorType::Conflict(key)) => assert_eq!(b"y".to_vec(), key),
+            _ => panic!("expected a conflict"),
+        }
+        assert!(!tree.txn);
+        assert!(tree.commit(&mut store, third).is_ok());
+        assert_eq!(Some(b"3".to_vec()), get(&tree, &store, b"x"));
+        assert_eq!(Some(b"2".to_vec()), get(&tree, &store, b"y"));
+
+        let mut txn = tree.transaction();
+        assert_eq!(None, txn_get(&mut txn, &tree, &store, b"z"));
+        txn.assign(b"z", b"1");
+        assert!(tree.insert_if_absent(&mut store, b"z", b"0").unwrap_or(false));
+        assert_eq!(Some(b"1".to_vec()), txn_get(&mut txn, &tree, &store, b"z"));
+        assert!(tree.commit(&mut store, txn).is_err());
+        assert_eq!(Some(b"0".to_vec()), get(&tree, &store, b"z"));
+    }
+
+    #[test]
+    fn transaction_keys_follow_comparator() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.comparator = Arc::new(CaseInsensitive);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key", b"1");
+        assert!(tree.write(&mut store, batch).is_ok());
+
+        let mut txn = tree.transaction();
+        assert_eq!(Some(b"1".to_vec()), txn_get(&mut txn, &tree, &store, b"key"));
+        txn.merge(b"KEY", b"+");
+        assert_eq!(Some(b"1+".to_vec()), txn_get(&mut txn, &tree, &store, b"Key"));
+        assert_eq!(1, txn.reads.len());
+        let mut batch = WriteBatch::new();
+        batch.assign(b"kEy", b"2");
+        assert!(tree.write(&mut store, batch).is_ok());
+        match tree.commit(&mut store, txn) {
+            Err(ErrorType::Conflict(key)) => assert_eq!(b"key".to_vec(), key),
+            _ => panic!("expected a conflict"),
+        }
+        assert_eq!(Some(b"2".to_vec()), get(&tree, &store, b"KEY"));
+    }
+
+    #[test]
+    fn deferred_flush() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for chunk in pairs(40).chunks(4) {
+            let mut batch = WriteBatch::new();
+            for pair in chunk {
+                batch.assign(&pair.0, &pair.1);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        tree.defer_flush = true;
+        let flushes = tree.stats.flushes;
+        for (key, _) in pairs(40) {
+            let mut batch = WriteBatch::new();
+            batch.merge(&key, b"+");
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        assert_eq!(flushes, tree.stats.flushes);
+        assert!(tree.backlog_full);
+        assert!(tree.backlog > 0);
+        for (key, val) in pairs(40) {
+            let mut val = val.clone();
+            val.push(b'+');
+            assert_eq!(Some(val), get(&tree, &store, &key));
+        }
+        while tree.backlog_full {
+            assert!(tree.flush(&mut store).unwrap_or(false));
+        }
+        assert!(tree.defer_flush);
+        for (key, val) in pairs(40) {
+            let mut val = val.clone();
+            val.push(b'+');
+            assert_eq!(Some(val), get(&tree, &store, &key));
+        }
+    }
+
+    fn buffered_keys(store: &Store, id: u64) -> Vec<Vec<u8>> {
+        let node = store.read(id).unwrap();
+        let mut keys = vec![];
+        if let Body::Internal(ref internal) = node.body {
+            keys.extend(internal.buffer.iter().map(|msg| msg.key.to_vec()));
+            for child in &internal.children {
+                keys.extend(buffered_keys(store, *child));
+            }
+        }
+        keys
+    }
+
+    fn pivots(store: &Store, id: u64) -> Vec<Vec<u8>> {
+        let node = store.read(id).unwrap();
+        let mut keys = vec![];
+        if let Body::Internal(ref internal) = node.body {
+            keys.extend(internal.keys.iter().map(|key| key.to_vec()));
+            for child in &internal.children {
+                keys.extend(pivots(store, *child));
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn short_pivots() {
+        let input: Vec<(Vec<u8>, Vec<u8>)> = (0..200)
+            .map(|i| (format!("/docs/section{:03}/index.html", i).into_bytes(), vec![]))
+            .collect();
+        let check = |tree: &Tree, store: &Store| {
+            let pivots = pivots(store, tree.root.unwrap());
+            assert!(!pivots.is_empty());
+            // no pivot needs more than the section number
+            assert!(pivots.iter().all(|key| key.len() <= "/docs/section000".len()));
+            for (key, val) in &input {
+                assert_eq!(Some(val.clone()), get(tree, store, key));
+            }
+        };
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for chunk in input.chunks(10) {
+            let mut batch = WriteBatch::new();
+            for (key, val) in chunk {
+                batch.assign(key, val);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        assert!(tree.compact(&mut store, ..).is_ok());
+        check(&tree, &store);
+
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        assert!(tree.bulk_load(&mut store, 100, input.clone()).is_ok());
+        check(&tree, &store);
+    }
+
+    fn churn(tree: &mut Tree, store: &mut Store) {
+        for chunk in pairs(100).chunks(5) {
+            let mut batch = WriteBatch::new();
+            for pair in chunk {
+                batch.assign(&pair.0, &pair.1);
+            }
+            assert!(tree.write(store, batch).is_ok());
+        }
+        for (i, (key, _)) in pairs(100).into_iter().enumerate() {
+            let mut batch = WriteBatch::new();
+            if i % 2 == 0 {
+                batch.delete(&key);
+            } else {
+                batch.merge(&key, b"+");
+            }
+            assert!(tree.write(store, batch).is_ok());
+        }
+    }
+
+    // total size of the nodes under `id`
+    fn tree_size(store: &Store, id: u64) -> u64 {
+        let node = store.read(id).unwrap();
+        let children = match node.body {
+            Body::Leaf(_) => vec![],
+            Body::Internal(ref internal) => internal.children.clone(),
+        };
+        children.into_iter().fold(node.size() as u64, |total, child| total + tree_size(store, child))
+    }
+
+    fn compact_churned(workers: usize) {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.flush_workers = workers;
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        churn(&mut tree, &mut store);
+        assert!(!buffered_keys(&store, tree.root.unwrap()).is_empty());
+        let before = tree_size(&store, tree.root.unwrap());
+        let reclaimed = match tree.compact(&mut store, ..) {
+            Ok(reclaimed) => reclaimed,
+            Err(_) => panic!("compaction failed"),
+        };
+        assert!(reclaimed > 0);
+        assert_eq!(before - tree_size(&store, tree.root.unwrap()), reclaimed);
+        assert!(!tree.txn);
+        assert!(buffered_keys(&store, tree.root.unwrap()).is_empty());
+        for (i, (key, val)) in pairs(100).into_iter().enumerate() {
+            let mut val = val.clone();
+            val.push(b'+');
+            let expected = if i % 2 == 0 { None } else { Some(val) };
+            assert_eq!(expected, get(&tree, &store, &key));
+        }
+        assert_eq!(0, tree.compact(&mut store, ..).unwrap_or(1));
+    }
+
+    #[test]
+    fn compact_all() {
+        compact_churned(1);
+    }
+
+    #[test]
+    fn compact_parallel() {
+        compact_churned(4);
+    }
+
+    #[test]
+    fn compact_range() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        churn(&mut tree, &mut store);
+        let range = &b"key030"[..]..&b"key070"[..];
+        assert!(tree.compact(&mut store, range.clone()).is_ok());
+        let buffered = buffered_keys(&store, tree.root.unwrap());
+        assert!(!buffered.is_empty());
+        assert!(buffered.iter().all(|key| !range.contains(&&key[..])));
+        for (i, (key, val)) in pairs(100).into_iter().enumerate() {
+            let mut val = val.clone();
+            val.push(b'+');
+            let expected = if i % 2 == 0 { None } else { Some(val) };
+            assert_eq!(expected, get(&tree, &store, &key));
+        }
+    }
+
+    #[test]
+    fn custom_comparators() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.comparator = Arc::new(Reverse);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for chunk in pairs(100).chunks(5) {
+            let mut batch = WriteBatch::new();
+            for pair in chunk {
+                batch.assign(&pair.0, &pair.1);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        for (key, val) in pairs(100) {
+            assert_eq!(Some(val), get(&tree, &store, &key));
+        }
+        let mut node = store.read(tree.root.unwrap()).unwrap();
+        while let Body::Internal(ref internal) = node.body.clone() {
+            node = store.read(internal.children[0]).unwrap();
+        }
+        assert_eq!(b"key099", node.body.leaf().keys[0].bytes());
+
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.comparator = Arc::new(CaseInsensitive);
+        let mut batch = WriteBatch::new();
+        batch.assign(b"Key", b"a").merge(b"KEY", b"b");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(Some(b"ab".to_vec()), get(&tree, &store, b"key"));
+    }
+
+    #[test]
+    fn open_with_comparator() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.comparator = Arc::new(Reverse);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut batch = WriteBatch::new();
+        batch.assign(b"a", b"1").assign(b"b", b"2");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let mut data = vec![];
+        assert!(tree.manifest().serialize(&mut data).is_ok());
+        let manifest = Manifest::deserialize(&data).unwrap();
+        assert_eq!("reverse", manifest.comparator);
+        match Tree::open(&manifest, Arc::new(Bytewise), 4, 4, Mode::Test) {
+            Err(ErrorType::Msg(_)) => {}
+            _ => panic!("opened with the wrong comparator"),
+        }
+        let reopened = Tree::open(&manifest, Arc::new(Reverse), 4, 4, Mode::Test);
+        let reopened = match reopened {
+            Ok(tree) => tree,
+            Err(_) => panic!("open failed"),
+        };
+        assert_eq!(Some(b"2".to_vec()), get(&reopened, &store, b"b"));
+        assert_eq!(tree.epoch, reopened.epoch);
+    }
+
+    fn leaf_entries(store: &Store, id: u64) -> usize {
+        match store.read(id).unwrap().body {
+            Body::Leaf(ref leaf) => leaf.keys.len(),
+            Body::Internal(ref internal) => {
+                internal.children.iter().map(|child| leaf_entries(store, *child)).sum()
+            }
+        }
+    }
+
+    #[test]
+    fn expiring_values() {
+        let clock = Arc::new(ManualClock::new(100));
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.clock = clock.clone();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for chunk in pairs(40).chunks(5) {
+            let mut batch = WriteBatch::new();
+            for (i, pair) in chunk.iter().enumerate() {
+                if i % 2 == 0 {
+                    batch.assign_expiring(&pair.0, &pair.1, 150);
+                } else {
+                    batch.assign(&pair.0, &pair.1);
+                }
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        let mut batch = WriteBatch::new();
+        batch.merge(b"key001", b"+");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(Some(b"val1+".to_vec()), get(&tree, &store, b"key001"));
+        assert_eq!(Some(b"val2".to_vec()), get(&tree, &store, b"key002"));
+
+        clock.set(150);
+        for (i, (key, mut val)) in pairs(40).into_iter().enumerate() {
+            if i == 1 {
+                val.push(b'+');
+            }
+            let expected = if i % 5 % 2 == 0 { None } else { Some(val) };
+            assert_eq!(expected, get(&tree, &store, &key));
+        }
+        assert!(tree.compact(&mut store, ..).is_ok());
+        assert!(buffered_keys(&store, tree.root.unwrap()).is_empty());
+        assert_eq!(16, leaf_entries(&store, tree.root.unwrap()));
+
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key000", b"again");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(Some(b"again".to_vec()), get(&tree, &store, b"key000"));
+    }
+
+    #[test]
+    fn merge_after_expiry() {
+        // the expired value is still in its leaf, or compaction dropped it
+        for &dropped in &[false, true] {
+            let clock = Arc::new(ManualClock::new(0));
+            let mut tree = Tree::new(4, 4, Mode::Test);
+            tree.clock = clock.clone();
+            let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+            let mut batch = WriteBatch::new();
+            batch.assign_expiring(b"a", b"a", 10);
+            for (key, val) in pairs(40) {
+                batch.assign(&key, &val);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+            assert!(tree.compact(&mut store, ..).is_ok());
+            assert_eq!(41, leaf_entries(&store, tree.root.unwrap()));
+
+            clock.set(20);
+            if dropped {
+                assert!(tree.compact(&mut store, ..).is_ok());
+                assert_eq!(40, leaf_entries(&store, tree.root.unwrap()));
+            }
+            let mut batch = WriteBatch::new();
+            batch.merge(b"a", b"z");
+            assert!(tree.write(&mut store, batch).is_ok());
+            assert_eq!(Some(b"z".to_vec()), get(&tree, &store, b"a"));
+            assert!(tree.compact(&mut store, ..).is_ok());
+            assert_eq!(Some(b"z".to_vec()), get(&tree, &store, b"a"));
+        }
+    }
+
+    #[test]
+    fn leaf_filters() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.filters = Some(FilterPolicy {
+            bits_per_key: 10,
+            prefix_len: 4,
+        });
+        let mut store = CountingStore {
+            store: NodeStore::new(MemStore::new(), LevelCompression::none()),
+            leaf_reads: Cell::new(0),
+        };
+        let mut input = pairs(200);
+        input.retain(|pair| pair.0[3] == b'0' || pair.0[3] == b'1');
+        for chunk in input.chunks(10) {
+            let mut batch = WriteBatch::new();
+            for (key, val) in chunk {
+                batch.assign(key, val);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        assert!(tree.compact(&mut store, ..).is_ok());
+
+        store.leaf_reads.set(0);
+        for (key, val) in &input {
+            assert_eq!(Some(val.clone()), get(&tree, &store, key));
+        }
+        assert!(store.leaf_reads.get() >= input.len());
+        store.leaf_reads.set(0);
+        for i in 200..300 {
+            assert_eq!(None, get(&tree, &store, format!("key{:03}", i).as_bytes()));
+        }
+        assert!(store.leaf_reads.get() < 10, "{} leaf reads", store.leaf_reads.get());
+
+        // buffered messages are seen even where the leaf is skipped
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key250", b"new");
+        batch.assign(b"kez0", b"new");
+        batch.delete(b"key150");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(Some(b"new".to_vec()), get(&tree, &store, b"key250"));
+
+        let keys = |tree: &Tree, store: &CountingStore, prefix: &[u8]| match tree.scan_prefix(store, prefix) {
+            Ok(entries) => entries.into_iter().map(|entry| entry.0).collect::<Vec<_>>(),
+            Err(_) => panic!("scan failed"),
+        };
+        let mut expected: Vec<Vec<u8>> = (100..200).filter(|i| *i != 150).map(|i| format!("key{}", i).into_bytes()).collect();
+        expected.push(b"key250".to_vec());
+        assert_eq!(expected, keys(&tree, &store, b"key1").into_iter().chain(keys(&tree, &store, b"key2")).collect::<Vec<_>>());
+        assert_eq!(10, keys(&tree, &store, b"key10").len());
+        assert_eq!(vec![b"kez0".to_vec()], keys(&tree, &store, b"kez"));
+        store.leaf_reads.set(0);
+        assert!(keys(&tree, &store, b"kez9").is_empty());
+        assert_eq!(0, store.leaf_reads.get());
+
+        tree.comparator = Arc::new(CaseInsensitive);
+        assert!(tree.filter_policy().is_none());
+    }
+
+    #[test]
+    fn prefix_scan_routes_by_pivots() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        let mut store = CountingStore {
+            store: NodeStore::new(MemStore::new(), LevelCompression::none()),
+            leaf_reads: Cell::new(0),
+        };
+        assert!(tree.bulk_load(&mut store, 100, pairs(200)).is_ok());
+        let total = leaves(&store, tree.root.unwrap()).len();
+        // buffered above the leaves, in and next to the prefix range
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key0505", b"new");
+        batch.delete(b"key052");
+        batch.assign(b"key060", b"new");
+        assert!(tree.write(&mut store, batch).is_ok());
+
+        store.leaf_reads.set(0);
+        let entries = match tree.scan_prefix(&store, b"key05") {
+            Ok(entries) => entries,
+            Err(_) => panic!("scan failed"),
+        };
+        assert!(store.leaf_reads.get() <= 5, "{} of {} leaves read", store.leaf_reads.get(), total);
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = pairs(60).split_off(50);
+        expected.insert(1, (b"key0505".to_vec(), b"new".to_vec()));
+        expected.retain(|pair| pair.0 != b"key052");
+        assert_eq!(expected, entries);
+
+        // entries are passed on as each leaf is read
+        store.leaf_reads.set(0);
+        let mut first = None;
+        assert!(tree.scan(&store, |_, _| { first.get_or_insert(store.leaf_reads.get()); }).is_ok());
+        assert_eq!(Some(1), first);
+        assert_eq!(total, store.leaf_reads.get());
+    }
+
+    // number of blocks that hold a chunk of `val`
+    fn chunks(store: &NodeStore<MemStore>, val: &[u8]) -> usize {
+        store.blocks.blocks.values().filter(|data| val.chunks(8).any(|chunk| chunk == &data[..])).count()
+    }
+
+    #[test]
+    fn blob_values() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.blobs = Some(BlobPolicy {
+            threshold: 16,
+            chunk: 8,
+        });
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let big = b"0123456789abcdefghijklmnopqrstuvwxyzABCD".to_vec();
+        let other = b"EFGHIJKLMNOPQRSTUVWXYZ!?".to_vec();
+        let mut batch = WriteBatch::new();
+        batch.assign(b"big", &big);
+        batch.assign(b"other", &other);
+        batch.assign(b"small", b"inline");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(5, chunks(&store, &big));
+        assert_eq!(3, chunks(&store, &other));
+        assert_eq!(Some(big.clone()), get(&tree, &store, b"big"));
+        assert_eq!(Some(b"inline".to_vec()), get(&tree, &store, b"small"));
+
+        let mut batch = WriteBatch::new();
+        batch.merge(b"big", b"+");
+        assert!(tree.write(&mut store, batch).is_ok());
+        for chunk in pairs(40).chunks(5) {
+            let mut batch = WriteBatch::new();
+            for (key, val) in chunk {
+                batch.assign(key, val);
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        let mut expected = big.clone();
+        expected.push(b'+');
+        let mut reader = match tree.value_reader(&store, b"big") {
+            Ok(Some(reader)) => reader,
+            _ => panic!("value_reader failed"),
+        };
+        assert_eq!(41, reader.len());
+        let mut first = [0; 10];
+        assert!(reader.read_exact(&mut first).is_ok());
+        assert_eq!(&expected[..10], &first);
+        assert_eq!(&expected[10..], &reader.into_vec().unwrap()[..]);
+        match tree.scan_prefix(&store, b"b") {
+            Ok(entries) => assert_eq!(vec![(b"big".to_vec(), expected)], entries),
+            Err(_) => panic!("scan_prefix failed"),
+        }
+
+        // the chunks go once no root references them
+        let mut batch = WriteBatch::new();
+        batch.assign(b"big", b"replaced");
+        batch.delete(b"other");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert!(tree.compact(&mut store, ..).is_ok());
+        assert_eq!(0, chunks(&store, &big));
+        assert_eq!(0, chunks(&store, &other));
+        assert_eq!(Some(b"replaced".to_vec()), get(&tree, &store, b"big"));
+        assert_eq!(None, get(&tree, &store, b"other"));
+    }
+
+    #[test]
+    fn bulk_load_blobs() {
+        let policy = BlobPolicy {
+            threshold: 16,
+            chunk: 8,
+        };
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.blobs = Some(policy);
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut input: Vec<(Vec<u8>, Vec<u8>)> = (0..20)
+            .map(|i| (format!("key{:02}", i).into_bytes(), format!("{:02}", i).repeat(12).into_bytes()))
+            .collect();
+        input[7].1 = b"inline".to_vec();
+        assert!(tree.bulk_load(&mut store, 100, input.clone()).is_ok());
+        assert_eq!(3, chunks(&store, &input[3].1));
+        assert_eq!(0, chunks(&store, &input[7].1));
+        assert_eq!(input, scanned(&tree, &store));
+
+        // reopen from the manifest over the blocks the tree was written to
+        let mut data = vec![];
+        assert!(tree.manifest().serialize(&mut data).is_ok());
+        let manifest = Manifest::deserialize(&data).unwrap();
+        let blocks = MemStore {
+            blocks: store.blocks.blocks,
+        };
+        let mut store = NodeStore::new(blocks, LevelCompression::none());
+        let mut reopened = match Tree::open(&manifest, Arc::new(Bytewise), 4, 4, Mode::Test) {
+            Ok(tree) => tree,
+            Err(_) => panic!("open failed"),
+        };
+        reopened.blobs = Some(policy);
+        let clock = Arc::new(ManualClock::new(0));
+        reopened.clock = clock.clone();
+        assert_eq!(input, scanned(&reopened, &store));
+        let expiring = b"0123456789abcdefghij".to_vec();
+        let mut batch = WriteBatch::new();
+        batch.assign_expiring(b"key99", &expiring, 10);
+        assert!(reopened.write(&mut store, batch).is_ok());
+        assert_eq!(3, chunks(&store, &expiring));
+        let mut expected = input.clone();
+        expected.push((b"key99".to_vec(), expiring));
+        assert_eq!(expected, scanned(&reopened, &store));
+        clock.set(10);
+        assert_eq!(input, scanned(&reopened, &store));
+    }
+
+    #[test]
+    fn aborted_blob_writes_are_deleted() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.blobs = Some(BlobPolicy {
+            threshold: 16,
+            chunk: 8,
+        });
+        let mut store = FailingStore::new();
+        let mut batch = WriteBatch::new();
+        batch.assign(b"foo", b"1");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let mut ids: Vec<u64> = store.store.blocks.blocks.keys().cloned().collect();
+        ids.sort();
+        let big = b"0123456789abcdefghijklmnopqrstuvwxyzABCD".to_vec();
+        // the write fails after some of the chunks, and after all of them
+        for &writes in &[3, 5] {
+            store.writes_left = writes;
+            let mut batch = WriteBatch::new();
+            batch.assign(b"big", &big);
+            assert!(tree.write(&mut store, batch).is_err());
+            let mut left: Vec<u64> = store.store.blocks.blocks.keys().cloned().collect();
+            left.sort();
+            assert_eq!(ids, left);
+        }
+        store.writes_left = usize::MAX;
+        let mut batch = WriteBatch::new();
+        batch.assign(b"big", &big);
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(5, chunks(&store.store, &big));
+        assert_eq!(Some(big), get(&tree, &store, b"big"));
+    }
+
+    #[test]
+    fn expiring_blobs_and_merges() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.blobs = Some(BlobPolicy {
+            threshold: 16,
+            chunk: 8,
+        });
+        let clock = Arc::new(ManualClock::new(0));
+        tree.clock = clock.clone();
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let big = b"0123456789abcdefghijklmnopqrstuvwxyzABCD".to_vec();
+        let mut batch = WriteBatch::new();
+        batch.assign_expiring(b"big", &big, 10);
+        batch.assign(b"merged", b"abcdefghij");
+        batch.merge(b"merged", b"klmnopqrst");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(5, chunks(&store, &big));
+        assert_eq!(Some(big.clone()), get(&tree, &store, b"big"));
+        // a merge grows the value past the threshold in the leaf
+        assert_eq!(Some(b"abcdefghijklmnopqrst".to_vec()), get(&tree, &store, b"merged"));
+        assert_eq!(0, chunks(&store, b"abcdefghijklmnopqrst"));
+
+        clock.set(10);
+        assert_eq!(None, get(&tree, &store, b"big"));
+        // the expired value is dropped when its leaf is next written
+        let mut batch = WriteBatch::new();
+        batch.assign(b"other", b"x");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert_eq!(0, chunks(&store, &big));
+        assert_eq!(None, get(&tree, &store, b"big"));
+    }
+
+    fn history(tree: &Tree, store: &Store, key: &[u8]) -> Vec<(u64, Option<Vec<u8>>)> {
+        match tree.history(store, key) {
+            Ok(versions) => versions.into_iter().map(|version| (version.epoch, version.val)).collect(),
+            Err(_) => panic!("history failed"),
+        }
+    }
+
+    #[test]
+    fn versioned_bulk_load_keeps_values_inline() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.versions = Some(Retention::Last(3));
+        tree.blobs = Some(BlobPolicy {
+            threshold: 16,
+            chunk: 8,
+        });
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let input: Vec<(Vec<u8>, Vec<u8>)> = (0..20)
+            .map(|i| (format!("key{:02}", i).into_bytes(), format!("{:02}", i).repeat(12).into_bytes()))
+            .collect();
+        assert!(tree.bulk_load(&mut store, 100, input.clone()).is_ok());
+        assert_eq!(0, chunks(&store, &input[3].1));
+        let loaded = tree.epoch;
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key03", b"new");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let written = tree.epoch;
+        assert!(tree.compact(&mut store, ..).is_ok());
+        match tree.get_at(&store, b"key03", loaded) {
+            Ok(val) => assert_eq!(Some(input[3].1.clone()), val),
+            Err(_) => panic!("get_at failed"),
+        }
+        let versions = history(&tree, &store, b"key03");
+        assert_eq!((written, Some(b"new".to_vec())), versions[0]);
+        assert_eq!(Some(input[3].1.clone()), versions[1].1);
+        assert_eq!(input[4..], scanned(&tree, &store)[4..]);
+    }
+
+    #[test]
+    fn versioned_values() {
+        let mut tree = Tree::new(4, 4, Mode::Test);
+        tree.versions = Some(Retention::Last(3));
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut epochs = vec![];
+        for round in 0..6 {
+            let mut batch = WriteBatch::new();
+            for (key, mut val) in pairs(40) {
+                val.push(b'0' + round);
+                batch.assign(&key, &val);
+            }
+            if round == 4 {
+                batch.delete(b"key007");
+            }
+            assert!(tree.write(&mut store, batch).is_ok());
+            epochs.push(tree.epoch);
+        }
+        let expected = vec![
+            (epochs[5], Some(b"val75".to_vec())),
+            (epochs[4], None),
+            (epochs[3], Some(b"val73".to_vec())),
+        ];
+        assert_eq!(expected, history(&tree, &store, b"key007"));
+        let at = |tree: &Tree, store: &Store, epoch: u64| match tree.get_at(store, b"key007", epoch) {
+            Ok(val) => val,
+            Err(_) => panic!("get_at failed"),
+        };
+        assert_eq!(Some(b"val73".to_vec()), at(&tree, &store, epochs[3]));
+        assert_eq!(None, at(&tree, &store, epochs[4]));
+        assert_eq!(None, at(&tree, &store, epochs[1]));
+        assert_eq!(Some(b"val75".to_vec()), at(&tree, &store, tree.epoch));
+
+        // the same versions once every message has reached the leaves
+        assert!(tree.compact(&mut store, ..).is_ok());
+        assert!(buffered_keys(&store, tree.root.unwrap()).is_empty());
+        assert_eq!(expected, history(&tree, &store, b"key007"));
+        assert_eq!(3, history(&tree, &store, b"key021").len());
+
+        tree.versions = Some(Retention::Since(epochs[4]));
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key007");
+        assert!(tree.write(&mut store, batch).is_ok());
+        let deleted = tree.epoch;
+        assert!(tree.compact(&mut store, ..).is_ok());
+        assert_eq!(None, get(&tree, &store, b"key007"));
+        let expected = vec![
+            (deleted, None),
+            (epochs[5], Some(b"val75".to_vec())),
+            (epochs[4], None),
+        ];
+        assert_eq!(expected, history(&tree, &store, b"key007"));
+
+        // turning versions off drops them with the next write
+        tree.versions = None;
+        let mut batch = WriteBatch::new();
+        batch.assign(b"key021", b"plain");
+        assert!(tree.write(&mut store, batch).is_ok());
+        assert!(tree.compact(&mut store, ..).is_ok());
+        assert_eq!(vec![(0, Some(b"plain".to_vec()))], history(&tree, &store, b"key021"));
     }
 }
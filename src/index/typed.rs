@@ -0,0 +1,132 @@
+use super::batch::WriteBatch;
+use super::codec::Codec;
+use super::error::ErrorType;
+use super::store::Store;
+use super::tree::Tree;
+
+/// A tree of typed keys and values. Keys and values are encoded with
+/// the given codecs; keys should use an order-preserving codec such as
+/// `codec::Ordered` so that the tree orders them like their values.
+pub struct TypedTree<K, V> {
+    pub tree: Tree,
+    keys: Box<Codec<K>>,
+    vals: Box<Codec<V>>,
+}
+
+impl<K, V> TypedTree<K, V> {
+    pub fn new(tree: Tree, keys: Box<Codec<K>>, vals: Box<Codec<V>>) -> TypedTree<K, V> {
+        TypedTree {
+            tree: tree,
+            keys: keys,
+            vals: vals,
+        }
+    }
+
+    pub fn encode_key(&self, key: &K) -> Vec<u8> {
+        let mut out = vec![];
+        self.keys.encode(key, &mut out);
+        out
+    }
+
+    pub fn decode_key(&self, data: &[u8]) -> Result<K, ErrorType> {
+        self.keys.decode(data).map_err(ErrorType::IO)
+    }
+
+    pub fn get<'a>(&self, store: &Store<'a>, key: &K) -> Result<Option<V>, ErrorType> {
+        match self.tree.get(store, &self.encode_key(key))? {
+            Some(data) => self.vals.decode(&data).map(Some).map_err(ErrorType::IO),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds an assignment to `batch`.
+    pub fn assign<'b>(&self, batch: &'b mut WriteBatch, key: &K, val: &V) -> &'b mut WriteBatch {
+        let mut data = vec![];
+        self.vals.encode(val, &mut data);
+        batch.assign(&self.encode_key(key), &data)
+    }
+
+    /// Adds a delete to `batch`.
+    pub fn delete<'b>(&self, batch: &'b mut WriteBatch, key: &K) -> &'b mut WriteBatch {
+        batch.delete(&self.encode_key(key))
+    }
+
+    pub fn write<'a>(&mut self, store: &mut Store<'a>, batch: WriteBatch) -> Result<(), ErrorType> {
+        self.tree.write(store, batch)
+    }
+
+    pub fn put<'a>(&mut self, store: &mut Store<'a>, key: &K, val: &V) -> Result<(), ErrorType> {
+        let mut batch = WriteBatch::new();
+        self.assign(&mut batch, key, val);
+        self.write(store, batch)
+    }
+
+    pub fn remove<'a>(&mut self, store: &mut Store<'a>, key: &K) -> Result<(), ErrorType> {
+        let mut batch = WriteBatch::new();
+        self.delete(&mut batch, key);
+        self.write(store, batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use index::codec::Ordered;
+    use index::codec::Utf8;
+    use index::compression::LevelCompression;
+    use index::mode::Mode;
+    use index::node::Body;
+    use index::store::MemStore;
+    use index::store::NodeStore;
+
+    fn get<K, V>(tree: &TypedTree<K, V>, store: &Store, key: &K) -> Option<V> {
+        match tree.get(store, key) {
+            Ok(val) => val,
+            Err(_) => panic!("get failed"),
+        }
+    }
+
+    #[test]
+    fn typed_keys_and_values() {
+        let mut tree: TypedTree<(String, i32), String> =
+            TypedTree::new(Tree::new(4, 4, Mode::Test), Box::new(Ordered), Box::new(Utf8));
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        for i in -20..20 {
+            let mut batch = WriteBatch::new();
+            tree.assign(&mut batch, &("a".to_string(), i), &format!("v{}", i));
+            tree.assign(&mut batch, &("b".to_string(), -i), &format!("w{}", i));
+            assert!(tree.write(&mut store, batch).is_ok());
+        }
+        assert_eq!(Some("v-7".to_string()), get(&tree, &store, &("a".to_string(), -7)));
+        assert_eq!(Some("w3".to_string()), get(&tree, &store, &("b".to_string(), -3)));
+        assert_eq!(None, get(&tree, &store, &("c".to_string(), 0)));
+        assert!(tree.remove(&mut store, &("a".to_string(), -7)).is_ok());
+        assert_eq!(None, get(&tree, &store, &("a".to_string(), -7)));
+
+        // the leftmost leaf holds the smallest key
+        let mut node = store.read(tree.tree.root.unwrap()).unwrap();
+        while let Body::Internal(ref internal) = node.body.clone() {
+            node = store.read(internal.children[0]).unwrap();
+        }
+        let first = node.body.leaf().keys[0].bytes().to_vec();
+        match tree.decode_key(&first) {
+            Ok(key) => assert_eq!(("a".to_string(), -20), key),
+            Err(_) => panic!("decode failed"),
+        }
+    }
+
+    #[test]
+    fn bad_value() {
+        let mut tree: TypedTree<u64, String> =
+            TypedTree::new(Tree::new(4, 4, Mode::Test), Box::new(Ordered), Box::new(Utf8));
+        let mut store = NodeStore::new(MemStore::new(), LevelCompression::none());
+        let mut batch = WriteBatch::new();
+        batch.assign(&tree.encode_key(&1), &[0xff]);
+        assert!(tree.write(&mut store, batch).is_ok());
+        match tree.get(&store, &1) {
+            Err(ErrorType::IO(_)) => {}
+            _ => panic!("decoded invalid utf-8"),
+        }
+    }
+}
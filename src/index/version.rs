@@ -0,0 +1,93 @@
+/// Which earlier versions of each key a versioned tree keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retention {
+    /// The last `n` versions, counting the current one.
+    Last(usize),
+    /// Every version that was still current at or after the epoch.
+    Since(u64),
+}
+
+/// The value of a key as written by the commit in `epoch`,
+/// or `None` if the commit deleted the key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub epoch: u64,
+    pub val: Option<Vec<u8>>,
+}
+
+/// What a versioned leaf keeps for an entry besides its current value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct History {
+    /// Epoch of the commit that wrote the current value.
+    pub epoch: u64,
+    /// Whether the current version is a delete. The entry stays in the
+    /// leaf for as long as it has older versions.
+    pub deleted: bool,
+    /// Earlier versions, newest first.
+    pub older: Vec<Version>,
+}
+
+impl Retention {
+    /// Drops the versions in `older`, newest first, that are no longer
+    /// kept once the version written in `epoch` is the current one.
+    pub fn prune(self, epoch: u64, older: &mut Vec<Version>) {
+        match self {
+            Retention::Last(n) => older.truncate(n.saturating_sub(1)),
+            Retention::Since(since) => {
+                // a version was current until the epoch of the next one
+                let mut next = epoch;
+                let keep = older
+                    .iter()
+                    .take_while(|version| {
+                        let current = next > since;
+                        next = version.epoch;
+                        current
+                    })
+                    .count();
+                older.truncate(keep);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(epochs: &[u64]) -> Vec<Version> {
+        epochs
+            .iter()
+            .map(|epoch| Version {
+                epoch: *epoch,
+                val: Some(vec![*epoch as u8]),
+            })
+            .collect()
+    }
+
+    fn epochs(versions: &[Version]) -> Vec<u64> {
+        versions.iter().map(|version| version.epoch).collect()
+    }
+
+    #[test]
+    fn prune_versions() {
+        let mut older = versions(&[8, 5, 3, 1]);
+        Retention::Last(3).prune(9, &mut older);
+        assert_eq!(vec![8, 5], epochs(&older));
+        Retention::Last(0).prune(9, &mut older);
+        assert!(older.is_empty());
+
+        // 5 was current from 5 to 7, so it is needed for epoch 6
+        let mut older = versions(&[8, 5, 3, 1]);
+        Retention::Since(6).prune(9, &mut older);
+        assert_eq!(vec![8, 5], epochs(&older));
+        let mut older = versions(&[8, 5, 3, 1]);
+        Retention::Since(5).prune(9, &mut older);
+        assert_eq!(vec![8, 5], epochs(&older));
+        let mut older = versions(&[8, 5, 3, 1]);
+        Retention::Since(4).prune(9, &mut older);
+        assert_eq!(vec![8, 5, 3], epochs(&older));
+        let mut older = versions(&[8, 5, 3, 1]);
+        Retention::Since(9).prune(9, &mut older);
+        assert!(older.is_empty());
+    }
+}
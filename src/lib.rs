@@ -1,7 +1,10 @@
 #![feature(test)]
 
 extern crate byteorder;
+extern crate chacha20poly1305;
+extern crate lz4_flex;
+extern crate miniz_oxide;
 extern crate rand;
 extern crate test;
 
-pub mod index;
\ No newline at end of file
+pub mod index;